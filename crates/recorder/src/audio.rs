@@ -0,0 +1,548 @@
+//! Audio tracks: captures from an FFmpeg input device, resamples to what the
+//! encoder wants and feeds the encoded packets into the replay buffer next to
+//! the video.
+
+use std::ffi::c_void;
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use common::cstring;
+use common::log::{debug, error, info};
+use common::sys;
+use common::tokio::sync::Mutex;
+use storage::ReplayBuffer;
+
+use crate::capture::{self, StreamParams};
+use crate::clock::{CaptureTimes, SessionClock};
+use crate::filter::{AudioFilterGraph, AudioFilters};
+use crate::stats::SharedStats;
+
+/// Replay buffer stream that system audio is recorded to.
+pub const SYSTEM_AUDIO_STREAM: usize = 1;
+/// Replay buffer stream that the microphone is recorded to, kept apart from
+/// system audio so it can be muted or removed when editing.
+pub const MIC_STREAM: usize = 2;
+
+/// How far a track may run ahead of or behind the session clock before it is
+/// resampled back into line, as a fraction of a second.
+const DRIFT_TOLERANCE: f64 = 0.02;
+/// Largest correction applied per second of audio, as a fraction of the
+/// sample rate. Keeps the pitch change inaudible.
+const MAX_DRIFT_CORRECTION: f64 = 0.01;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AudioCodec {
+    #[default]
+    Aac,
+    Opus,
+}
+
+impl AudioCodec {
+    fn encoder_name(self) -> &'static str {
+        match self {
+            AudioCodec::Aac => "aac",
+            AudioCodec::Opus => "libopus",
+        }
+    }
+}
+
+/// Where an audio track is captured from and how it is encoded.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AudioConfig {
+    /// FFmpeg input format, e.g. `pulse`.
+    pub input_format: String,
+    /// Device passed to the input format. For `pulse` this is a source name;
+    /// `@DEFAULT_MONITOR@` records whatever the default sink plays.
+    pub device: String,
+    pub codec: AudioCodec,
+    pub bitrate_kbps: u32,
+    pub sample_rate: u32,
+    pub channels: u32,
+    /// Volume change applied before encoding, in dB.
+    pub gain_db: f32,
+    /// Record silence except while the push-to-talk key is held.
+    pub push_to_talk: bool,
+    pub filters: AudioFilters,
+}
+
+impl AudioConfig {
+    /// Everything the desktop plays, through PulseAudio or PipeWire's pulse
+    /// server.
+    pub fn system_default() -> Self {
+        Self {
+            input_format: "pulse".to_string(),
+            device: "@DEFAULT_MONITOR@".to_string(),
+            codec: AudioCodec::Aac,
+            bitrate_kbps: 160,
+            sample_rate: 48000,
+            channels: 2,
+            gain_db: 0.0,
+            push_to_talk: false,
+            filters: AudioFilters::default(),
+        }
+    }
+
+    /// The default PulseAudio/PipeWire input, usually the microphone.
+    pub fn default_microphone() -> Self {
+        Self {
+            device: "default".to_string(),
+            channels: 1,
+            bitrate_kbps: 96,
+            ..Self::system_default()
+        }
+    }
+
+    /// A 440 Hz tone from lavfi, paced in real time. Stands in for a sound
+    /// server on headless machines.
+    pub fn test_tone() -> Self {
+        Self {
+            input_format: "lavfi".to_string(),
+            device: "sine=frequency=440:sample_rate=48000,arealtime".to_string(),
+            ..Self::system_default()
+        }
+    }
+
+    /// Whether this platform's FFmpeg has the input format: pulse and alsa
+    /// exist on Linux only, dshow on Windows and avfoundation on macOS.
+    pub fn is_supported(&self) -> bool {
+        match self.input_format.as_str() {
+            "pulse" | "alsa" => cfg!(target_os = "linux"),
+            "dshow" => cfg!(target_os = "windows"),
+            "avfoundation" => cfg!(target_os = "macos"),
+            _ => true,
+        }
+    }
+
+    fn input_options(&self) -> Vec<(String, String)> {
+        match self.input_format.as_str() {
+            "pulse" => vec![
+                ("sample_rate".to_string(), self.sample_rate.to_string()),
+                ("channels".to_string(), self.channels.to_string()),
+            ],
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Multiplies every sample in `frame` by `gain`. Handles the sample formats
+/// our encoders take; anything else passes through unchanged.
+unsafe fn apply_gain(frame: *mut sys::AVFrame, format: sys::AVSampleFormat, gain: f32) {
+    unsafe {
+        let channels = (*frame).ch_layout.nb_channels as usize;
+        let samples = (*frame).nb_samples as usize;
+        let (planes, per_plane) = if sys::av_sample_fmt_is_planar(format) != 0 {
+            (channels, samples)
+        } else {
+            (1, samples * channels)
+        };
+
+        for plane in 0..planes {
+            let data = (*frame).data[plane];
+            match sys::av_get_packed_sample_fmt(format) {
+                sys::AVSampleFormat::AV_SAMPLE_FMT_FLT => {
+                    let samples = std::slice::from_raw_parts_mut(data as *mut f32, per_plane);
+                    for sample in samples {
+                        *sample *= gain;
+                    }
+                }
+                sys::AVSampleFormat::AV_SAMPLE_FMT_S16 => {
+                    let samples = std::slice::from_raw_parts_mut(data as *mut i16, per_plane);
+                    for sample in samples {
+                        *sample =
+                            (*sample as f32 * gain).clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+}
+
+unsafe fn open_audio_encoder(config: &AudioConfig) -> Result<*mut sys::AVCodecContext, String> {
+    unsafe {
+        let name = config.codec.encoder_name();
+        let encoder = sys::avcodec_find_encoder_by_name(cstring!(name).as_ptr());
+        if encoder.is_null() {
+            return Err(format!("{} is not available in this FFmpeg build", name));
+        }
+
+        let mut enc_ctx = sys::avcodec_alloc_context3(encoder);
+        if enc_ctx.is_null() {
+            return Err("Failed to allocate audio encoder context".to_string());
+        }
+
+        let sample_fmts = (*encoder).sample_fmts;
+        (*enc_ctx).sample_fmt = if sample_fmts.is_null() {
+            sys::AVSampleFormat::AV_SAMPLE_FMT_FLTP
+        } else {
+            *sample_fmts
+        };
+        (*enc_ctx).sample_rate = config.sample_rate as i32;
+        sys::av_channel_layout_default(&mut (*enc_ctx).ch_layout, config.channels as i32);
+        (*enc_ctx).bit_rate = config.bitrate_kbps as i64 * 1000;
+        (*enc_ctx).time_base = sys::AVRational {
+            num: 1,
+            den: config.sample_rate as i32,
+        };
+        (*enc_ctx).flags |= sys::AV_CODEC_FLAG_GLOBAL_HEADER as i32;
+
+        if sys::avcodec_open2(enc_ctx, encoder, ptr::null_mut()) < 0 {
+            sys::avcodec_free_context(&mut enc_ctx);
+            return Err(format!(
+                "Failed to open {} at {} Hz, {} channels",
+                name, config.sample_rate, config.channels
+            ));
+        }
+        info!("[recorder] Selected audio encoder: {}", name);
+        Ok(enc_ctx)
+    }
+}
+
+/// Captures `config` into `stream` of the replay buffer until stopped. A
+/// device that cannot be opened is logged and leaves the stream out of clips.
+///
+/// With push-to-talk on, samples are replaced by silence whenever
+/// `talk_gate` is false, so the track keeps its length and stays in sync.
+///
+/// Sound cards run on their own clock, which drifts against `clock` by a few
+/// samples per second. The drift is measured against capture times and
+/// resampled away, so long buffers stay in sync with the video.
+#[allow(unused_assignments, clippy::too_many_arguments)]
+pub(crate) fn capture_audio_loop(
+    stream: usize,
+    config: AudioConfig,
+    clock: SessionClock,
+    replay_buffer: Arc<ReplayBuffer>,
+    stop_signal: Arc<Mutex<bool>>,
+    streams: StreamParams,
+    talk_gate: Arc<AtomicBool>,
+    stats: SharedStats,
+) {
+    unsafe {
+        let mut fmt_ctx: *mut sys::AVFormatContext = ptr::null_mut();
+        let mut dec_ctx: *mut sys::AVCodecContext = ptr::null_mut();
+        let mut enc_ctx: *mut sys::AVCodecContext = ptr::null_mut();
+        let mut swr_ctx: *mut sys::SwrContext = ptr::null_mut();
+
+        info!(
+            "[recorder] Attempting to capture audio from {} {:?}",
+            config.input_format, config.device
+        );
+        fmt_ctx = match capture::open_input(
+            &config.input_format,
+            &config.device,
+            &config.input_options(),
+        ) {
+            Ok(ctx) => ctx,
+            Err(e) => {
+                error!("[recorder] {}; recording without this audio track", e);
+                return;
+            }
+        };
+
+        let audio_stream_index;
+        (audio_stream_index, dec_ctx) =
+            match capture::open_decoder(fmt_ctx, sys::AVMediaType::AVMEDIA_TYPE_AUDIO) {
+                Ok(opened) => opened,
+                Err(e) => {
+                    error!("[recorder] {}", e);
+                    sys::avformat_close_input(&mut fmt_ctx);
+                    return;
+                }
+            };
+
+        enc_ctx = match open_audio_encoder(&config) {
+            Ok(ctx) => ctx,
+            Err(e) => {
+                error!("[recorder] {}", e);
+                sys::avcodec_free_context(&mut dec_ctx);
+                sys::avformat_close_input(&mut fmt_ctx);
+                return;
+            }
+        };
+
+        // Some devices leave the layout unspecified; assume the usual one for
+        // the channel count.
+        if (*dec_ctx).ch_layout.order == sys::AVChannelOrder::AV_CHANNEL_ORDER_UNSPEC {
+            let channels = (*dec_ctx).ch_layout.nb_channels;
+            sys::av_channel_layout_uninit(&mut (*dec_ctx).ch_layout);
+            sys::av_channel_layout_default(&mut (*dec_ctx).ch_layout, channels);
+        }
+
+        let mut filters = match config.filters.graph() {
+            Some(chain) => match AudioFilterGraph::new(&chain, dec_ctx) {
+                Ok(graph) => {
+                    info!("[recorder] Audio filters: {}", chain);
+                    Some(graph)
+                }
+                Err(e) => {
+                    error!("[recorder] {}", e);
+                    sys::avcodec_free_context(&mut dec_ctx);
+                    sys::avcodec_free_context(&mut enc_ctx);
+                    sys::avformat_close_input(&mut fmt_ctx);
+                    return;
+                }
+            },
+            None => None,
+        };
+
+        // The resampler takes whatever the filters put out; loudnorm for one
+        // changes the sample rate.
+        let (in_layout, in_format, in_rate) = match &filters {
+            Some(graph) => (
+                &graph.output_layout as *const sys::AVChannelLayout,
+                graph.sample_format(),
+                graph.sample_rate(),
+            ),
+            None => (
+                &(*dec_ctx).ch_layout as *const sys::AVChannelLayout,
+                (*dec_ctx).sample_fmt,
+                (*dec_ctx).sample_rate,
+            ),
+        };
+        let swr_result = sys::swr_alloc_set_opts2(
+            &mut swr_ctx,
+            &(*enc_ctx).ch_layout,
+            (*enc_ctx).sample_fmt,
+            (*enc_ctx).sample_rate,
+            in_layout,
+            in_format,
+            in_rate,
+            0,
+            ptr::null_mut(),
+        );
+        if swr_result < 0 || sys::swr_init(swr_ctx) < 0 {
+            error!(
+                "[recorder] Failed to create resampler from {} Hz to {} Hz",
+                in_rate,
+                (*enc_ctx).sample_rate
+            );
+            sys::swr_free(&mut swr_ctx);
+            sys::avcodec_free_context(&mut dec_ctx);
+            sys::avcodec_free_context(&mut enc_ctx);
+            sys::avformat_close_input(&mut fmt_ctx);
+            return;
+        }
+
+        // Encoders with a variable frame size report 0.
+        let frame_size = match (*enc_ctx).frame_size {
+            0 => 1024,
+            size => size,
+        };
+        let channels = (*enc_ctx).ch_layout.nb_channels;
+        let fifo = sys::av_audio_fifo_alloc((*enc_ctx).sample_fmt, channels, frame_size * 4);
+        let mut packet = sys::av_packet_alloc();
+        let mut decoded_frame = sys::av_frame_alloc();
+        let mut filtered_frame = sys::av_frame_alloc();
+        let mut resampled_frame = sys::av_frame_alloc();
+        let mut enc_frame = sys::av_frame_alloc();
+
+        if fifo.is_null()
+            || packet.is_null()
+            || decoded_frame.is_null()
+            || filtered_frame.is_null()
+            || resampled_frame.is_null()
+            || enc_frame.is_null()
+        {
+            error!("[recorder] Failed to allocate audio buffers");
+        } else {
+            capture::publish_stream(&streams, stream, enc_ctx);
+
+            info!(
+                "[recorder] Starting audio capture loop ({} Hz, {} channels)",
+                (*enc_ctx).sample_rate,
+                channels
+            );
+            let mut next_pts = 0i64;
+            let mut encoded_packets = 0u64;
+            let gain = 10f32.powf(config.gain_db / 20.0);
+            let rate = (*enc_ctx).sample_rate;
+            let samples = |count: i64| Duration::from_secs_f64(count as f64 / rate as f64);
+
+            let mut capture_times = CaptureTimes::new((*enc_ctx).time_base);
+            // Capture time of the oldest sample waiting in the FIFO, set by
+            // the first frame.
+            let mut fifo_start = clock.origin();
+            // Capture time of the first sample sent to the encoder.
+            let mut audio_start: Option<Instant> = None;
+            let mut last_drift_check = 0i64;
+
+            // Resamples, gates and encodes one decoded (or filtered) frame
+            // whose first sample was captured at `captured_at`.
+            let mut encode_frame = |input: *mut sys::AVFrame, captured_at: Instant| {
+                sys::av_frame_unref(resampled_frame);
+                sys::av_channel_layout_copy(
+                    &mut (*resampled_frame).ch_layout,
+                    &(*enc_ctx).ch_layout,
+                );
+                (*resampled_frame).sample_rate = (*enc_ctx).sample_rate;
+                (*resampled_frame).format = (*enc_ctx).sample_fmt as i32;
+                if sys::swr_convert_frame(swr_ctx, resampled_frame, input) < 0 {
+                    error!("[recorder] Failed to resample audio frame");
+                    return;
+                }
+                if config.push_to_talk && !talk_gate.load(Ordering::Relaxed) {
+                    sys::av_samples_set_silence(
+                        (*resampled_frame).data.as_mut_ptr(),
+                        0,
+                        (*resampled_frame).nb_samples,
+                        channels,
+                        (*enc_ctx).sample_fmt,
+                    );
+                } else if gain != 1.0 {
+                    apply_gain(resampled_frame, (*enc_ctx).sample_fmt, gain);
+                }
+                let queued = samples(sys::av_audio_fifo_size(fifo) as i64);
+                fifo_start = captured_at.checked_sub(queued).unwrap_or(captured_at);
+                sys::av_audio_fifo_write(
+                    fifo,
+                    (*resampled_frame).data.as_ptr() as *const *mut c_void,
+                    (*resampled_frame).nb_samples,
+                );
+
+                // The encoder takes fixed-size frames.
+                while sys::av_audio_fifo_size(fifo) >= frame_size {
+                    sys::av_frame_unref(enc_frame);
+                    (*enc_frame).nb_samples = frame_size;
+                    (*enc_frame).format = (*enc_ctx).sample_fmt as i32;
+                    (*enc_frame).sample_rate = (*enc_ctx).sample_rate;
+                    sys::av_channel_layout_copy(&mut (*enc_frame).ch_layout, &(*enc_ctx).ch_layout);
+                    if sys::av_frame_get_buffer(enc_frame, 0) < 0 {
+                        error!("[recorder] Failed to allocate audio frame buffer");
+                        break;
+                    }
+                    sys::av_audio_fifo_read(
+                        fifo,
+                        (*enc_frame).data.as_ptr() as *const *mut c_void,
+                        frame_size,
+                    );
+                    (*enc_frame).pts = next_pts;
+                    capture_times.record(next_pts, fifo_start);
+
+                    // Compare samples sent with time passed, once a second.
+                    let start = *audio_start.get_or_insert(fifo_start);
+                    if next_pts - last_drift_check >= rate as i64 {
+                        last_drift_check = next_pts;
+                        let expected = fifo_start.duration_since(start).as_secs_f64() * rate as f64;
+                        let drift = next_pts as f64 - expected;
+                        if drift.abs() > rate as f64 * DRIFT_TOLERANCE {
+                            let max_step = rate as f64 * MAX_DRIFT_CORRECTION;
+                            let correction = (-drift).clamp(-max_step, max_step) as i32;
+                            debug!(
+                                "[recorder] Audio stream {} is {:.1} ms off the session clock, correcting by {} samples",
+                                stream,
+                                drift * 1000.0 / rate as f64,
+                                correction
+                            );
+                            sys::swr_set_compensation(swr_ctx, correction, rate);
+                        }
+                    }
+
+                    next_pts += frame_size as i64;
+                    fifo_start += samples(frame_size as i64);
+
+                    if sys::avcodec_send_frame(enc_ctx, enc_frame) >= 0 {
+                        encoded_packets += capture::drain_encoder(
+                            enc_ctx,
+                            stream,
+                            &replay_buffer,
+                            &capture_times,
+                            &stats,
+                        );
+                    }
+                }
+            };
+
+            let mut input_samples = 0i64;
+            let mut input_times = CaptureTimes::new(sys::AVRational {
+                num: 1,
+                den: (*dec_ctx).sample_rate,
+            });
+
+            while sys::av_read_frame(fmt_ctx, packet) >= 0 {
+                let read_at = Instant::now();
+                if *stop_signal.blocking_lock() {
+                    sys::av_packet_unref(packet);
+                    break;
+                }
+
+                if (*packet).stream_index == audio_stream_index
+                    && sys::avcodec_send_packet(dec_ctx, packet) >= 0
+                {
+                    while sys::avcodec_receive_frame(dec_ctx, decoded_frame) >= 0 {
+                        // The packet was read as its last sample came in.
+                        let length = Duration::from_secs_f64(
+                            (*decoded_frame).nb_samples as f64 / (*dec_ctx).sample_rate as f64,
+                        );
+                        let captured_at = read_at.checked_sub(length).unwrap_or(read_at);
+
+                        let Some(graph) = filters.as_mut() else {
+                            encode_frame(decoded_frame, captured_at);
+                            continue;
+                        };
+                        // Number the input by sample so filtered frames can be
+                        // traced back to when they were captured.
+                        (*decoded_frame).pts = input_samples;
+                        input_times.record(input_samples, captured_at);
+                        input_samples += (*decoded_frame).nb_samples as i64;
+                        if let Err(e) = graph.push(decoded_frame) {
+                            error!("[recorder] {}", e);
+                            continue;
+                        }
+                        while graph.pull(filtered_frame) {
+                            let input_pts = sys::av_rescale_q(
+                                (*filtered_frame).pts,
+                                graph.time_base(),
+                                input_times.time_base(),
+                            );
+                            let filtered_at = input_times.at(input_pts).unwrap_or(captured_at);
+                            encode_frame(filtered_frame, filtered_at);
+                            sys::av_frame_unref(filtered_frame);
+                        }
+                    }
+                    stats.lock().unwrap().audio_progress(
+                        stream,
+                        samples(sys::av_audio_fifo_size(fifo) as i64),
+                        filters
+                            .as_ref()
+                            .map(|graph| graph.cpu_time)
+                            .unwrap_or_default(),
+                    );
+                }
+                sys::av_packet_unref(packet);
+            }
+
+            info!(
+                "[recorder] Audio capture thread stopped. Encoded packets: {}",
+                encoded_packets
+            );
+            if let Some(graph) = &filters {
+                let captured =
+                    Duration::from_secs_f64(next_pts as f64 / (*enc_ctx).sample_rate as f64);
+                info!(
+                    "[recorder] Audio filters used {:?} of CPU for {:?} of audio ({:.2}%)",
+                    graph.cpu_time,
+                    captured,
+                    100.0 * graph.cpu_time.as_secs_f64() / captured.as_secs_f64().max(0.001)
+                );
+            }
+        }
+
+        // Cleanup resources
+        if !fifo.is_null() {
+            sys::av_audio_fifo_free(fifo);
+        }
+        sys::av_frame_free(&mut enc_frame);
+        sys::av_frame_free(&mut resampled_frame);
+        sys::av_frame_free(&mut filtered_frame);
+        sys::av_frame_free(&mut decoded_frame);
+        sys::av_packet_free(&mut packet);
+
+        sys::swr_free(&mut swr_ctx);
+        sys::avcodec_free_context(&mut dec_ctx);
+        sys::avcodec_free_context(&mut enc_ctx);
+        sys::avformat_close_input(&mut fmt_ctx);
+    }
+}
@@ -0,0 +1,840 @@
+//! Capture pipeline shared by the platform recorders. Each platform only
+//! decides which FFmpeg input device to grab the screen from; decoding,
+//! scaling, encoding and feeding the replay buffer happen here, alongside any
+//! audio tracks.
+
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use common::avdict::AVDict;
+use common::cstring;
+use common::log::{error, info};
+use common::sys;
+use common::tokio::sync::Mutex;
+use storage::{OverwritePolicy, PacketTiming, ReplayBuffer, SavedClip, VIDEO_STREAM};
+
+use crate::audio::{self, MIC_STREAM, SYSTEM_AUDIO_STREAM};
+use crate::clock::{
+    CaptureTimes, FramePacer, FramePacing, FrameTimestamps, InputTimeline, SessionClock,
+};
+use crate::codecpar::CodecParPtr;
+use crate::encoder;
+use crate::filter::VideoFilterGraph;
+use crate::recorder::{CaptureRegion, RecorderConfig};
+use crate::scaler::{Scaler, crop_frame};
+use crate::stats::{RecorderStats, SessionStats, SharedStats};
+
+type ArcM<T> = Arc<Mutex<T>>;
+
+/// Codec parameters of every stream in the replay buffer, indexed by stream.
+/// A stream stays `None` until its encoder is open, and for good if its
+/// device failed.
+pub(crate) type StreamParams = Arc<std::sync::Mutex<Vec<Option<CodecParPtr>>>>;
+
+/// Number of stream slots a session reserves.
+const STREAM_COUNT: usize = 3;
+
+/// The FFmpeg input device a platform captures the screen from.
+pub struct VideoInput {
+    /// Input format name, e.g. `gdigrab`.
+    pub format: &'static str,
+    pub url: String,
+    pub options: Vec<(String, String)>,
+    /// Region cut out of each frame after decoding, for devices that cannot
+    /// capture part of the screen themselves.
+    pub crop: Option<CaptureRegion>,
+    /// The window being captured, if any. Capture is reopened when it
+    /// changes size and ends when it closes.
+    pub window: Option<Box<dyn WindowWatch>>,
+    /// Extra lines logged when the device fails to open.
+    pub help: &'static [&'static str],
+}
+
+/// A captured window, polled so the capture can follow it.
+pub trait WindowWatch: Send {
+    /// Current size of the window, or `None` once it has closed.
+    fn size(&self) -> Option<(u32, u32)>;
+}
+
+/// One recording session: the replay buffer plus the capture threads that
+/// fill it.
+pub struct CaptureSession {
+    config: RecorderConfig,
+    stop_signal: ArcM<bool>,
+    replay_buffer: Arc<ReplayBuffer>,
+    streams: StreamParams,
+    /// Name of the video encoder that opened, e.g. `h264_nvenc` or, after a
+    /// fallback, `libx264`. Empty until the encoder is open.
+    video_encoder: Arc<std::sync::Mutex<String>>,
+    force_keyframe: Arc<AtomicBool>,
+    /// Whether the push-to-talk key is currently held.
+    talk_gate: Arc<AtomicBool>,
+    stats: SharedStats,
+}
+
+impl CaptureSession {
+    pub fn new(config: RecorderConfig) -> Self {
+        // Initialize FFmpeg
+        unsafe { sys::avdevice_register_all() };
+
+        let estimated_packets = (config.fps as usize) * (config.buffer_secs as usize) * 2;
+        let replay_buffer = Arc::new(ReplayBuffer::new(config.buffer_secs, estimated_packets));
+
+        Self {
+            config,
+            stop_signal: Arc::new(Mutex::new(false)),
+            replay_buffer,
+            streams: Arc::new(std::sync::Mutex::new(vec![None; STREAM_COUNT])),
+            video_encoder: Arc::default(),
+            force_keyframe: Arc::new(AtomicBool::new(false)),
+            talk_gate: Arc::new(AtomicBool::new(false)),
+            stats: SharedStats::default(),
+        }
+    }
+
+    pub fn config(&self) -> &RecorderConfig {
+        &self.config
+    }
+
+    /// Spawns the video capture thread and, if configured, the audio ones.
+    /// All of them stamp frames against one [`SessionClock`].
+    pub async fn start(&mut self, video_input: VideoInput) {
+        *self.stop_signal.lock().await = false;
+        *self.stats.lock().unwrap() = SessionStats::default();
+        let clock = SessionClock::start();
+
+        let stop = self.stop_signal.clone();
+        let buf = self.replay_buffer.clone();
+        let config = self.config.clone();
+        let streams = self.streams.clone();
+        let video_encoder = self.video_encoder.clone();
+        let force_keyframe = self.force_keyframe.clone();
+        let stats = self.stats.clone();
+
+        common::tokio::task::spawn_blocking(move || {
+            capture_encode_loop_sys(
+                video_input,
+                clock,
+                config,
+                buf,
+                stop,
+                streams,
+                video_encoder,
+                force_keyframe,
+                stats,
+            );
+        });
+
+        let audio_tracks = [
+            (SYSTEM_AUDIO_STREAM, self.config.system_audio.clone()),
+            (MIC_STREAM, self.config.microphone.clone()),
+        ];
+        for (stream, audio_config) in audio_tracks {
+            let Some(audio_config) = audio_config else {
+                continue;
+            };
+            let stop = self.stop_signal.clone();
+            let buf = self.replay_buffer.clone();
+            let streams = self.streams.clone();
+            let talk_gate = self.talk_gate.clone();
+            let stats = self.stats.clone();
+            common::tokio::task::spawn_blocking(move || {
+                audio::capture_audio_loop(
+                    stream,
+                    audio_config,
+                    clock,
+                    buf,
+                    stop,
+                    streams,
+                    talk_gate,
+                    stats,
+                );
+            });
+        }
+
+        info!("[recorder] Capture threads started");
+    }
+
+    pub async fn stop(&mut self) {
+        *self.stop_signal.lock().await = true;
+    }
+
+    pub fn save(
+        &self,
+        final_output_path: &str,
+        overwrite: OverwritePolicy,
+    ) -> Result<SavedClip, String> {
+        info!("[recorder] Saving replay buffer to {}", final_output_path);
+        self.replay_buffer.save_to_file(
+            final_output_path,
+            &self.stream_params(),
+            &self.video_encoder.lock().unwrap(),
+            overwrite,
+        )
+    }
+
+    pub fn save_range(
+        &self,
+        final_output_path: &str,
+        start: Instant,
+        end: Instant,
+        overwrite: OverwritePolicy,
+    ) -> Result<SavedClip, String> {
+        info!("[recorder] Saving replay range to {}", final_output_path);
+        self.replay_buffer.save_range_to_file(
+            final_output_path,
+            &self.stream_params(),
+            &self.video_encoder.lock().unwrap(),
+            start,
+            end,
+            overwrite,
+        )
+    }
+
+    pub fn request_keyframe(&self) {
+        self.force_keyframe.store(true, Ordering::Relaxed);
+    }
+
+    pub fn set_push_to_talk(&self, held: bool) {
+        self.talk_gate.store(held, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> RecorderStats {
+        self.stats.lock().unwrap().snapshot(&self.replay_buffer)
+    }
+
+    fn stream_params(&self) -> Vec<Option<*mut sys::AVCodecParameters>> {
+        let streams = self.streams.lock().unwrap();
+        if streams[VIDEO_STREAM].is_none() {
+            return Vec::new();
+        }
+        streams.iter().map(|s| s.map(|p| p.0)).collect()
+    }
+}
+
+/// Opens `url` with the named input device and reads its stream info.
+pub(crate) unsafe fn open_input(
+    format: &str,
+    url: &str,
+    options: &[(String, String)],
+) -> Result<*mut sys::AVFormatContext, String> {
+    unsafe {
+        let input_format = sys::av_find_input_format(cstring!(format).as_ptr());
+        if input_format.is_null() {
+            return Err(format!("Failed to find {} input format", format));
+        }
+
+        let mut dict = AVDict::new();
+        for (key, value) in options {
+            dict.set(key, value);
+        }
+
+        let mut fmt_ctx: *mut sys::AVFormatContext = ptr::null_mut();
+        let url_c = cstring!(url);
+        let open_result = sys::avformat_open_input(
+            &mut fmt_ctx,
+            url_c.as_ptr(),
+            input_format,
+            dict.as_mut_ptr(),
+        );
+        if open_result < 0 {
+            return Err(format!(
+                "Failed to open {} input {:?}. Error code: {}",
+                format, url, open_result
+            ));
+        }
+
+        if sys::avformat_find_stream_info(fmt_ctx, ptr::null_mut()) < 0 {
+            sys::avformat_close_input(&mut fmt_ctx);
+            return Err(format!(
+                "Failed to find stream info for {} input - the capture device may not be available",
+                format
+            ));
+        }
+        Ok(fmt_ctx)
+    }
+}
+
+/// Finds the first stream of `media_type` in `fmt_ctx` and opens a decoder
+/// for it. Returns the stream index and the decoder context.
+pub(crate) unsafe fn open_decoder(
+    fmt_ctx: *mut sys::AVFormatContext,
+    media_type: sys::AVMediaType,
+) -> Result<(i32, *mut sys::AVCodecContext), String> {
+    unsafe {
+        let stream_index = (0..(*fmt_ctx).nb_streams as i32)
+            .find(|&i| {
+                let stream = *(*fmt_ctx).streams.add(i as usize);
+                (*(*stream).codecpar).codec_type == media_type
+            })
+            .ok_or_else(|| {
+                format!(
+                    "Failed to find {:?} stream in {} available streams",
+                    media_type,
+                    (*fmt_ctx).nb_streams
+                )
+            })?;
+
+        let input_stream = *(*fmt_ctx).streams.add(stream_index as usize);
+        let input_codecpar = (*input_stream).codecpar;
+
+        let decoder = sys::avcodec_find_decoder((*input_codecpar).codec_id);
+        if decoder.is_null() {
+            return Err(format!(
+                "Failed to find decoder for codec ID: {:?}",
+                (*input_codecpar).codec_id
+            ));
+        }
+
+        let mut dec_ctx = sys::avcodec_alloc_context3(decoder);
+        if dec_ctx.is_null() {
+            return Err("Failed to allocate decoder context".to_string());
+        }
+
+        if sys::avcodec_parameters_to_context(dec_ctx, input_codecpar) < 0 {
+            sys::avcodec_free_context(&mut dec_ctx);
+            return Err("Failed to copy decoder parameters".to_string());
+        }
+
+        if sys::avcodec_open2(dec_ctx, decoder, ptr::null_mut()) < 0 {
+            sys::avcodec_free_context(&mut dec_ctx);
+            return Err("Failed to open decoder".to_string());
+        }
+        Ok((stream_index, dec_ctx))
+    }
+}
+
+/// Publishes the codec parameters of an opened encoder for `save()`.
+pub(crate) unsafe fn publish_stream(
+    streams: &StreamParams,
+    stream: usize,
+    enc_ctx: *mut sys::AVCodecContext,
+) {
+    unsafe {
+        let encoder_codecpar = sys::avcodec_parameters_alloc();
+        if sys::avcodec_parameters_from_context(encoder_codecpar, enc_ctx) >= 0 {
+            let mut lock = streams.lock().unwrap();
+            lock[stream] = Some(CodecParPtr(encoder_codecpar));
+        }
+    }
+}
+
+/// Drains every packet the encoder has ready into the replay buffer, stamped
+/// with the capture time of their frames, and counts them in `stats`.
+/// Returns how many packets were added.
+pub(crate) unsafe fn drain_encoder(
+    enc_ctx: *mut sys::AVCodecContext,
+    stream: usize,
+    replay_buffer: &ReplayBuffer,
+    capture_times: &CaptureTimes,
+    stats: &SharedStats,
+) -> u64 {
+    unsafe {
+        let mut added = 0;
+        loop {
+            let mut enc_packet = sys::av_packet_alloc();
+            let ret = sys::avcodec_receive_packet(enc_ctx, enc_packet);
+            if ret == sys::AVERROR(sys::EAGAIN) || ret == sys::AVERROR_EOF {
+                sys::av_packet_free(&mut enc_packet);
+                break;
+            } else if ret < 0 {
+                error!("[recorder] Error receiving packet from encoder");
+                sys::av_packet_free(&mut enc_packet);
+                break;
+            }
+
+            let is_key = ((*enc_packet).flags & sys::AV_PKT_FLAG_KEY as i32) != 0;
+            let data = std::slice::from_raw_parts((*enc_packet).data, (*enc_packet).size as usize);
+            replay_buffer.add_packet(
+                stream,
+                data.to_vec(),
+                is_key,
+                PacketTiming {
+                    pts: (*enc_packet).pts,
+                    dts: (*enc_packet).dts,
+                    duration: (*enc_packet).duration,
+                    time_base: (*enc_ctx).time_base,
+                },
+                capture_times
+                    .at((*enc_packet).pts)
+                    .unwrap_or_else(Instant::now),
+            );
+            added += 1;
+            stats
+                .lock()
+                .unwrap()
+                .packet_encoded(stream, (*enc_packet).size as u64);
+
+            sys::av_packet_unref(enc_packet);
+            sys::av_packet_free(&mut enc_packet);
+        }
+        added
+    }
+}
+
+/// Why a capture input stopped delivering frames.
+enum InputEnd {
+    /// The session was stopped.
+    Stopped,
+    /// The device failed or ran dry; nothing more will come from it.
+    Failed,
+    /// The captured window changed size, so the input has to be reopened.
+    Resized,
+    /// The captured window is gone.
+    Closed,
+}
+
+/// The encoding half of the video pipeline. It outlives capture inputs, so a
+/// window can be reopened at a new size while the stream carries on.
+struct VideoEncoder {
+    enc_ctx: *mut sys::AVCodecContext,
+    /// Name of the encoder the fallback chain settled on.
+    name: String,
+    scaled_frame: *mut sys::AVFrame,
+    /// User filters run on scaled frames, and the frame they output into.
+    filter: Option<VideoFilterGraph>,
+    filtered_frame: *mut sys::AVFrame,
+    /// The last frame handed on, kept to fill skipped intervals with when
+    /// pacing at a constant rate.
+    previous_frame: *mut sys::AVFrame,
+    fps: u32,
+    clock: SessionClock,
+    pacer: FramePacer,
+    capture_times: CaptureTimes,
+    total_frames: u64,
+    /// Frames sent into the encoder, and packets that came out of it.
+    submitted_frames: u64,
+    encoded_frames: u64,
+    force_keyframe: Arc<AtomicBool>,
+    replay_buffer: Arc<ReplayBuffer>,
+    stats: SharedStats,
+}
+
+impl VideoEncoder {
+    unsafe fn open(
+        config: &RecorderConfig,
+        clock: SessionClock,
+        force_keyframe: Arc<AtomicBool>,
+        replay_buffer: Arc<ReplayBuffer>,
+        stats: SharedStats,
+    ) -> Result<Self, String> {
+        let (width, height, fps) = (config.width, config.height, config.fps);
+        unsafe {
+            let encoder::OpenedEncoder {
+                ctx: mut enc_ctx,
+                name,
+            } = encoder::open_encoder(&config.encoder, width, height, fps)?;
+
+            let mut scaled_frame = sys::av_frame_alloc();
+            if scaled_frame.is_null() {
+                sys::avcodec_free_context(&mut enc_ctx);
+                return Err("Failed to allocate frame".to_string());
+            }
+            (*scaled_frame).width = width as i32;
+            (*scaled_frame).height = height as i32;
+            (*scaled_frame).format = (*enc_ctx).pix_fmt as i32;
+            (*scaled_frame).colorspace = (*enc_ctx).colorspace;
+            (*scaled_frame).color_range = (*enc_ctx).color_range;
+            (*scaled_frame).color_primaries = (*enc_ctx).color_primaries;
+            (*scaled_frame).color_trc = (*enc_ctx).color_trc;
+            if sys::av_frame_get_buffer(scaled_frame, 0) < 0 {
+                sys::av_frame_free(&mut scaled_frame);
+                sys::avcodec_free_context(&mut enc_ctx);
+                return Err("Failed to allocate frame buffer".to_string());
+            }
+
+            let mut encoder = Self {
+                enc_ctx,
+                name,
+                scaled_frame,
+                filter: None,
+                filtered_frame: sys::av_frame_alloc(),
+                previous_frame: sys::av_frame_alloc(),
+                fps,
+                clock,
+                pacer: FramePacer::new(config.pacing, fps),
+                capture_times: CaptureTimes::new((*enc_ctx).time_base),
+                total_frames: 0,
+                submitted_frames: 0,
+                encoded_frames: 0,
+                force_keyframe,
+                replay_buffer,
+                stats,
+            };
+            if encoder.filtered_frame.is_null() || encoder.previous_frame.is_null() {
+                return Err("Failed to allocate frame".to_string());
+            }
+            if let Some(chain) = config.overlays.graph(config.video_filter.as_deref())? {
+                let graph =
+                    VideoFilterGraph::new(&chain, encoder.scaled_frame, (*enc_ctx).time_base)?;
+                info!("[recorder] Video filters: {}", chain);
+                encoder.filter = Some(graph);
+            }
+            Ok(encoder)
+        }
+    }
+
+    fn width(&self) -> i32 {
+        unsafe { (*self.scaled_frame).width }
+    }
+
+    fn height(&self) -> i32 {
+        unsafe { (*self.scaled_frame).height }
+    }
+
+    /// Encodes the frame waiting in `scaled_frame`, taken `timestamp` after
+    /// the session started. Depending on the pacing it may be dropped, or
+    /// the previous frame repeated first to fill the time since it.
+    unsafe fn encode(&mut self, timestamp: Duration) {
+        unsafe {
+            let before = self.encoded_frames;
+            let slots = self.pacer.slots(timestamp);
+            if slots.is_empty() {
+                self.report(before);
+                return;
+            }
+            let slot = slots.end - 1;
+            for repeated in slots.start..slot {
+                let captured_at = self.clock.origin() + self.pacer.slot_time(repeated);
+                self.submit(self.previous_frame, repeated, captured_at);
+            }
+            self.submit(self.scaled_frame, slot, self.clock.origin() + timestamp);
+
+            if self.pacer.pacing() == FramePacing::Constant {
+                sys::av_frame_unref(self.previous_frame);
+                if sys::av_frame_ref(self.previous_frame, self.scaled_frame) < 0 {
+                    error!("[recorder] Failed to keep the last frame for repeating");
+                }
+            }
+            self.report(before);
+        }
+    }
+
+    /// Updates the session statistics, and logs them every few seconds of
+    /// video, counting from `before` encoded frames.
+    fn report(&self, before: u64) {
+        let mut stats = self.stats.lock().unwrap();
+        stats.video_progress(
+            self.pacer.dropped,
+            self.pacer.duplicated,
+            self.submitted_frames.saturating_sub(self.encoded_frames),
+            self.filter
+                .as_ref()
+                .map(|filter| filter.cpu_time)
+                .unwrap_or_default(),
+        );
+        let interval = (self.fps as u64 * 5).max(1);
+        if self.encoded_frames / interval != before / interval {
+            info!("[recorder] {}", stats.snapshot(&self.replay_buffer));
+        }
+    }
+
+    /// Encodes `frame` as the one for `slot`, after running it through the
+    /// video filters if there are any.
+    unsafe fn submit(&mut self, frame: *mut sys::AVFrame, slot: i64, captured_at: Instant) {
+        unsafe {
+            (*frame).pts = slot;
+            (*frame).duration = 1;
+            self.capture_times.record(slot, captured_at);
+
+            let Some(mut filter) = self.filter.take() else {
+                self.send(frame);
+                return;
+            };
+            match filter.push(frame) {
+                Ok(()) => {
+                    while filter.pull(self.filtered_frame) {
+                        self.send(self.filtered_frame);
+                        sys::av_frame_unref(self.filtered_frame);
+                    }
+                }
+                Err(e) => error!("[recorder] {}", e),
+            }
+            self.filter = Some(filter);
+        }
+    }
+
+    unsafe fn send(&mut self, frame: *mut sys::AVFrame) {
+        unsafe {
+            // A pending save asked for a clean cut point here.
+            (*frame).pict_type = if self.force_keyframe.swap(false, Ordering::Relaxed) {
+                sys::AVPictureType::AV_PICTURE_TYPE_I
+            } else {
+                sys::AVPictureType::AV_PICTURE_TYPE_NONE
+            };
+
+            if sys::avcodec_send_frame(self.enc_ctx, frame) >= 0 {
+                self.submitted_frames += 1;
+                self.encoded_frames += drain_encoder(
+                    self.enc_ctx,
+                    VIDEO_STREAM,
+                    &self.replay_buffer,
+                    &self.capture_times,
+                    &self.stats,
+                );
+            }
+        }
+    }
+}
+
+impl Drop for VideoEncoder {
+    fn drop(&mut self) {
+        unsafe {
+            self.filter = None;
+            sys::av_frame_free(&mut self.filtered_frame);
+            sys::av_frame_free(&mut self.previous_frame);
+            sys::av_frame_free(&mut self.scaled_frame);
+            sys::avcodec_free_context(&mut self.enc_ctx);
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn capture_encode_loop_sys(
+    video_input: VideoInput,
+    clock: SessionClock,
+    config: RecorderConfig,
+    replay_buffer: Arc<ReplayBuffer>,
+    stop_signal: ArcM<bool>,
+    streams: StreamParams,
+    video_encoder: Arc<std::sync::Mutex<String>>,
+    force_keyframe: Arc<AtomicBool>,
+    stats: SharedStats,
+) {
+    let (width, height, fps) = (config.width, config.height, config.fps);
+    unsafe {
+        let mut encoder =
+            match VideoEncoder::open(&config, clock, force_keyframe, replay_buffer, stats) {
+                Ok(encoder) => encoder,
+                Err(e) => {
+                    error!("[recorder] {}", e);
+                    return;
+                }
+            };
+        publish_stream(&streams, VIDEO_STREAM, encoder.enc_ctx);
+        *video_encoder.lock().unwrap() = encoder.name.clone();
+
+        info!(
+            "[recorder] Attempting to capture {} {:?} at {}x{} @ {}fps",
+            video_input.format, video_input.url, width, height, fps
+        );
+        loop {
+            match capture_input(&video_input, &config, &mut encoder, clock, &stop_signal) {
+                InputEnd::Stopped | InputEnd::Failed => break,
+                InputEnd::Resized => {
+                    info!("[recorder] Captured window changed size, reopening capture");
+                    // Give the window a moment to settle before reopening.
+                    std::thread::sleep(std::time::Duration::from_millis(500));
+                    if *stop_signal.blocking_lock() {
+                        break;
+                    }
+                }
+                InputEnd::Closed => {
+                    info!("[recorder] Captured window closed, video capture ended");
+                    break;
+                }
+            }
+        }
+
+        info!(
+            "[recorder] Capture thread stopped. Total frames: {}, Encoded frames: {}, Dropped: {}, Repeated: {}",
+            encoder.total_frames,
+            encoder.encoded_frames,
+            encoder.pacer.dropped,
+            encoder.pacer.duplicated
+        );
+        let stats = encoder
+            .stats
+            .lock()
+            .unwrap()
+            .snapshot(&encoder.replay_buffer);
+        info!("[recorder] {}", stats);
+        if encoder.filter.is_some() {
+            info!(
+                "[recorder] Video filters used {:?} of CPU for {:?} of video ({:.2}%)",
+                stats.video_filter_cpu_time,
+                stats.elapsed,
+                100.0 * stats.video_filter_cpu_time.as_secs_f64()
+                    / stats.elapsed.as_secs_f64().max(0.001)
+            );
+        }
+    }
+}
+
+/// Opens `video_input` and feeds its frames to `encoder` until the input
+/// ends or the session stops.
+#[allow(unused_assignments)]
+unsafe fn capture_input(
+    video_input: &VideoInput,
+    config: &RecorderConfig,
+    encoder: &mut VideoEncoder,
+    clock: SessionClock,
+    stop_signal: &ArcM<bool>,
+) -> InputEnd {
+    unsafe {
+        let mut fmt_ctx: *mut sys::AVFormatContext = ptr::null_mut();
+        let mut dec_ctx: *mut sys::AVCodecContext = ptr::null_mut();
+
+        let window_size = video_input.window.as_ref().map(|w| w.size());
+        if window_size == Some(None) {
+            return InputEnd::Closed;
+        }
+
+        fmt_ctx = match open_input(video_input.format, &video_input.url, &video_input.options) {
+            Ok(ctx) => ctx,
+            Err(e) => {
+                error!("[recorder] {}", e);
+                for line in video_input.help {
+                    error!("[recorder] {}", line);
+                }
+                return InputEnd::Failed;
+            }
+        };
+        info!("[recorder] Found {} streams", (*fmt_ctx).nb_streams);
+
+        let video_stream_index;
+        (video_stream_index, dec_ctx) =
+            match open_decoder(fmt_ctx, sys::AVMediaType::AVMEDIA_TYPE_VIDEO) {
+                Ok(opened) => opened,
+                Err(e) => {
+                    error!("[recorder] {}", e);
+                    sys::avformat_close_input(&mut fmt_ctx);
+                    return InputEnd::Failed;
+                }
+            };
+        info!(
+            "[recorder] Decoder initialized for video stream {}",
+            video_stream_index
+        );
+
+        let (source_width, source_height) = match video_input.crop {
+            Some(region) => (region.width as i32, region.height as i32),
+            None => ((*dec_ctx).width, (*dec_ctx).height),
+        };
+        let scaler = match Scaler::new(
+            source_width,
+            source_height,
+            dec_ctx,
+            encoder.scaled_frame,
+            config.fit,
+            config.scaler,
+        ) {
+            Ok(scaler) => scaler,
+            Err(e) => {
+                error!("[recorder] {}", e);
+                sys::avcodec_free_context(&mut dec_ctx);
+                sys::avformat_close_input(&mut fmt_ctx);
+                return InputEnd::Failed;
+            }
+        };
+        info!(
+            "[recorder] Scaling {}x{} -> {}x{} ({:?}, {:?})",
+            source_width,
+            source_height,
+            encoder.width(),
+            encoder.height(),
+            config.fit,
+            config.scaler
+        );
+
+        let mut timeline =
+            InputTimeline::new((*(*(*fmt_ctx).streams.add(video_stream_index as usize))).time_base);
+        let mut packet = sys::av_packet_alloc();
+        let mut decoded_frame = sys::av_frame_alloc();
+
+        let mut end = InputEnd::Failed;
+        if packet.is_null() || decoded_frame.is_null() {
+            error!("[recorder] Failed to allocate packet or frames");
+        } else {
+            info!("[recorder] Starting screen capture loop");
+            let mut last_window_check = Instant::now();
+
+            // Main capture loop
+            end = 'read: loop {
+                if sys::av_read_frame(fmt_ctx, packet) < 0 {
+                    // A window that shrank or was unmapped ends the input.
+                    break match &video_input.window {
+                        Some(window) if window.size().is_none() => InputEnd::Closed,
+                        Some(_) => InputEnd::Resized,
+                        None => InputEnd::Failed,
+                    };
+                }
+                let captured_at = Instant::now();
+                encoder.total_frames += 1;
+                encoder.stats.lock().unwrap().frame_captured(captured_at);
+                if *stop_signal.blocking_lock() {
+                    sys::av_packet_unref(packet);
+                    break InputEnd::Stopped;
+                }
+
+                if let Some(window) = &video_input.window {
+                    if captured_at.duration_since(last_window_check).as_secs() >= 1 {
+                        last_window_check = captured_at;
+                        let size = window.size();
+                        if size.is_none() {
+                            sys::av_packet_unref(packet);
+                            break InputEnd::Closed;
+                        }
+                        if Some(size) != window_size {
+                            sys::av_packet_unref(packet);
+                            break InputEnd::Resized;
+                        }
+                    }
+                }
+
+                let mut decode_started = Instant::now();
+                if (*packet).stream_index == video_stream_index
+                    && sys::avcodec_send_packet(dec_ctx, packet) >= 0
+                {
+                    while sys::avcodec_receive_frame(dec_ctx, decoded_frame) >= 0 {
+                        let decoded_at = Instant::now();
+                        // The encoder or filters may still hold the last
+                        // frame, so scale into a fresh buffer if they do.
+                        if sys::av_frame_make_writable(encoder.scaled_frame) < 0 {
+                            error!("[recorder] Failed to make the output frame writable");
+                            break;
+                        }
+                        // Scale the frame from input format to the encoder's
+                        let scaled = match video_input.crop {
+                            Some(region) => crop_frame(decoded_frame, region),
+                            None => Ok(()),
+                        }
+                        .and_then(|_| scaler.scale(decoded_frame, encoder.scaled_frame));
+                        // Ends this input only; audio keeps recording.
+                        if let Err(e) = scaled {
+                            error!("[recorder] {}", e);
+                            sys::av_packet_unref(packet);
+                            break 'read InputEnd::Failed;
+                        }
+                        let captured = captured_at.duration_since(clock.origin());
+                        let timestamp = match config.timestamps {
+                            FrameTimestamps::CaptureClock => captured,
+                            FrameTimestamps::InputPts => {
+                                timeline.timestamp((*decoded_frame).best_effort_timestamp, captured)
+                            }
+                        };
+                        let scaled_at = Instant::now();
+                        encoder.encode(timestamp);
+                        encoder.stats.lock().unwrap().frame_processed(
+                            decoded_at - decode_started,
+                            scaled_at - decoded_at,
+                            scaled_at.elapsed(),
+                        );
+                        decode_started = Instant::now();
+                    }
+                }
+                sys::av_packet_unref(packet);
+            };
+        }
+
+        // Cleanup resources
+        sys::av_frame_free(&mut decoded_frame);
+        sys::av_packet_free(&mut packet);
+        drop(scaler);
+        sys::avcodec_free_context(&mut dec_ctx);
+        sys::avformat_close_input(&mut fmt_ctx);
+        end
+    }
+}
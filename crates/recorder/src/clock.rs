@@ -0,0 +1,319 @@
+//! The clock all capture sources of a session stamp their frames against.
+
+use std::collections::VecDeque;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+use common::sys;
+
+/// How many frames [`CaptureTimes`] remembers. Far more than any encoder
+/// holds back, while keeping lookups cheap.
+const MAX_PENDING: usize = 512;
+
+/// How far an input's own timestamps may wander from the capture clock
+/// before they are no longer trusted, e.g. after the device restarted.
+const MAX_INPUT_DRIFT: Duration = Duration::from_secs(1);
+
+/// Where video frame timestamps come from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FrameTimestamps {
+    /// When the frame was read from the capture device.
+    #[default]
+    CaptureClock,
+    /// The timestamps the capture device put on its packets, which do not
+    /// include the delay of reading them. Frames without one fall back to
+    /// the capture clock.
+    InputPts,
+}
+
+/// What happens to frames that do not arrive exactly once per frame
+/// interval.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FramePacing {
+    /// Keep a constant frame rate: frames landing in an interval that
+    /// already has one are dropped, and skipped intervals repeat the
+    /// previous frame.
+    #[default]
+    Constant,
+    /// Drop frames landing in an interval that already has one, but leave
+    /// skipped intervals empty, so the clip has a variable frame rate.
+    Variable,
+}
+
+/// Monotonic time shared by every source of one recording session. Frames
+/// are stamped with [`Instant::now`] when they are captured, not when their
+/// packets come out of the encoder, so sources with different encoder delays
+/// still line up; stream timestamps are measured from [`SessionClock::origin`].
+#[derive(Clone, Copy, Debug)]
+pub struct SessionClock {
+    origin: Instant,
+}
+
+impl SessionClock {
+    pub fn start() -> Self {
+        Self {
+            origin: Instant::now(),
+        }
+    }
+
+    /// Time since the session started.
+    pub fn elapsed(&self) -> Duration {
+        self.origin.elapsed()
+    }
+
+    pub fn origin(&self) -> Instant {
+        self.origin
+    }
+}
+
+/// Remembers when the frames sent into an encoder or filter were captured,
+/// so whatever comes out the other end can be stamped with capture time.
+pub(crate) struct CaptureTimes {
+    time_base: sys::AVRational,
+    pending: VecDeque<(i64, Instant)>,
+}
+
+impl CaptureTimes {
+    pub(crate) fn new(time_base: sys::AVRational) -> Self {
+        Self {
+            time_base,
+            pending: VecDeque::with_capacity(MAX_PENDING),
+        }
+    }
+
+    pub(crate) fn time_base(&self) -> sys::AVRational {
+        self.time_base
+    }
+
+    pub(crate) fn record(&mut self, pts: i64, captured_at: Instant) {
+        if self.pending.len() == MAX_PENDING {
+            self.pending.pop_front();
+        }
+        self.pending.push_back((pts, captured_at));
+    }
+
+    /// Capture time for `pts`. Timestamps that were never recorded, e.g.
+    /// because an encoder shifts audio by its priming delay or a filter
+    /// regroups samples, are placed relative to the nearest recorded one.
+    pub(crate) fn at(&self, pts: i64) -> Option<Instant> {
+        let &(nearest, captured_at) = self
+            .pending
+            .iter()
+            .min_by_key(|(recorded, _)| recorded.abs_diff(pts))?;
+        let offset_us = unsafe {
+            sys::av_rescale_q(
+                pts - nearest,
+                self.time_base,
+                sys::AVRational {
+                    num: 1,
+                    den: 1_000_000,
+                },
+            )
+        };
+        let offset = Duration::from_micros(offset_us.unsigned_abs());
+        if offset_us >= 0 {
+            Some(captured_at + offset)
+        } else {
+            Some(captured_at.checked_sub(offset).unwrap_or(captured_at))
+        }
+    }
+}
+
+/// Turns the timestamps an input device puts on its frames into time since
+/// the session started, anchored to the capture clock at the first frame.
+pub(crate) struct InputTimeline {
+    time_base: sys::AVRational,
+    /// A frame's pts and its time since the session started.
+    anchor: Option<(i64, Duration)>,
+}
+
+impl InputTimeline {
+    pub(crate) fn new(time_base: sys::AVRational) -> Self {
+        Self {
+            time_base,
+            anchor: None,
+        }
+    }
+
+    /// Time since the session started of a frame with `pts`, read at
+    /// `captured` since the session started.
+    pub(crate) fn timestamp(&mut self, pts: i64, captured: Duration) -> Duration {
+        if pts == sys::AV_NOPTS_VALUE {
+            return captured;
+        }
+        if let Some((anchor_pts, anchor)) = self.anchor {
+            let offset_us = unsafe {
+                sys::av_rescale_q(
+                    pts - anchor_pts,
+                    self.time_base,
+                    sys::AVRational {
+                        num: 1,
+                        den: 1_000_000,
+                    },
+                )
+            };
+            let timestamp = if offset_us >= 0 {
+                anchor + Duration::from_micros(offset_us as u64)
+            } else {
+                anchor.saturating_sub(Duration::from_micros(offset_us.unsigned_abs()))
+            };
+            if timestamp.abs_diff(captured) <= MAX_INPUT_DRIFT {
+                return timestamp;
+            }
+        }
+        self.anchor = Some((pts, captured));
+        captured
+    }
+}
+
+/// Places frames on the encoder's frame grid of one slot per frame
+/// interval, dropping and duplicating them as [`FramePacing`] says.
+pub(crate) struct FramePacer {
+    pacing: FramePacing,
+    fps: u32,
+    /// Time since the session started of slot 0, the first frame.
+    origin: Option<Duration>,
+    last_slot: Option<i64>,
+    pub(crate) dropped: u64,
+    pub(crate) duplicated: u64,
+}
+
+impl FramePacer {
+    pub(crate) fn new(pacing: FramePacing, fps: u32) -> Self {
+        Self {
+            pacing,
+            fps: fps.max(1),
+            origin: None,
+            last_slot: None,
+            dropped: 0,
+            duplicated: 0,
+        }
+    }
+
+    /// Slots to fill for a frame taken `timestamp` after the session
+    /// started. The frame itself goes in the last one, and any before it
+    /// repeat the previous frame. Empty when the frame is dropped.
+    ///
+    /// Gaps longer than a second are left empty even with constant pacing,
+    /// so a stalled capture does not flood the encoder with copies.
+    pub(crate) fn slots(&mut self, timestamp: Duration) -> Range<i64> {
+        let origin = *self.origin.get_or_insert(timestamp);
+        let slot =
+            (timestamp.saturating_sub(origin).as_secs_f64() * self.fps as f64).round() as i64;
+        let Some(last) = self.last_slot else {
+            self.last_slot = Some(slot);
+            return slot..slot + 1;
+        };
+        if slot <= last {
+            self.dropped += 1;
+            return slot..slot;
+        }
+        self.last_slot = Some(slot);
+        let skipped = slot - last - 1;
+        if self.pacing == FramePacing::Constant && skipped <= self.fps as i64 {
+            self.duplicated += skipped as u64;
+            last + 1..slot + 1
+        } else {
+            slot..slot + 1
+        }
+    }
+
+    pub(crate) fn pacing(&self) -> FramePacing {
+        self.pacing
+    }
+
+    /// Time since the session started that `slot` stands for.
+    pub(crate) fn slot_time(&self, slot: i64) -> Duration {
+        self.origin.unwrap_or_default() + Duration::from_secs_f64(slot as f64 / self.fps as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn pacer_places_frames_on_slots() {
+        let mut pacer = FramePacer::new(FramePacing::Constant, 10);
+        assert_eq!(pacer.slots(ms(500)), 0..1);
+        assert_eq!(pacer.slots(ms(600)), 1..2);
+        // Up to half an interval early or late still lands on the slot.
+        assert_eq!(pacer.slots(ms(740)), 2..3);
+        assert_eq!(pacer.slot_time(2), ms(700));
+        assert_eq!((pacer.dropped, pacer.duplicated), (0, 0));
+    }
+
+    #[test]
+    fn pacer_drops_frames_in_a_filled_slot() {
+        let mut pacer = FramePacer::new(FramePacing::Constant, 10);
+        pacer.slots(ms(0));
+        pacer.slots(ms(100));
+        assert!(pacer.slots(ms(120)).is_empty());
+        assert!(pacer.slots(ms(60)).is_empty());
+        assert_eq!(pacer.slots(ms(200)), 2..3);
+        assert_eq!(pacer.dropped, 2);
+    }
+
+    #[test]
+    fn constant_pacing_repeats_frames_into_skipped_slots() {
+        let mut pacer = FramePacer::new(FramePacing::Constant, 10);
+        pacer.slots(ms(0));
+        assert_eq!(pacer.slots(ms(400)), 1..5);
+        assert_eq!(pacer.duplicated, 3);
+    }
+
+    #[test]
+    fn variable_pacing_leaves_skipped_slots_empty() {
+        let mut pacer = FramePacer::new(FramePacing::Variable, 10);
+        pacer.slots(ms(0));
+        assert_eq!(pacer.slots(ms(400)), 4..5);
+        assert_eq!(pacer.duplicated, 0);
+    }
+
+    #[test]
+    fn gaps_over_a_second_are_not_filled() {
+        let mut pacer = FramePacer::new(FramePacing::Constant, 10);
+        pacer.slots(ms(0));
+        // Ten skipped slots are a second's worth and still filled...
+        assert_eq!(pacer.slots(ms(1100)), 1..12);
+        // ...eleven are not.
+        assert_eq!(pacer.slots(ms(2300)), 23..24);
+        assert_eq!(pacer.duplicated, 10);
+    }
+
+    fn timeline() -> InputTimeline {
+        InputTimeline::new(sys::AVRational { num: 1, den: 1000 })
+    }
+
+    #[test]
+    fn timeline_follows_input_pts_from_the_first_frame() {
+        let mut timeline = timeline();
+        assert_eq!(timeline.timestamp(10_000, ms(2000)), ms(2000));
+        // Read late, but stamped when the device says.
+        assert_eq!(timeline.timestamp(10_033, ms(2050)), ms(2033));
+        assert_eq!(timeline.timestamp(9_990, ms(2060)), ms(1990));
+    }
+
+    #[test]
+    fn timeline_reanchors_after_a_jump() {
+        let mut timeline = timeline();
+        timeline.timestamp(10_000, ms(2000));
+        // The device restarted its clock.
+        assert_eq!(timeline.timestamp(50, ms(3000)), ms(3000));
+        assert_eq!(timeline.timestamp(150, ms(3110)), ms(3100));
+        // Drifting more than a second off the capture clock re-anchors too.
+        assert_eq!(timeline.timestamp(1_300, ms(5300)), ms(5300));
+    }
+
+    #[test]
+    fn timeline_uses_capture_time_without_pts() {
+        let mut timeline = timeline();
+        timeline.timestamp(10_000, ms(2000));
+        assert_eq!(timeline.timestamp(sys::AV_NOPTS_VALUE, ms(2500)), ms(2500));
+        assert_eq!(timeline.timestamp(10_600, ms(2610)), ms(2600));
+    }
+}
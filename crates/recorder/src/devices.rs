@@ -0,0 +1,296 @@
+//! What this machine can capture from and encode with, so users can pick
+//! from a list instead of guessing device names and indices.
+
+use std::ffi::{CStr, c_void};
+use std::ptr;
+
+use common::cstring;
+use common::log::warn;
+use common::sys;
+
+use crate::capture::open_input;
+use crate::encoder::CodecFamily;
+use crate::recorder::CaptureRegion;
+
+/// Input formats asked for their audio sources, in order.
+const AUDIO_INPUT_FORMATS: &[&str] = &["pulse", "alsa", "dshow"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MediaKind {
+    Video,
+    Audio,
+}
+
+/// A screen that can be recorded.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Display {
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+    /// Where the display sits on the shared desktop. Record it by passing
+    /// this as [`RecorderConfig::region`](crate::recorder::RecorderConfig).
+    pub region: Option<CaptureRegion>,
+    /// Capture device for platforms where every display is a device of its
+    /// own. Record it by passing this as
+    /// [`RecorderConfig::display`](crate::recorder::RecorderConfig).
+    pub device: Option<String>,
+    pub primary: bool,
+}
+
+/// A sound card input, microphone or output monitor.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AudioSource {
+    /// FFmpeg input format to open it with, e.g. `pulse`.
+    pub input_format: String,
+    pub device: String,
+    pub description: String,
+    /// Whether this source records what an output plays rather than a
+    /// microphone.
+    pub is_monitor: bool,
+    pub is_default: bool,
+}
+
+/// An FFmpeg capture device compiled into this build.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InputFormat {
+    pub name: String,
+    pub description: String,
+    pub kind: MediaKind,
+}
+
+/// An encoder compiled into this build. Hardware encoders are listed even if
+/// the hardware they need is missing; opening them is the only real test.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EncoderInfo {
+    pub name: String,
+    pub description: String,
+    pub kind: MediaKind,
+    /// The codec family it produces, for video codecs clips can use.
+    pub family: Option<CodecFamily>,
+    pub hardware: bool,
+}
+
+/// Everything found by [`probe`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Devices {
+    pub displays: Vec<Display>,
+    pub audio_sources: Vec<AudioSource>,
+    pub input_formats: Vec<InputFormat>,
+    pub encoders: Vec<EncoderInfo>,
+}
+
+impl Devices {
+    /// Video encoders for `family`, in FFmpeg's order.
+    pub fn encoders_for(&self, family: CodecFamily) -> impl Iterator<Item = &EncoderInfo> {
+        self.encoders
+            .iter()
+            .filter(move |encoder| encoder.family == Some(family))
+    }
+}
+
+/// Lists displays, audio sources, capture devices and encoders. Talks to the
+/// display and sound servers, so this can take a moment.
+pub fn probe() -> Devices {
+    unsafe { sys::avdevice_register_all() };
+    let displays = displays().unwrap_or_else(|e| {
+        warn!("[recorder] Could not list displays: {}", e);
+        Vec::new()
+    });
+    Devices {
+        displays,
+        audio_sources: audio_sources(),
+        input_formats: input_formats(),
+        encoders: encoders(),
+    }
+}
+
+/// Monitors as the X server's RandR extension reports them.
+#[cfg(target_os = "linux")]
+pub fn displays() -> Result<Vec<Display>, String> {
+    crate::x11_window::monitors()
+}
+
+/// gdigrab records the desktop as one surface, so there is a single entry
+/// sized to span every monitor.
+#[cfg(target_os = "windows")]
+pub fn displays() -> Result<Vec<Display>, String> {
+    let (width, height) = probe_size("gdigrab", "desktop")?;
+    Ok(vec![Display {
+        name: "Desktop".to_string(),
+        width,
+        height,
+        region: None,
+        device: None,
+        primary: true,
+    }])
+}
+
+/// AVFoundation lists each screen as a device named `Capture screen N`,
+/// after the cameras.
+#[cfg(target_os = "macos")]
+pub fn displays() -> Result<Vec<Display>, String> {
+    let mut displays = Vec::new();
+    for index in 0.. {
+        let device = format!("Capture screen {}", index);
+        let Ok((width, height)) = probe_size("avfoundation", &format!("{}:", device)) else {
+            break;
+        };
+        displays.push(Display {
+            name: format!("Screen {}", index + 1),
+            width,
+            height,
+            region: None,
+            device: Some(device),
+            primary: index == 0,
+        });
+    }
+    if displays.is_empty() {
+        return Err("No screens found. Check the screen recording permission".to_string());
+    }
+    Ok(displays)
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "linux", target_os = "macos")))]
+pub fn displays() -> Result<Vec<Display>, String> {
+    Err("Listing displays is not supported on this OS".to_string())
+}
+
+/// Opens a capture device just long enough to read its frame size.
+fn probe_size(format: &str, url: &str) -> Result<(u32, u32), String> {
+    unsafe {
+        let mut fmt_ctx = open_input(format, url, &[])?;
+        let size = (0..(*fmt_ctx).nb_streams as usize)
+            .map(|i| (*(*(*fmt_ctx).streams.add(i))).codecpar)
+            .find(|&par| (*par).codec_type == sys::AVMediaType::AVMEDIA_TYPE_VIDEO)
+            .map(|par| ((*par).width as u32, (*par).height as u32));
+        sys::avformat_close_input(&mut fmt_ctx);
+        size.ok_or_else(|| format!("{} input {:?} has no video", format, url))
+    }
+}
+
+/// Audio inputs of every sound server that can list them.
+pub fn audio_sources() -> Vec<AudioSource> {
+    let mut sources = Vec::new();
+    for &format in AUDIO_INPUT_FORMATS {
+        unsafe {
+            let input_format = sys::av_find_input_format(cstring!(format).as_ptr());
+            if input_format.is_null() {
+                continue;
+            }
+            let mut list: *mut sys::AVDeviceInfoList = ptr::null_mut();
+            // Formats without device listing fail here; so does a sound
+            // server that is not running.
+            if sys::avdevice_list_input_sources(
+                input_format,
+                ptr::null(),
+                ptr::null_mut(),
+                &mut list,
+            ) < 0
+            {
+                sys::avdevice_free_list_devices(&mut list);
+                continue;
+            }
+            for i in 0..(*list).nb_devices as usize {
+                let info = *(*list).devices.add(i);
+                let media_types = if (*info).media_types.is_null() {
+                    &[][..]
+                } else {
+                    std::slice::from_raw_parts((*info).media_types, (*info).nb_media_types as usize)
+                };
+                // Devices that do not say what they carry are assumed to be
+                // audio, since only audio formats are asked.
+                if !media_types.is_empty()
+                    && !media_types.contains(&sys::AVMediaType::AVMEDIA_TYPE_AUDIO)
+                {
+                    continue;
+                }
+                let mut device = c_string((*info).device_name);
+                let is_monitor = format == "pulse" && device.ends_with(".monitor");
+                // DirectShow lists audio and video devices together and
+                // needs to be told which kind to open.
+                if format == "dshow" {
+                    device = format!("audio={}", device);
+                }
+                sources.push(AudioSource {
+                    input_format: format.to_string(),
+                    is_monitor,
+                    is_default: i as i32 == (*list).default_device,
+                    description: c_string((*info).device_description),
+                    device,
+                });
+            }
+            sys::avdevice_free_list_devices(&mut list);
+        }
+    }
+    sources
+}
+
+/// Capture devices compiled into this FFmpeg build.
+pub fn input_formats() -> Vec<InputFormat> {
+    let mut formats = Vec::new();
+    unsafe {
+        let mut video = sys::av_input_video_device_next(ptr::null());
+        while !video.is_null() {
+            formats.push(InputFormat {
+                name: c_string((*video).name),
+                description: c_string((*video).long_name),
+                kind: MediaKind::Video,
+            });
+            video = sys::av_input_video_device_next(video);
+        }
+        let mut audio = sys::av_input_audio_device_next(ptr::null());
+        while !audio.is_null() {
+            formats.push(InputFormat {
+                name: c_string((*audio).name),
+                description: c_string((*audio).long_name),
+                kind: MediaKind::Audio,
+            });
+            audio = sys::av_input_audio_device_next(audio);
+        }
+    }
+    formats
+}
+
+/// Audio and video encoders compiled into this FFmpeg build.
+pub fn encoders() -> Vec<EncoderInfo> {
+    let mut encoders = Vec::new();
+    let mut opaque: *mut c_void = ptr::null_mut();
+    unsafe {
+        loop {
+            let codec = sys::av_codec_iterate(&mut opaque);
+            if codec.is_null() {
+                break;
+            }
+            if sys::av_codec_is_encoder(codec) == 0 {
+                continue;
+            }
+            let kind = match (*codec).type_ {
+                sys::AVMediaType::AVMEDIA_TYPE_VIDEO => MediaKind::Video,
+                sys::AVMediaType::AVMEDIA_TYPE_AUDIO => MediaKind::Audio,
+                _ => continue,
+            };
+            let family = match (*codec).id {
+                sys::AVCodecID::AV_CODEC_ID_H264 => Some(CodecFamily::H264),
+                sys::AVCodecID::AV_CODEC_ID_HEVC => Some(CodecFamily::Hevc),
+                sys::AVCodecID::AV_CODEC_ID_AV1 => Some(CodecFamily::Av1),
+                sys::AVCodecID::AV_CODEC_ID_VP9 => Some(CodecFamily::Vp9),
+                _ => None,
+            };
+            encoders.push(EncoderInfo {
+                name: c_string((*codec).name),
+                description: c_string((*codec).long_name),
+                kind,
+                family,
+                hardware: (*codec).capabilities & sys::AV_CODEC_CAP_HARDWARE as i32 != 0,
+            });
+        }
+    }
+    encoders
+}
+
+fn c_string(ptr: *const std::ffi::c_char) -> String {
+    if ptr.is_null() {
+        return String::new();
+    }
+    unsafe { CStr::from_ptr(ptr).to_string_lossy().into_owned() }
+}
@@ -0,0 +1,481 @@
+//! Picks the video encoder and opens it with the configured rate control.
+
+use std::ffi::CStr;
+use std::ptr;
+
+use common::avdict::AVDict;
+use common::cstring;
+use common::log::{info, warn};
+use common::sys;
+
+/// The compression standard clips are encoded with. Each family has its own
+/// chain of encoders to try, hardware first.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CodecFamily {
+    #[default]
+    H264,
+    Hevc,
+    Av1,
+    Vp9,
+}
+
+impl CodecFamily {
+    /// Encoders tried when [`EncoderConfig::encoders`] is empty, in order of
+    /// preference. The software encoder at the end works on any machine.
+    pub fn default_encoders(self) -> &'static [&'static str] {
+        match self {
+            #[cfg(target_os = "macos")]
+            CodecFamily::H264 => &["h264_videotoolbox", "libx264"],
+            #[cfg(not(target_os = "macos"))]
+            CodecFamily::H264 => &["h264_nvenc", "libx264"],
+            #[cfg(target_os = "macos")]
+            CodecFamily::Hevc => &["hevc_videotoolbox", "libx265"],
+            #[cfg(not(target_os = "macos"))]
+            CodecFamily::Hevc => &["hevc_nvenc", "libx265"],
+            CodecFamily::Av1 => &["av1_nvenc", "libsvtav1", "libaom-av1"],
+            CodecFamily::Vp9 => &["libvpx-vp9"],
+        }
+    }
+}
+
+/// How the encoder trades size for quality.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateControl {
+    /// Constant quality: CRF for software encoders, CQ for NVENC. Lower is
+    /// better.
+    Quality(u32),
+    /// Constant bitrate in kbit/s.
+    Cbr { bitrate_kbps: u32 },
+    /// Variable bitrate averaging `target_kbps`, never exceeding `max_kbps`.
+    Vbr { target_kbps: u32, max_kbps: u32 },
+}
+
+/// The YUV matrix, primaries and transfer curve clips are converted to and
+/// tagged with. Screens are sRGB, which shares BT.709's primaries, so BT.709
+/// suits almost everything; BT.601 is for players stuck on SD defaults.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorSpace {
+    Bt601,
+    #[default]
+    Bt709,
+}
+
+impl ColorSpace {
+    pub fn matrix(self) -> sys::AVColorSpace {
+        match self {
+            ColorSpace::Bt601 => sys::AVColorSpace::AVCOL_SPC_SMPTE170M,
+            ColorSpace::Bt709 => sys::AVColorSpace::AVCOL_SPC_BT709,
+        }
+    }
+
+    pub fn primaries(self) -> sys::AVColorPrimaries {
+        match self {
+            ColorSpace::Bt601 => sys::AVColorPrimaries::AVCOL_PRI_SMPTE170M,
+            ColorSpace::Bt709 => sys::AVColorPrimaries::AVCOL_PRI_BT709,
+        }
+    }
+
+    pub fn transfer(self) -> sys::AVColorTransferCharacteristic {
+        match self {
+            ColorSpace::Bt601 => sys::AVColorTransferCharacteristic::AVCOL_TRC_SMPTE170M,
+            ColorSpace::Bt709 => sys::AVColorTransferCharacteristic::AVCOL_TRC_BT709,
+        }
+    }
+}
+
+/// Whether luma uses the broadcast 16-235 range or all of 0-255. Limited is
+/// what players assume when a file does not say.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorRange {
+    #[default]
+    Limited,
+    Full,
+}
+
+impl ColorRange {
+    pub fn range(self) -> sys::AVColorRange {
+        match self {
+            ColorRange::Limited => sys::AVColorRange::AVCOL_RANGE_MPEG,
+            ColorRange::Full => sys::AVColorRange::AVCOL_RANGE_JPEG,
+        }
+    }
+}
+
+/// How captured frames are encoded before they enter the replay buffer.
+///
+/// Unset fields fall back to low-latency defaults for whichever encoder ends
+/// up being used.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EncoderConfig {
+    pub codec: CodecFamily,
+    /// Encoder names to try in order, e.g. `["hevc_nvenc", "libx265"]`. Empty
+    /// means [`CodecFamily::default_encoders`].
+    pub encoders: Vec<String>,
+    pub preset: Option<String>,
+    pub profile: Option<String>,
+    /// FFmpeg pixel format name such as `yuv420p` or `nv12`. Defaults to
+    /// `yuv420p`.
+    pub pixel_format: Option<String>,
+    pub color_space: ColorSpace,
+    pub color_range: ColorRange,
+    /// Unset uses each encoder's default constant-quality level.
+    pub rate_control: Option<RateControl>,
+    /// Size of the rate-control buffer in kbit. Only meaningful with CBR or
+    /// VBR; defaults to one second at the maximum bitrate.
+    pub vbv_buffer_kbits: Option<u32>,
+    /// Frames between keyframes. Defaults to one second. Saved clips can only
+    /// start on a keyframe, so longer GOPs make cut points coarser.
+    pub gop_length: Option<u32>,
+    pub b_frames: u32,
+    /// Extra private options passed to the encoder, applied last so they
+    /// override everything else.
+    pub options: Vec<(String, String)>,
+}
+
+impl EncoderConfig {
+    pub fn encoder_chain(&self) -> Vec<String> {
+        if self.encoders.is_empty() {
+            self.codec
+                .default_encoders()
+                .iter()
+                .map(|s| s.to_string())
+                .collect()
+        } else {
+            self.encoders.clone()
+        }
+    }
+
+    /// Checks the settings that do not depend on which encoder is picked.
+    pub fn validate(&self) -> Result<(), String> {
+        self.pix_fmt()?;
+
+        match self.rate_control {
+            Some(RateControl::Cbr { bitrate_kbps: 0 }) => {
+                return Err("CBR bitrate must be above zero".to_string());
+            }
+            Some(RateControl::Vbr {
+                target_kbps,
+                max_kbps,
+            }) => {
+                if target_kbps == 0 {
+                    return Err("VBR target bitrate must be above zero".to_string());
+                }
+                if max_kbps < target_kbps {
+                    return Err(format!(
+                        "VBR max bitrate ({} kbps) is below the target ({} kbps)",
+                        max_kbps, target_kbps
+                    ));
+                }
+            }
+            Some(RateControl::Quality(_)) | None if self.vbv_buffer_kbits.is_some() => {
+                return Err("A VBV buffer needs CBR or VBR rate control".to_string());
+            }
+            _ => {}
+        }
+
+        if self.gop_length == Some(0) {
+            return Err("GOP length must be at least one frame".to_string());
+        }
+        if self.b_frames > 16 {
+            return Err(format!(
+                "{} B-frames is more than the maximum of 16",
+                self.b_frames
+            ));
+        }
+        Ok(())
+    }
+
+    /// Resolves [`EncoderConfig::pixel_format`] to an FFmpeg pixel format.
+    pub fn pix_fmt(&self) -> Result<sys::AVPixelFormat, String> {
+        let name = self.pixel_format.as_deref().unwrap_or("yuv420p");
+        let pix_fmt = unsafe { sys::av_get_pix_fmt(cstring!(name).as_ptr()) };
+        if pix_fmt == sys::AVPixelFormat::AV_PIX_FMT_NONE {
+            return Err(format!("Unknown pixel format: {}", name));
+        }
+        Ok(pix_fmt)
+    }
+}
+
+/// Built-in settings per encoder, tuned for real-time capture.
+fn default_options(encoder: &str) -> &'static [(&'static str, &'static str)] {
+    match encoder {
+        "h264_nvenc" | "hevc_nvenc" | "av1_nvenc" => &[
+            ("preset", "p5"), // p4 or p5 are good balances of speed/quality
+            ("tune", "ll"),   // Low-latency tune
+            ("forced-idr", "1"),
+        ],
+        "h264_videotoolbox" => &[("profile", "main"), ("level", "4.0"), ("realtime", "1")],
+        "hevc_videotoolbox" => &[("realtime", "1")],
+        // No `tune=zerolatency`: it turns off the B-frames that
+        // `EncoderConfig::b_frames` asks for, and clips are muxed from the
+        // buffer long after encoding, so latency does not matter.
+        "libx264" => &[
+            ("preset", "veryfast"),
+            ("forced-idr", "1"), // Forced keyframes must be IDR
+        ],
+        "libx265" => &[("preset", "veryfast"), ("forced-idr", "1")],
+        "libsvtav1" => &[("preset", "10")],
+        "libaom-av1" => &[("cpu-used", "8"), ("usage", "realtime")],
+        "libvpx-vp9" => &[("deadline", "realtime"), ("cpu-used", "8"), ("row-mt", "1")],
+        _ => &[],
+    }
+}
+
+/// Constant-quality level used when no rate control is configured, and the
+/// highest level the encoder accepts.
+fn quality_range(encoder: &str) -> Option<(u32, u32)> {
+    match encoder {
+        "h264_nvenc" | "hevc_nvenc" => Some((24, 51)),
+        "av1_nvenc" => Some((30, 63)),
+        "libx264" => Some((22, 51)),
+        "libx265" => Some((24, 51)),
+        "libsvtav1" | "libaom-av1" => Some((35, 63)),
+        "libvpx-vp9" => Some((32, 63)),
+        _ => None,
+    }
+}
+
+/// Applies `rate_control` to a not-yet-opened encoder. Fails when the encoder
+/// cannot do the requested mode.
+unsafe fn apply_rate_control(
+    name: &str,
+    config: &EncoderConfig,
+    enc_ctx: *mut sys::AVCodecContext,
+    enc_opts: &mut AVDict,
+) -> Result<(), String> {
+    let is_nvenc = name.ends_with("_nvenc");
+    let rate_control = match config.rate_control {
+        Some(rate_control) => rate_control,
+        None => match quality_range(name) {
+            Some((default, _)) => RateControl::Quality(default),
+            None => return Ok(()),
+        },
+    };
+
+    unsafe {
+        match rate_control {
+            RateControl::Quality(level) => {
+                let (_, max) = quality_range(name)
+                    .ok_or_else(|| "constant quality is not supported".to_string())?;
+                if level > max {
+                    return Err(format!("quality {} is above the maximum of {}", level, max));
+                }
+                if is_nvenc {
+                    enc_opts.set("rc", "vbr");
+                    enc_opts.set("cq", &level.to_string());
+                } else {
+                    enc_opts.set("crf", &level.to_string());
+                }
+                // libvpx only uses constant quality without a bitrate target.
+                (*enc_ctx).bit_rate = 0;
+            }
+            RateControl::Cbr { bitrate_kbps } => {
+                let bitrate = bitrate_kbps as i64 * 1000;
+                (*enc_ctx).bit_rate = bitrate;
+                (*enc_ctx).rc_min_rate = bitrate;
+                (*enc_ctx).rc_max_rate = bitrate;
+                (*enc_ctx).rc_buffer_size =
+                    vbv_buffer_bits(config.vbv_buffer_kbits.unwrap_or(bitrate_kbps))?;
+                match name {
+                    _ if is_nvenc => enc_opts.set("rc", "cbr"),
+                    "libx264" => enc_opts.set("nal-hrd", "cbr"),
+                    "libaom-av1" => enc_opts.set("end-usage", "cbr"),
+                    "h264_videotoolbox" | "hevc_videotoolbox" => {
+                        enc_opts.set("constant_bit_rate", "1")
+                    }
+                    "libsvtav1" => return Err("CBR is not supported".to_string()),
+                    _ => {}
+                }
+            }
+            RateControl::Vbr {
+                target_kbps,
+                max_kbps,
+            } => {
+                (*enc_ctx).bit_rate = target_kbps as i64 * 1000;
+                (*enc_ctx).rc_max_rate = max_kbps as i64 * 1000;
+                (*enc_ctx).rc_buffer_size =
+                    vbv_buffer_bits(config.vbv_buffer_kbits.unwrap_or(max_kbps))?;
+                match name {
+                    _ if is_nvenc => enc_opts.set("rc", "vbr"),
+                    "libaom-av1" => enc_opts.set("end-usage", "vbr"),
+                    _ => {}
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Rate control buffer size in bits, which FFmpeg keeps in an `int`.
+fn vbv_buffer_bits(kbits: u32) -> Result<i32, String> {
+    i32::try_from(kbits as i64 * 1000)
+        .map_err(|_| format!("rate control buffer of {} kbit is too large", kbits))
+}
+
+/// libvpx has no `preset`; its closest equivalent is `deadline`.
+fn preset_option(encoder: &str) -> &'static str {
+    if encoder.starts_with("libvpx") {
+        "deadline"
+    } else {
+        "preset"
+    }
+}
+
+/// An opened encoder context. The caller owns `ctx` and must free it with
+/// `avcodec_free_context`.
+pub struct OpenedEncoder {
+    pub ctx: *mut sys::AVCodecContext,
+    pub name: String,
+}
+
+/// Opens the first encoder in the configured chain that exists in this FFmpeg
+/// build, supports the pixel format and accepts the settings.
+pub fn open_encoder(
+    config: &EncoderConfig,
+    width: u32,
+    height: u32,
+    fps: u32,
+) -> Result<OpenedEncoder, String> {
+    config.validate()?;
+    let pix_fmt = config.pix_fmt()?;
+    let mut skipped = Vec::new();
+
+    for name in config.encoder_chain() {
+        match try_open(config, &name, pix_fmt, width, height, fps) {
+            Ok(ctx) => {
+                info!("[recorder] Selected encoder: {}", name);
+                return Ok(OpenedEncoder { ctx, name });
+            }
+            Err(e) => {
+                info!("[recorder] Skipping encoder {}: {}", name, e);
+                skipped.push(format!("{}: {}", name, e));
+            }
+        }
+    }
+
+    Err(format!(
+        "None of the {:?} encoders could be opened ({})",
+        config.codec,
+        skipped.join("; ")
+    ))
+}
+
+fn try_open(
+    config: &EncoderConfig,
+    name: &str,
+    pix_fmt: sys::AVPixelFormat,
+    width: u32,
+    height: u32,
+    fps: u32,
+) -> Result<*mut sys::AVCodecContext, String> {
+    unsafe {
+        let encoder = sys::avcodec_find_encoder_by_name(cstring!(name).as_ptr());
+        if encoder.is_null() {
+            return Err("not available in this FFmpeg build".to_string());
+        }
+        if !supports_pix_fmt(encoder, pix_fmt) {
+            return Err(format!(
+                "does not support pixel format {}",
+                pix_fmt_name(pix_fmt)
+            ));
+        }
+
+        let mut enc_ctx = sys::avcodec_alloc_context3(encoder);
+        if enc_ctx.is_null() {
+            return Err("failed to allocate encoder context".to_string());
+        }
+
+        (*enc_ctx).width = width as i32;
+        (*enc_ctx).height = height as i32;
+        (*enc_ctx).pix_fmt = pix_fmt;
+        // Encoders write these into the bitstream, and they are copied to
+        // the codec parameters and from there into the container.
+        (*enc_ctx).colorspace = config.color_space.matrix();
+        (*enc_ctx).color_primaries = config.color_space.primaries();
+        (*enc_ctx).color_trc = config.color_space.transfer();
+        (*enc_ctx).color_range = config.color_range.range();
+        (*enc_ctx).time_base = sys::AVRational {
+            num: 1,
+            den: fps as i32,
+        };
+        (*enc_ctx).framerate = sys::AVRational {
+            num: fps as i32,
+            den: 1,
+        };
+
+        (*enc_ctx).gop_size = config.gop_length.unwrap_or(fps) as i32;
+        (*enc_ctx).max_b_frames = config.b_frames as i32;
+
+        let mut enc_opts = AVDict::new();
+        for (key, value) in default_options(name) {
+            enc_opts.set(key, value);
+        }
+        if let Err(e) = apply_rate_control(name, config, enc_ctx, &mut enc_opts) {
+            sys::avcodec_free_context(&mut enc_ctx);
+            return Err(e);
+        }
+        if let Some(preset) = &config.preset {
+            enc_opts.set(preset_option(name), preset);
+        }
+        if let Some(profile) = &config.profile {
+            enc_opts.set("profile", profile);
+        }
+        for (key, value) in &config.options {
+            enc_opts.set(key, value);
+        }
+
+        if sys::avcodec_open2(enc_ctx, encoder, enc_opts.as_mut_ptr()) < 0 {
+            sys::avcodec_free_context(&mut enc_ctx);
+            return Err("failed to open with the requested settings".to_string());
+        }
+
+        // avcodec_open2 leaves behind whatever the encoder did not recognise.
+        let mut entry: *mut sys::AVDictionaryEntry = ptr::null_mut();
+        loop {
+            entry = sys::av_dict_get(
+                enc_opts.inner(),
+                cstring!("").as_ptr(),
+                entry,
+                sys::AV_DICT_IGNORE_SUFFIX as i32,
+            );
+            if entry.is_null() {
+                break;
+            }
+            warn!(
+                "[recorder] {} ignored option {}={}",
+                name,
+                CStr::from_ptr((*entry).key).to_string_lossy(),
+                CStr::from_ptr((*entry).value).to_string_lossy()
+            );
+        }
+
+        Ok(enc_ctx)
+    }
+}
+
+unsafe fn supports_pix_fmt(encoder: *const sys::AVCodec, pix_fmt: sys::AVPixelFormat) -> bool {
+    unsafe {
+        let mut formats = (*encoder).pix_fmts;
+        // Encoders that do not list their formats accept anything.
+        if formats.is_null() {
+            return true;
+        }
+        while *formats != sys::AVPixelFormat::AV_PIX_FMT_NONE {
+            if *formats == pix_fmt {
+                return true;
+            }
+            formats = formats.add(1);
+        }
+        false
+    }
+}
+
+fn pix_fmt_name(pix_fmt: sys::AVPixelFormat) -> String {
+    unsafe {
+        let name = sys::av_get_pix_fmt_name(pix_fmt);
+        if name.is_null() {
+            return format!("{:?}", pix_fmt);
+        }
+        CStr::from_ptr(name).to_string_lossy().into_owned()
+    }
+}
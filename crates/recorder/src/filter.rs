@@ -0,0 +1,438 @@
+//! libavfilter processing for audio tracks and video, run between the
+//! decoder and the encoder.
+
+use std::ffi::CStr;
+use std::path::PathBuf;
+use std::ptr;
+use std::time::{Duration, Instant};
+
+use common::cstring;
+use common::sys;
+
+use crate::encoder::EncoderConfig;
+
+/// How background noise is removed from a track.
+#[derive(Clone, Debug, PartialEq)]
+pub enum NoiseReduction {
+    /// FFT denoiser (`afftdn`). Needs no setup and is cheap.
+    Fft,
+    /// RNNoise (`arnndn`) with the given model file. Better on speech but
+    /// costs more CPU.
+    Rnn { model: PathBuf },
+}
+
+/// Filters applied to an audio track before it is encoded. Presets run in
+/// the order listed here, followed by `custom`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AudioFilters {
+    pub noise_reduction: Option<NoiseReduction>,
+    /// Evens out loud and quiet speech.
+    pub compressor: bool,
+    /// EBU R128 loudness normalization to -16 LUFS.
+    pub loudnorm: bool,
+    /// Extra libavfilter chain, e.g. `highpass=f=80`.
+    pub custom: Option<String>,
+}
+
+impl AudioFilters {
+    /// The filter chain as libavfilter syntax, or `None` when nothing is
+    /// enabled.
+    pub fn graph(&self) -> Option<String> {
+        let mut chain = Vec::new();
+        match &self.noise_reduction {
+            Some(NoiseReduction::Fft) => chain.push("afftdn=nf=-25".to_string()),
+            Some(NoiseReduction::Rnn { model }) => chain.push(format!(
+                "arnndn=m={}",
+                escape_value(&model.to_string_lossy())
+            )),
+            None => {}
+        }
+        if self.compressor {
+            chain.push(
+                "acompressor=threshold=-18dB:ratio=4:attack=5:release=100:makeup=2".to_string(),
+            );
+        }
+        if self.loudnorm {
+            chain.push("loudnorm=I=-16:TP=-1.5:LRA=11".to_string());
+        }
+        if let Some(custom) = self.custom.as_deref().map(str::trim) {
+            if !custom.is_empty() {
+                chain.push(custom.to_string());
+            }
+        }
+
+        if chain.is_empty() {
+            None
+        } else {
+            Some(chain.join(","))
+        }
+    }
+}
+
+/// Escapes an option value for use inside a filter graph description. Values
+/// go through two rounds of unescaping: once as a filter argument and once as
+/// part of the graph.
+pub(crate) fn escape_value(value: &str) -> String {
+    let mut argument = String::new();
+    for c in value.chars() {
+        if matches!(c, '\\' | '\'' | ':') {
+            argument.push('\\');
+        }
+        argument.push(c);
+    }
+    let mut escaped = String::new();
+    for c in argument.chars() {
+        if matches!(c, '\\' | '\'' | '[' | ']' | ',' | ';') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// A configured `abuffer -> chain -> abuffersink` graph.
+pub(crate) struct AudioFilterGraph {
+    graph: *mut sys::AVFilterGraph,
+    source: *mut sys::AVFilterContext,
+    sink: *mut sys::AVFilterContext,
+    /// Channel layout of the frames coming out of the sink.
+    pub(crate) output_layout: sys::AVChannelLayout,
+    /// Time spent inside the filters so far.
+    pub(crate) cpu_time: Duration,
+}
+
+impl AudioFilterGraph {
+    /// Builds `chain` for frames shaped like the output of `dec_ctx`.
+    pub(crate) unsafe fn new(
+        chain: &str,
+        dec_ctx: *const sys::AVCodecContext,
+    ) -> Result<Self, String> {
+        unsafe {
+            let mut filter_graph = Self {
+                graph: sys::avfilter_graph_alloc(),
+                source: ptr::null_mut(),
+                sink: ptr::null_mut(),
+                output_layout: std::mem::zeroed(),
+                cpu_time: Duration::ZERO,
+            };
+            if filter_graph.graph.is_null() {
+                return Err("Failed to allocate filter graph".to_string());
+            }
+
+            let mut layout = [0 as std::ffi::c_char; 64];
+            sys::av_channel_layout_describe(&(*dec_ctx).ch_layout, layout.as_mut_ptr(), 64);
+            let sample_fmt = CStr::from_ptr(sys::av_get_sample_fmt_name((*dec_ctx).sample_fmt));
+            let args = format!(
+                "time_base=1/{rate}:sample_rate={rate}:sample_fmt={}:channel_layout={}",
+                sample_fmt.to_string_lossy(),
+                CStr::from_ptr(layout.as_ptr()).to_string_lossy(),
+                rate = (*dec_ctx).sample_rate,
+            );
+
+            let ret = sys::avfilter_graph_create_filter(
+                &mut filter_graph.source,
+                sys::avfilter_get_by_name(cstring!("abuffer").as_ptr()),
+                cstring!("in").as_ptr(),
+                cstring!(args).as_ptr(),
+                ptr::null_mut(),
+                filter_graph.graph,
+            );
+            if ret < 0 {
+                return Err(format!("Failed to create audio source with {}", args));
+            }
+            let ret = sys::avfilter_graph_create_filter(
+                &mut filter_graph.sink,
+                sys::avfilter_get_by_name(cstring!("abuffersink").as_ptr()),
+                cstring!("out").as_ptr(),
+                ptr::null(),
+                ptr::null_mut(),
+                filter_graph.graph,
+            );
+            if ret < 0 {
+                return Err("Failed to create audio sink".to_string());
+            }
+
+            if parse_chain(
+                filter_graph.graph,
+                filter_graph.source,
+                filter_graph.sink,
+                chain,
+            ) < 0
+            {
+                return Err(format!("Invalid audio filter chain {:?}", chain));
+            }
+            if sys::avfilter_graph_config(filter_graph.graph, ptr::null_mut()) < 0 {
+                return Err(format!(
+                    "Audio filter chain {:?} does not fit a {} Hz {} stream",
+                    chain,
+                    (*dec_ctx).sample_rate,
+                    CStr::from_ptr(layout.as_ptr()).to_string_lossy()
+                ));
+            }
+
+            sys::av_buffersink_get_ch_layout(filter_graph.sink, &mut filter_graph.output_layout);
+            Ok(filter_graph)
+        }
+    }
+
+    pub(crate) fn sample_format(&self) -> sys::AVSampleFormat {
+        unsafe { std::mem::transmute(sys::av_buffersink_get_format(self.sink)) }
+    }
+
+    pub(crate) fn sample_rate(&self) -> i32 {
+        unsafe { sys::av_buffersink_get_sample_rate(self.sink) }
+    }
+
+    /// Time base of the timestamps on filtered frames.
+    pub(crate) fn time_base(&self) -> sys::AVRational {
+        unsafe { sys::av_buffersink_get_time_base(self.sink) }
+    }
+
+    /// Feeds a decoded frame into the graph. The frame is left untouched.
+    pub(crate) unsafe fn push(&mut self, frame: *mut sys::AVFrame) -> Result<(), String> {
+        let started = Instant::now();
+        let ret = unsafe {
+            sys::av_buffersrc_add_frame_flags(
+                self.source,
+                frame,
+                sys::AV_BUFFERSRC_FLAG_KEEP_REF as i32,
+            )
+        };
+        self.cpu_time += started.elapsed();
+        if ret < 0 {
+            return Err(format!("Audio filters rejected a frame (error {})", ret));
+        }
+        Ok(())
+    }
+
+    /// Takes the next filtered frame into `frame`. Returns false once the
+    /// graph needs more input.
+    pub(crate) unsafe fn pull(&mut self, frame: *mut sys::AVFrame) -> bool {
+        let started = Instant::now();
+        let ret = unsafe { sys::av_buffersink_get_frame(self.sink, frame) };
+        self.cpu_time += started.elapsed();
+        ret >= 0
+    }
+}
+
+impl Drop for AudioFilterGraph {
+    fn drop(&mut self) {
+        unsafe {
+            sys::av_channel_layout_uninit(&mut self.output_layout);
+            sys::avfilter_graph_free(&mut self.graph);
+        }
+    }
+}
+
+/// Parses `chain` into `graph`, reading from `source` and writing to `sink`.
+unsafe fn parse_chain(
+    graph: *mut sys::AVFilterGraph,
+    source: *mut sys::AVFilterContext,
+    sink: *mut sys::AVFilterContext,
+    chain: &str,
+) -> i32 {
+    unsafe {
+        // The parsed chain reads from "in" and writes to "out".
+        let mut outputs = sys::avfilter_inout_alloc();
+        let mut inputs = sys::avfilter_inout_alloc();
+        (*outputs).name = sys::av_strdup(cstring!("in").as_ptr());
+        (*outputs).filter_ctx = source;
+        (*outputs).pad_idx = 0;
+        (*outputs).next = ptr::null_mut();
+        (*inputs).name = sys::av_strdup(cstring!("out").as_ptr());
+        (*inputs).filter_ctx = sink;
+        (*inputs).pad_idx = 0;
+        (*inputs).next = ptr::null_mut();
+
+        let ret = sys::avfilter_graph_parse_ptr(
+            graph,
+            cstring!(chain).as_ptr(),
+            &mut inputs,
+            &mut outputs,
+            ptr::null_mut(),
+        );
+        sys::avfilter_inout_free(&mut inputs);
+        sys::avfilter_inout_free(&mut outputs);
+        ret
+    }
+}
+
+/// A configured `buffer -> chain -> buffersink` graph for video frames. It
+/// runs on frames already scaled to the output size, and has to hand back
+/// frames of that same size.
+pub(crate) struct VideoFilterGraph {
+    graph: *mut sys::AVFilterGraph,
+    source: *mut sys::AVFilterContext,
+    sink: *mut sys::AVFilterContext,
+    /// Time spent inside the filters so far.
+    pub(crate) cpu_time: Duration,
+}
+
+impl VideoFilterGraph {
+    /// Builds `chain` for frames shaped like `frame`, with timestamps in
+    /// `time_base`. Only the frame's properties are read, so it needs no
+    /// buffers.
+    pub(crate) unsafe fn new(
+        chain: &str,
+        frame: *const sys::AVFrame,
+        time_base: sys::AVRational,
+    ) -> Result<Self, String> {
+        unsafe {
+            let mut filter_graph = Self {
+                graph: sys::avfilter_graph_alloc(),
+                source: ptr::null_mut(),
+                sink: ptr::null_mut(),
+                cpu_time: Duration::ZERO,
+            };
+            if filter_graph.graph.is_null() {
+                return Err("Failed to allocate filter graph".to_string());
+            }
+
+            let pix_fmt: sys::AVPixelFormat = std::mem::transmute((*frame).format);
+            let pix_fmt_name = CStr::from_ptr(sys::av_get_pix_fmt_name(pix_fmt)).to_string_lossy();
+            let (width, height) = ((*frame).width, (*frame).height);
+            let args = format!(
+                "video_size={}x{}:pix_fmt={}:time_base={}/{}:pixel_aspect=1/1:colorspace={}:range={}",
+                width,
+                height,
+                pix_fmt_name,
+                time_base.num,
+                time_base.den,
+                (*frame).colorspace as i32,
+                (*frame).color_range as i32,
+            );
+
+            let ret = sys::avfilter_graph_create_filter(
+                &mut filter_graph.source,
+                sys::avfilter_get_by_name(cstring!("buffer").as_ptr()),
+                cstring!("in").as_ptr(),
+                cstring!(args).as_ptr(),
+                ptr::null_mut(),
+                filter_graph.graph,
+            );
+            if ret < 0 {
+                return Err(format!("Failed to create video source with {}", args));
+            }
+            let ret = sys::avfilter_graph_create_filter(
+                &mut filter_graph.sink,
+                sys::avfilter_get_by_name(cstring!("buffersink").as_ptr()),
+                cstring!("out").as_ptr(),
+                ptr::null(),
+                ptr::null_mut(),
+                filter_graph.graph,
+            );
+            if ret < 0 {
+                return Err("Failed to create video sink".to_string());
+            }
+            // Make the graph convert back to what the encoder takes.
+            let ret = sys::av_opt_set_bin(
+                filter_graph.sink as *mut std::ffi::c_void,
+                cstring!("pix_fmts").as_ptr(),
+                &pix_fmt as *const sys::AVPixelFormat as *const u8,
+                std::mem::size_of::<sys::AVPixelFormat>() as i32,
+                sys::AV_OPT_SEARCH_CHILDREN as i32,
+            );
+            if ret < 0 {
+                return Err("Failed to set the video sink format".to_string());
+            }
+
+            if parse_chain(
+                filter_graph.graph,
+                filter_graph.source,
+                filter_graph.sink,
+                chain,
+            ) < 0
+            {
+                return Err(format!("Invalid video filter chain {:?}", chain));
+            }
+            if sys::avfilter_graph_config(filter_graph.graph, ptr::null_mut()) < 0 {
+                return Err(format!(
+                    "Video filter chain {:?} does not accept {}x{} {} frames",
+                    chain, width, height, pix_fmt_name
+                ));
+            }
+
+            let (out_width, out_height) = (
+                sys::av_buffersink_get_w(filter_graph.sink),
+                sys::av_buffersink_get_h(filter_graph.sink),
+            );
+            if (out_width, out_height) != (width, height) {
+                return Err(format!(
+                    "Video filter chain {:?} turns {}x{} frames into {}x{}; clips are encoded at {}x{}",
+                    chain, width, height, out_width, out_height, width, height
+                ));
+            }
+            let out_time_base = sys::av_buffersink_get_time_base(filter_graph.sink);
+            if sys::av_cmp_q(out_time_base, time_base) != 0 {
+                return Err(format!(
+                    "Video filter chain {:?} changes frame timing, which clips do not support",
+                    chain
+                ));
+            }
+            Ok(filter_graph)
+        }
+    }
+
+    /// Feeds a frame into the graph. The graph takes a reference, so the
+    /// caller must make the frame writable before changing it again.
+    pub(crate) unsafe fn push(&mut self, frame: *mut sys::AVFrame) -> Result<(), String> {
+        let started = Instant::now();
+        let ret = unsafe {
+            sys::av_buffersrc_add_frame_flags(
+                self.source,
+                frame,
+                sys::AV_BUFFERSRC_FLAG_KEEP_REF as i32,
+            )
+        };
+        self.cpu_time += started.elapsed();
+        if ret < 0 {
+            return Err(format!("Video filters rejected a frame (error {})", ret));
+        }
+        Ok(())
+    }
+
+    /// Takes the next filtered frame into `frame`. Returns false once the
+    /// graph needs more input.
+    pub(crate) unsafe fn pull(&mut self, frame: *mut sys::AVFrame) -> bool {
+        let started = Instant::now();
+        let ret = unsafe { sys::av_buffersink_get_frame(self.sink, frame) };
+        self.cpu_time += started.elapsed();
+        ret >= 0
+    }
+}
+
+impl Drop for VideoFilterGraph {
+    fn drop(&mut self) {
+        unsafe { sys::avfilter_graph_free(&mut self.graph) };
+    }
+}
+
+/// Checks that a video filter chain parses and fits the frames a recorder
+/// with these settings would feed it, without capturing anything.
+pub fn validate_video_filter(
+    chain: &str,
+    width: u32,
+    height: u32,
+    fps: u32,
+    encoder: &EncoderConfig,
+) -> Result<(), String> {
+    let pix_fmt = encoder.pix_fmt()?;
+    unsafe {
+        let mut frame = sys::av_frame_alloc();
+        if frame.is_null() {
+            return Err("Failed to allocate frame".to_string());
+        }
+        (*frame).width = width as i32;
+        (*frame).height = height as i32;
+        (*frame).format = pix_fmt as i32;
+        (*frame).colorspace = encoder.color_space.matrix();
+        (*frame).color_range = encoder.color_range.range();
+        let time_base = sys::AVRational {
+            num: 1,
+            den: fps.max(1) as i32,
+        };
+        let graph = VideoFilterGraph::new(chain, frame, time_base);
+        sys::av_frame_free(&mut frame);
+        graph.map(|_| ())
+    }
+}
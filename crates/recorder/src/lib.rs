@@ -2,55 +2,42 @@
 
 
 
+pub mod audio;
+pub mod capture;
+pub mod clock;
 pub mod codecpar;
+pub mod devices;
+pub mod encoder;
+pub mod filter;
 pub mod linux_recorder;
 pub mod osx_recorder;
+pub mod overlay;
 pub mod recorder;
+pub mod save_scheduler;
+pub mod scaler;
+pub mod stats;
 pub mod utils;
 pub mod windows_recorder;
+pub mod x11_window;
 
-use recorder::Recorder;
+use recorder::{Recorder, RecorderConfig};
 
 pub fn init() {}
 
 /// Factory to create the appropriate recorder for the current OS
-pub fn create_recorder(
-    width: u32,
-    height: u32,
-    fps: u32,
-    buffer_secs: u32,
-    output: String,
-) -> Box<dyn Recorder> {
+pub fn create_recorder(config: RecorderConfig) -> Box<dyn Recorder> {
     #[cfg(target_os = "windows")]
     {
-        Box::new(windows_recorder::WindowsRecorder::new(
-            width,
-            height,
-            fps,
-            buffer_secs,
-            output,
-        ))
+        Box::new(windows_recorder::WindowsRecorder::new(config))
     }
     #[cfg(target_os = "linux")]
     {
-        Box::new(linux_recorder::LinuxRecorder::new(
-            width,
-            height,
-            fps,
-            buffer_secs,
-            output,
-        ))
+        Box::new(linux_recorder::LinuxRecorder::new(config))
     }
 
     #[cfg(target_os = "macos")]
     {
-        Box::new(osx_recorder::OsxRecorder::new(
-            width,
-            height,
-            fps,
-            buffer_secs,
-            output,
-        ))
+        Box::new(osx_recorder::OsxRecorder::new(config))
     }
     #[cfg(not(any(target_os = "windows", target_os = "linux", target_os = "macos")))]
     {
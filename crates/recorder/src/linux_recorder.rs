@@ -1,55 +1,115 @@
-use super::recorder::Recorder;
+use std::time::Instant;
+
 use common::async_trait::async_trait;
-use common::tokio::process::Child;
-use common::tokio::sync::Mutex;
-use std::sync::Arc;
-use storage::ReplayBuffer;
+use common::log::{error, info};
+
+use super::recorder::{CaptureTarget, Recorder, RecorderConfig};
+use crate::capture::{CaptureSession, VideoInput, WindowWatch};
+use crate::stats::RecorderStats;
+use crate::x11_window::X11Window;
+use storage::{OverwritePolicy, SavedClip};
 
 pub struct LinuxRecorder {
-    width: u32,
-    height: u32,
-    fps: u32,
-    buffer_secs: u32,
-    output: String,
-    child: Option<Child>,
-    replay_buffer: Arc<ReplayBuffer>,
-    stop_signal: Arc<Mutex<bool>>,
+    session: CaptureSession,
 }
 
+// SAFETY: We ensure the pointer is only used while valid.
+unsafe impl Send for LinuxRecorder {}
+unsafe impl Sync for LinuxRecorder {}
+
 #[async_trait]
 impl Recorder for LinuxRecorder {
-    fn new(width: u32, height: u32, fps: u32, buffer_secs: u32, output: String) -> Self {
-        let estimated_packets = (fps as usize) * (buffer_secs as usize) * 2;
-        let replay_buffer = Arc::new(ReplayBuffer::new(buffer_secs, estimated_packets));
-
+    fn new(config: RecorderConfig) -> Self {
         Self {
-            width,
-            height,
-            fps,
-            buffer_secs,
-            output,
-            child: None,
-            replay_buffer,
-            stop_signal: Arc::new(Mutex::new(false)),
+            session: CaptureSession::new(config),
         }
     }
 
     async fn start(&mut self) {
-        // TODO: Implement Linux screen recording
-        println!("Starting Linux screen recording (not implemented)");
+        let config = self.session.config();
+        // x11grab also works under Xvfb, and XWayland shows X11 windows.
+        let display = std::env::var("DISPLAY").unwrap_or_else(|_| ":0.0".to_string());
+        let mut options = vec![("framerate".to_string(), config.fps.to_string())];
+        let mut window: Option<Box<dyn WindowWatch>> = None;
+        let url = if config.target != CaptureTarget::Screen {
+            let found = match X11Window::find(&config.target) {
+                Ok(found) => found,
+                Err(e) => {
+                    error!("[recorder] {}", e);
+                    return;
+                }
+            };
+            info!(
+                "[recorder] Capturing window {:#x} {:?}",
+                found.id(),
+                found.title().unwrap_or_default()
+            );
+            // Without video_size, x11grab follows the window's own size.
+            options.push(("window_id".to_string(), found.id().to_string()));
+            window = Some(Box::new(found));
+            display
+        } else if let Some(region) = config.region {
+            options.push((
+                "video_size".to_string(),
+                format!("{}x{}", region.width, region.height),
+            ));
+            format!("{}+{},{}", display, region.x, region.y)
+        } else {
+            options.push((
+                "video_size".to_string(),
+                format!("{}x{}", config.width, config.height),
+            ));
+            display
+        };
+        options.push(("draw_mouse".to_string(), "1".to_string()));
+        let input = VideoInput {
+            format: "x11grab",
+            url,
+            options,
+            crop: None,
+            window,
+            help: &["Check that DISPLAY points at a running X server"],
+        };
+        self.session.start(input).await;
+        info!("[recorder] x11grab capture started");
     }
 
     async fn stop(&mut self) {
-        // TODO: Implement stop functionality
-        println!("Stopping Linux screen recording (not implemented)");
+        self.session.stop().await;
+    }
+
+    fn save(
+        &self,
+        final_output_path: &str,
+        overwrite: OverwritePolicy,
+    ) -> Result<SavedClip, String> {
+        self.session.save(final_output_path, overwrite)
+    }
+
+    fn save_range(
+        &self,
+        final_output_path: &str,
+        start: Instant,
+        end: Instant,
+        overwrite: OverwritePolicy,
+    ) -> Result<SavedClip, String> {
+        self.session
+            .save_range(final_output_path, start, end, overwrite)
+    }
+
+    fn request_keyframe(&self) {
+        self.session.request_keyframe();
+    }
+
+    fn set_push_to_talk(&self, held: bool) {
+        self.session.set_push_to_talk(held);
     }
 
-    fn save(&self, _final_output_path: &str) -> Result<(), String> {
-        // TODO: Implement save functionality
-        Err("Linux save not implemented".to_string())
+    fn stats(&self) -> RecorderStats {
+        self.session.stats()
     }
 
     fn get_output_path(&self) -> &str {
-        &self.output
+        &self.session.config().output
     }
 }
@@ -0,0 +1,222 @@
+//! Watermark, text and clock overlays burned into every frame, built as a
+//! video filter chain from presets.
+
+use std::path::PathBuf;
+
+use crate::filter::escape_value;
+
+const TEXT_VARIABLES: [&str; 4] = ["hostname", "user", "os", "build_id"];
+
+/// Distance kept between overlays and the frame edge, in pixels.
+const MARGIN: u32 = 16;
+
+/// Corner of the frame an overlay sits in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverlayPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    #[default]
+    BottomRight,
+}
+
+impl OverlayPosition {
+    /// `x` and `y` expressions placing an item `item_w`x`item_h` in a frame
+    /// `frame_w`x`frame_h`, where all four are names of filter variables.
+    /// `line` moves it that many rows of `line_height` away from the edge so
+    /// items sharing a corner do not overlap.
+    fn expressions(
+        self,
+        (frame_w, frame_h): (&str, &str),
+        (item_w, item_h): (&str, &str),
+        line: u32,
+        line_height: u32,
+    ) -> (String, String) {
+        let offset = MARGIN + line * line_height;
+        let left = MARGIN.to_string();
+        let right = format!("{}-{}-{}", frame_w, item_w, MARGIN);
+        let top = offset.to_string();
+        let bottom = format!("{}-{}-{}", frame_h, item_h, offset);
+        match self {
+            OverlayPosition::TopLeft => (left, top),
+            OverlayPosition::TopRight => (right, top),
+            OverlayPosition::BottomLeft => (left, bottom),
+            OverlayPosition::BottomRight => (right, bottom),
+        }
+    }
+}
+
+/// A line of text. `template` may use `{hostname}`, `{user}`, `{os}` and
+/// `{build_id}`, filled in when recording starts.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TextOverlay {
+    pub template: String,
+    pub position: OverlayPosition,
+    pub font_size: u32,
+}
+
+/// An image such as a logo, drawn at its own size.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogoOverlay {
+    pub image: PathBuf,
+    pub position: OverlayPosition,
+    /// From 0.0 (invisible) to 1.0 (opaque).
+    pub opacity: f32,
+}
+
+/// The local wall-clock time, updated on every frame.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClockOverlay {
+    pub position: OverlayPosition,
+    pub font_size: u32,
+}
+
+/// Overlays burned into every captured frame, after the user's video
+/// filters.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Overlays {
+    pub text: Option<TextOverlay>,
+    pub logo: Option<LogoOverlay>,
+    pub clock: Option<ClockOverlay>,
+    /// Font for text and the clock. `None` uses fontconfig's default, which
+    /// FFmpeg builds without fontconfig do not have.
+    pub font_file: Option<PathBuf>,
+    /// Value of `{build_id}` in text templates.
+    pub build_id: String,
+}
+
+impl Overlays {
+    pub fn is_empty(&self) -> bool {
+        self.text.is_none() && self.logo.is_none() && self.clock.is_none()
+    }
+
+    /// Checks the text template and logo settings.
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(text) = &self.text {
+            self.expand(&text.template)?;
+        }
+        if let Some(logo) = &self.logo {
+            if !(0.0..=1.0).contains(&logo.opacity) {
+                return Err(format!(
+                    "Logo opacity {} is outside 0.0 to 1.0",
+                    logo.opacity
+                ));
+            }
+            if !logo.image.is_file() {
+                return Err(format!("Logo image {} not found", logo.image.display()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Fills the variables into a text template, rejecting unknown ones and
+    /// unbalanced braces.
+    pub fn expand(&self, template: &str) -> Result<String, String> {
+        let mut text = String::new();
+        let mut rest = template;
+        while let Some(open) = rest.find(['{', '}']) {
+            if rest.as_bytes()[open] == b'}' {
+                return Err(format!("Unmatched '}}' in overlay text: {}", template));
+            }
+            text.push_str(&rest[..open]);
+            let close = rest[open..]
+                .find('}')
+                .map(|i| open + i)
+                .ok_or_else(|| format!("Unclosed '{{' in overlay text: {}", template))?;
+            text.push_str(&match &rest[open + 1..close] {
+                "hostname" => hostname(),
+                "user" => std::env::var("USER")
+                    .or_else(|_| std::env::var("USERNAME"))
+                    .unwrap_or_default(),
+                "os" => std::env::consts::OS.to_string(),
+                "build_id" => self.build_id.clone(),
+                name => {
+                    return Err(format!(
+                        "Unknown variable {{{}}} in overlay text (expected one of {})",
+                        name,
+                        TEXT_VARIABLES.map(|v| format!("{{{}}}", v)).join(", ")
+                    ));
+                }
+            });
+            rest = &rest[close + 1..];
+        }
+        text.push_str(rest);
+        Ok(text)
+    }
+
+    /// The whole video filter graph: `user_filter` first, then the
+    /// overlays. `None` when there is nothing to run.
+    pub fn graph(&self, user_filter: Option<&str>) -> Result<Option<String>, String> {
+        let mut chain: Vec<String> = user_filter
+            .map(str::trim)
+            .filter(|filter| !filter.is_empty())
+            .map(str::to_string)
+            .into_iter()
+            .collect();
+
+        let font = match &self.font_file {
+            Some(path) => format!(":fontfile={}", escape_value(&path.to_string_lossy())),
+            None => String::new(),
+        };
+        let mut text_lines = Vec::new();
+        if let Some(text) = &self.text {
+            let rendered = self.expand(&text.template)?;
+            text_lines.push((text.position, text.font_size, rendered, "none"));
+        }
+        if let Some(clock) = &self.clock {
+            // Colons inside the strftime format are escaped for drawtext's
+            // own parser; escape_value handles the layers above it.
+            let text = "%{localtime:%Y-%m-%d %H\\:%M\\:%S}".to_string();
+            text_lines.push((clock.position, clock.font_size, text, "normal"));
+        }
+        for (i, (position, font_size, text, expansion)) in text_lines.iter().enumerate() {
+            let line = text_lines[..i]
+                .iter()
+                .filter(|(other, ..)| other == position)
+                .count() as u32;
+            let (x, y) = position.expressions(("w", "h"), ("tw", "th"), line, font_size * 3 / 2);
+            chain.push(format!(
+                "drawtext=text={}:expansion={}:x={}:y={}:fontsize={}:fontcolor=white:box=1:boxcolor=black@0.5:boxborderw=6{}",
+                escape_value(text),
+                expansion,
+                x,
+                y,
+                font_size,
+                font
+            ));
+        }
+
+        let Some(logo) = &self.logo else {
+            return Ok((!chain.is_empty()).then(|| chain.join(",")));
+        };
+        // The logo comes in as a second source and is laid over the chain's
+        // output.
+        chain.insert(0, "null".to_string());
+        let (x, y) = logo.position.expressions(("W", "H"), ("w", "h"), 0, 0);
+        Ok(Some(format!(
+            "movie=filename={},format=rgba,colorchannelmixer=aa={}[logo];[in]{}[base];[base][logo]overlay=x={}:y={}",
+            escape_value(&logo.image.to_string_lossy()),
+            logo.opacity,
+            chain.join(","),
+            x,
+            y
+        )))
+    }
+}
+
+/// This machine's name, or `unknown`.
+fn hostname() -> String {
+    std::env::var("HOSTNAME")
+        .or_else(|_| std::env::var("COMPUTERNAME"))
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .or_else(|| {
+            std::process::Command::new("hostname")
+                .output()
+                .ok()
+                .and_then(|output| String::from_utf8(output.stdout).ok())
+        })
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
@@ -1,10 +1,133 @@
+use std::time::Instant;
+
+use storage::{OverwritePolicy, SavedClip};
+
+use crate::audio::AudioConfig;
+use crate::clock::{FramePacing, FrameTimestamps};
+use crate::encoder::EncoderConfig;
+use crate::overlay::Overlays;
+use crate::scaler::{FitMode, ScaleAlgorithm};
+use crate::stats::RecorderStats;
+
+/// A rectangle of the desktop, in screen pixels from its top-left corner.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CaptureRegion {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl CaptureRegion {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.width == 0 || self.height == 0 {
+            return Err(format!(
+                "Capture region {}x{} is empty",
+                self.width, self.height
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// What part of the desktop a recorder captures.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum CaptureTarget {
+    /// The whole screen, or the configured region of it.
+    #[default]
+    Screen,
+    /// The first window whose title contains this text, ignoring case.
+    WindowTitle(String),
+    /// The first window whose class or instance name matches this one.
+    WindowClass(String),
+    /// A window by its native ID.
+    WindowId(u64),
+}
+
+impl CaptureTarget {
+    /// Whether this platform's recorder can capture the target: x11grab
+    /// follows windows by title, class or ID, gdigrab finds them by title
+    /// only, and macOS records the screen only.
+    pub fn is_supported(&self) -> bool {
+        match self {
+            CaptureTarget::Screen => true,
+            CaptureTarget::WindowTitle(_) => {
+                cfg!(any(target_os = "linux", target_os = "windows"))
+            }
+            CaptureTarget::WindowClass(_) | CaptureTarget::WindowId(_) => {
+                cfg!(target_os = "linux")
+            }
+        }
+    }
+}
+
+/// Everything a recorder needs to know before it starts capturing.
+#[derive(Clone, Debug, Default)]
+pub struct RecorderConfig {
+    /// Size clips are encoded at. The captured image is scaled to it as
+    /// `fit` says.
+    pub width: u32,
+    pub height: u32,
+    /// How the captured image is fitted to the output size when their
+    /// aspect ratios differ.
+    pub fit: FitMode,
+    pub scaler: ScaleAlgorithm,
+    /// Part of the desktop to record. `None` records the whole screen at the
+    /// output size.
+    pub region: Option<CaptureRegion>,
+    /// Screen or window to record. The region only applies to the screen.
+    pub target: CaptureTarget,
+    /// Capture device of the screen to record, from
+    /// [`devices::probe`](crate::devices::probe). Only used where every
+    /// display is a device of its own; `None` picks the main one.
+    pub display: Option<String>,
+    pub fps: u32,
+    /// Where frame timestamps come from, and how frames that miss their
+    /// interval are dropped or repeated.
+    pub timestamps: FrameTimestamps,
+    pub pacing: FramePacing,
+    pub buffer_secs: u32,
+    pub output: String,
+    pub encoder: EncoderConfig,
+    /// libavfilter chain run on every frame after scaling, e.g.
+    /// `hqdn3d,unsharp`. It must keep the frame size and timing.
+    pub video_filter: Option<String>,
+    /// Text, logo and clock drawn over every frame, after `video_filter`.
+    pub overlays: Overlays,
+    /// Desktop audio recorded as a second track. `None` records video only.
+    pub system_audio: Option<AudioConfig>,
+    /// Microphone recorded to its own track. `None` leaves it out.
+    pub microphone: Option<AudioConfig>,
+}
+
 #[common::async_trait::async_trait]
 pub trait Recorder: Send + Sync {
-    fn new(width: u32, height: u32, fps: u32, buffer_secs: u32, output: String) -> Self
+    fn new(config: RecorderConfig) -> Self
     where
         Self: Sized;
     async fn start(&mut self);
     async fn stop(&mut self);
-    fn save(&self, final_output_path: &str) -> Result<(), String>;
+    fn save(
+        &self,
+        final_output_path: &str,
+        overwrite: OverwritePolicy,
+    ) -> Result<SavedClip, String>;
+    /// Saves the packets captured between `start` and `end`. Callers wanting
+    /// post-roll must wait until `end` has passed before calling this.
+    fn save_range(
+        &self,
+        final_output_path: &str,
+        start: Instant,
+        end: Instant,
+        overwrite: OverwritePolicy,
+    ) -> Result<SavedClip, String>;
+    /// Asks the encoder to make the next captured frame a keyframe, so a clip
+    /// can start exactly at the current moment.
+    fn request_keyframe(&self);
+    /// Opens or closes the gate on audio tracks that use push-to-talk.
+    fn set_push_to_talk(&self, held: bool);
+    /// Frame counts, stage times, bitrates and queue depths of the running
+    /// session.
+    fn stats(&self) -> RecorderStats;
     fn get_output_path(&self) -> &str;
 }
@@ -0,0 +1,359 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use common::log::{error, info};
+use common::tokio;
+use common::tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+
+use crate::recorder::Recorder;
+use storage::template::OutputTemplate;
+use storage::{OverwritePolicy, SavedClip};
+
+/// A clip the user asked for, covering `start..end` of the capture timeline.
+#[derive(Clone, Debug)]
+pub struct SaveRequest {
+    pub id: u64,
+    pub start: Instant,
+    pub end: Instant,
+}
+
+/// Result of one executed save. A save covers every request that was merged
+/// into it. `output_path` is the expanded template; where the clip actually
+/// landed is in the [`SavedClip`], since the overwrite policy may rename it.
+#[derive(Debug)]
+pub struct SaveOutcome {
+    pub request_ids: Vec<u64>,
+    pub output_path: String,
+    pub start: Instant,
+    pub end: Instant,
+    pub result: Result<SavedClip, String>,
+}
+
+/// Queues save requests and runs them one at a time against a recorder.
+///
+/// A request is held back until `coalesce_window` after its range ends. Any
+/// request arriving in the meantime whose range overlaps it is merged into a
+/// single, extended clip instead of producing a second near-identical file,
+/// as long as the merged clip stays within `max_range`; past that the request
+/// is saved on its own.
+pub struct SaveScheduler {
+    tx: UnboundedSender<SaveRequest>,
+    next_id: AtomicU64,
+    recorder: Arc<dyn Recorder>,
+}
+
+struct PendingSave {
+    request_ids: Vec<u64>,
+    start: Instant,
+    end: Instant,
+}
+
+impl PendingSave {
+    fn overlaps(&self, request: &SaveRequest) -> bool {
+        request.start <= self.end && request.end >= self.start
+    }
+
+    fn due(&self, coalesce_window: Duration) -> Instant {
+        self.end + coalesce_window
+    }
+}
+
+impl SaveScheduler {
+    /// Spawns the scheduler task on the current Tokio runtime. Outcomes are
+    /// delivered on the returned receiver in the order saves complete.
+    ///
+    /// Each save's path is expanded from `template` just before it is written,
+    /// with `{counter}` counting saves made by this scheduler.
+    ///
+    /// `max_range` is the longest clip a merge may produce. The oldest packet
+    /// of a save has to survive in the replay buffer until the save runs
+    /// `coalesce_window` after its end, so this should be at most the buffer
+    /// length minus `coalesce_window`.
+    pub fn new(
+        recorder: Arc<dyn Recorder>,
+        coalesce_window: Duration,
+        max_range: Duration,
+        template: OutputTemplate,
+        overwrite: OverwritePolicy,
+    ) -> (Self, UnboundedReceiver<SaveOutcome>) {
+        let (tx, rx) = unbounded_channel();
+        let (outcome_tx, outcome_rx) = unbounded_channel();
+
+        tokio::spawn(run_scheduler(
+            recorder.clone(),
+            coalesce_window,
+            max_range,
+            template,
+            overwrite,
+            rx,
+            outcome_tx,
+        ));
+
+        let scheduler = Self {
+            tx,
+            next_id: AtomicU64::new(1),
+            recorder,
+        };
+        (scheduler, outcome_rx)
+    }
+
+    /// Queues a save of `start..end` and returns the id used in its outcome.
+    ///
+    /// Every request forces a keyframe right away, whether or not its range
+    /// is still being captured. The clip itself starts at the keyframe before
+    /// `start`; the forced one gives the buffer a fresh cut point, so the next
+    /// press lands close to a keyframe instead of up to a full GOP after one.
+    pub fn request(&self, start: Instant, end: Instant) -> Result<u64, String> {
+        self.recorder.request_keyframe();
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.tx
+            .send(SaveRequest { id, start, end })
+            .map_err(|_| "Save scheduler has shut down".to_string())?;
+        Ok(id)
+    }
+}
+
+async fn run_scheduler(
+    recorder: Arc<dyn Recorder>,
+    coalesce_window: Duration,
+    max_range: Duration,
+    template: OutputTemplate,
+    overwrite: OverwritePolicy,
+    mut rx: UnboundedReceiver<SaveRequest>,
+    outcome_tx: UnboundedSender<SaveOutcome>,
+) {
+    let mut pending: Vec<PendingSave> = Vec::new();
+    let mut counter: u64 = 0;
+    let mut accepting = true;
+
+    while accepting || !pending.is_empty() {
+        let next_due = pending.iter().map(|p| p.due(coalesce_window)).min();
+
+        tokio::select! {
+            request = rx.recv(), if accepting => match request {
+                Some(request) => enqueue(&mut pending, request, max_range),
+                None => accepting = false,
+            },
+            _ = sleep_until(next_due), if next_due.is_some() => {
+                let now = Instant::now();
+                let (due, waiting): (Vec<_>, Vec<_>) = pending
+                    .drain(..)
+                    .partition(|p| p.due(coalesce_window) <= now);
+                pending = waiting;
+
+                for save in due {
+                    counter += 1;
+                    let path = template.expand(save.start, save.end - save.start, counter);
+                    let outcome = execute(&recorder, save, path, overwrite).await;
+                    if outcome_tx.send(outcome).is_err() {
+                        info!("[recorder] Save outcome receiver dropped");
+                    }
+                }
+            }
+        }
+    }
+
+    info!("[recorder] Save scheduler stopped");
+}
+
+fn enqueue(pending: &mut Vec<PendingSave>, request: SaveRequest, max_range: Duration) {
+    let (overlapping, mut rest): (Vec<_>, Vec<_>) =
+        pending.drain(..).partition(|p| p.overlaps(&request));
+
+    // A request can bridge several pending saves; they all collapse into the
+    // oldest one so the clip stays continuous.
+    let start = overlapping
+        .iter()
+        .map(|p| p.start)
+        .fold(request.start, Instant::min);
+    let end = overlapping
+        .iter()
+        .map(|p| p.end)
+        .fold(request.end, Instant::max);
+
+    if !overlapping.is_empty() && end - start <= max_range {
+        let mut request_ids: Vec<u64> = overlapping
+            .into_iter()
+            .flat_map(|p| p.request_ids)
+            .collect();
+        request_ids.push(request.id);
+        info!(
+            "[recorder] Merged save request {} into pending save {:?}",
+            request.id, request_ids
+        );
+        rest.push(PendingSave {
+            request_ids,
+            start,
+            end,
+        });
+        *pending = rest;
+        return;
+    }
+
+    if overlapping.is_empty() {
+        info!("[recorder] Queued save request {}", request.id);
+    } else {
+        // Merging would make a clip whose start leaves the buffer before the
+        // save runs.
+        info!(
+            "[recorder] Queued save request {} separately: merged clip would exceed {:?}",
+            request.id, max_range
+        );
+    }
+    rest.extend(overlapping);
+    rest.push(PendingSave {
+        request_ids: vec![request.id],
+        start: request.start,
+        end: request.end,
+    });
+    *pending = rest;
+}
+
+async fn execute(
+    recorder: &Arc<dyn Recorder>,
+    save: PendingSave,
+    output_path: PathBuf,
+    overwrite: OverwritePolicy,
+) -> SaveOutcome {
+    let recorder = recorder.clone();
+    let output_path = output_path.to_string_lossy().into_owned();
+    let path = output_path.clone();
+    let (start, end) = (save.start, save.end);
+
+    let result = tokio::task::spawn_blocking(move || {
+        // Templates may point into directories that do not exist yet.
+        if let Some(dir) = Path::new(&path)
+            .parent()
+            .filter(|d| !d.as_os_str().is_empty())
+        {
+            std::fs::create_dir_all(dir)
+                .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+        }
+        recorder.save_range(&path, start, end, overwrite)
+    })
+    .await
+    .unwrap_or_else(|e| Err(format!("Save task panicked: {}", e)));
+
+    if let Err(e) = &result {
+        error!(
+            "[recorder] Save for requests {:?} failed: {}",
+            save.request_ids, e
+        );
+    }
+
+    SaveOutcome {
+        request_ids: save.request_ids,
+        output_path,
+        start,
+        end,
+        result,
+    }
+}
+
+async fn sleep_until(deadline: Option<Instant>) {
+    if let Some(deadline) = deadline {
+        tokio::time::sleep_until(tokio::time::Instant::from_std(deadline)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_RANGE: Duration = Duration::from_secs(60);
+
+    fn request(id: u64, base: Instant, start: u64, end: u64) -> SaveRequest {
+        SaveRequest {
+            id,
+            start: base + Duration::from_secs(start),
+            end: base + Duration::from_secs(end),
+        }
+    }
+
+    fn ranges(pending: &[PendingSave], base: Instant) -> Vec<(Vec<u64>, u64, u64)> {
+        let mut ranges: Vec<_> = pending
+            .iter()
+            .map(|p| {
+                (
+                    p.request_ids.clone(),
+                    (p.start - base).as_secs(),
+                    (p.end - base).as_secs(),
+                )
+            })
+            .collect();
+        ranges.sort_by_key(|(_, start, _)| *start);
+        ranges
+    }
+
+    #[test]
+    fn separate_ranges_stay_separate() {
+        let base = Instant::now();
+        let mut pending = Vec::new();
+        enqueue(&mut pending, request(1, base, 0, 10), MAX_RANGE);
+        enqueue(&mut pending, request(2, base, 20, 30), MAX_RANGE);
+        assert_eq!(
+            ranges(&pending, base),
+            vec![(vec![1], 0, 10), (vec![2], 20, 30)]
+        );
+    }
+
+    #[test]
+    fn overlapping_ranges_merge() {
+        let base = Instant::now();
+        let mut pending = Vec::new();
+        enqueue(&mut pending, request(1, base, 0, 10), MAX_RANGE);
+        enqueue(&mut pending, request(2, base, 5, 15), MAX_RANGE);
+        assert_eq!(ranges(&pending, base), vec![(vec![1, 2], 0, 15)]);
+    }
+
+    #[test]
+    fn touching_ranges_merge() {
+        let base = Instant::now();
+        let mut pending = Vec::new();
+        enqueue(&mut pending, request(1, base, 0, 10), MAX_RANGE);
+        enqueue(&mut pending, request(2, base, 10, 20), MAX_RANGE);
+        assert_eq!(ranges(&pending, base), vec![(vec![1, 2], 0, 20)]);
+    }
+
+    #[test]
+    fn bridging_request_collapses_pending_saves() {
+        let base = Instant::now();
+        let mut pending = Vec::new();
+        enqueue(&mut pending, request(1, base, 0, 10), MAX_RANGE);
+        enqueue(&mut pending, request(2, base, 20, 30), MAX_RANGE);
+        enqueue(&mut pending, request(3, base, 8, 22), MAX_RANGE);
+        assert_eq!(ranges(&pending, base), vec![(vec![1, 2, 3], 0, 30)]);
+    }
+
+    #[test]
+    fn merge_past_max_range_is_refused() {
+        let base = Instant::now();
+        let mut pending = Vec::new();
+        enqueue(&mut pending, request(1, base, 0, 40), MAX_RANGE);
+        enqueue(&mut pending, request(2, base, 30, 70), MAX_RANGE);
+        assert_eq!(
+            ranges(&pending, base),
+            vec![(vec![1], 0, 40), (vec![2], 30, 70)]
+        );
+    }
+
+    #[test]
+    fn chained_presses_stop_growing_at_max_range() {
+        let base = Instant::now();
+        let mut pending = Vec::new();
+        for id in 0..10 {
+            enqueue(
+                &mut pending,
+                request(id, base, id * 10, id * 10 + 15),
+                MAX_RANGE,
+            );
+        }
+        assert!(pending.iter().all(|p| p.end - p.start <= MAX_RANGE));
+        let mut ids: Vec<u64> = pending.iter().flat_map(|p| p.request_ids.clone()).collect();
+        ids.sort_unstable();
+        assert_eq!(ids, (0..10).collect::<Vec<_>>());
+    }
+}
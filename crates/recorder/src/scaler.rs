@@ -0,0 +1,320 @@
+//! Scaling captured frames to the output size.
+
+use std::ptr;
+
+use common::log::warn;
+use common::sys;
+
+use crate::recorder::CaptureRegion;
+
+/// How a captured image whose aspect ratio differs from the output is fitted
+/// into it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FitMode {
+    /// Scale to the output size, distorting the image.
+    #[default]
+    Stretch,
+    /// Scale to fit inside the output and pad the rest with black.
+    Letterbox,
+    /// Scale to cover the output and cut off what sticks out, keeping the
+    /// center.
+    Crop,
+}
+
+/// swscale's resampling algorithm. Sharper ones cost more CPU per frame.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ScaleAlgorithm {
+    FastBilinear,
+    #[default]
+    Bilinear,
+    Bicubic,
+    Lanczos,
+    /// Averages source pixels; good for large downscales.
+    Area,
+    /// Nearest neighbour. Keeps pixel art and text crisp at integer ratios.
+    Point,
+}
+
+impl ScaleAlgorithm {
+    fn flags(self) -> i32 {
+        let flags = match self {
+            ScaleAlgorithm::FastBilinear => sys::SWS_FAST_BILINEAR,
+            ScaleAlgorithm::Bilinear => sys::SWS_BILINEAR,
+            ScaleAlgorithm::Bicubic => sys::SWS_BICUBIC,
+            ScaleAlgorithm::Lanczos => sys::SWS_LANCZOS,
+            ScaleAlgorithm::Area => sys::SWS_AREA,
+            ScaleAlgorithm::Point => sys::SWS_POINT,
+        };
+        flags as i32
+    }
+}
+
+/// Picture placement inside a frame, in pixels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Rect {
+    x: i32,
+    y: i32,
+    width: i32,
+    height: i32,
+}
+
+/// A swscale context set up for one source size, plus where the picture goes
+/// in the output frame.
+pub(crate) struct Scaler {
+    ctx: *mut sys::SwsContext,
+    /// Part of each source frame that is kept, in [`FitMode::Crop`].
+    source_crop: Option<CaptureRegion>,
+    /// Where the scaled picture lands in the output frame.
+    target: Rect,
+}
+
+impl Scaler {
+    /// Sets up scaling from `source_width`x`source_height` frames coming out
+    /// of `decoder` into `output`, which must already be allocated and
+    /// tagged with the color space and range to convert to. In
+    /// [`FitMode::Letterbox`] the bars are painted into `output` here, since
+    /// scaling never touches them.
+    pub(crate) unsafe fn new(
+        source_width: i32,
+        source_height: i32,
+        decoder: *const sys::AVCodecContext,
+        output: *mut sys::AVFrame,
+        fit: FitMode,
+        algorithm: ScaleAlgorithm,
+    ) -> Result<Self, String> {
+        unsafe {
+            let (output_width, output_height) = ((*output).width, (*output).height);
+            let source_format = (*decoder).pix_fmt;
+            let output_format: sys::AVPixelFormat = std::mem::transmute((*output).format);
+            let full = Rect {
+                x: 0,
+                y: 0,
+                width: output_width,
+                height: output_height,
+            };
+
+            let (source_crop, target) = match fit {
+                FitMode::Stretch => (None, full),
+                FitMode::Letterbox => {
+                    let fitted =
+                        fit_inside(source_width, source_height, output_width, output_height);
+                    (None, fitted)
+                }
+                FitMode::Crop => {
+                    let kept = fit_inside(output_width, output_height, source_width, source_height);
+                    let crop = CaptureRegion {
+                        x: kept.x,
+                        y: kept.y,
+                        width: kept.width as u32,
+                        height: kept.height as u32,
+                    };
+                    (Some(crop), full)
+                }
+            };
+            let (scaled_width, scaled_height) = match source_crop {
+                Some(crop) => (crop.width as i32, crop.height as i32),
+                None => (source_width, source_height),
+            };
+
+            let ctx = sys::sws_getContext(
+                scaled_width,
+                scaled_height,
+                source_format,
+                target.width,
+                target.height,
+                output_format,
+                algorithm.flags(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null(),
+            );
+            if ctx.is_null() {
+                return Err(format!(
+                    "Failed to create scaler context from {}x{} to {}x{}",
+                    scaled_width, scaled_height, target.width, target.height
+                ));
+            }
+
+            set_color_details(ctx, decoder, output);
+
+            if target != full {
+                let linesize: [isize; 4] = std::array::from_fn(|i| (*output).linesize[i] as isize);
+                sys::av_image_fill_black(
+                    (*output).data.as_ptr(),
+                    linesize.as_ptr(),
+                    output_format,
+                    (*output).color_range,
+                    output_width,
+                    output_height,
+                );
+            }
+
+            Ok(Self {
+                ctx,
+                source_crop,
+                target,
+            })
+        }
+    }
+
+    /// Scales `source` into `output`. `source` may be cropped in place.
+    pub(crate) unsafe fn scale(
+        &self,
+        source: *mut sys::AVFrame,
+        output: *mut sys::AVFrame,
+    ) -> Result<(), String> {
+        unsafe {
+            if let Some(crop) = self.source_crop {
+                crop_frame(source, crop)?;
+            }
+            let (data, linesize) = plane_pointers(output, self.target)?;
+            sys::sws_scale(
+                self.ctx,
+                (*source).data.as_ptr() as *const *const u8,
+                (*source).linesize.as_ptr(),
+                0,
+                (*source).height,
+                data.as_ptr(),
+                linesize.as_ptr(),
+            );
+            Ok(())
+        }
+    }
+}
+
+impl Drop for Scaler {
+    fn drop(&mut self) {
+        unsafe { sys::sws_freeContext(self.ctx) };
+    }
+}
+
+/// Makes swscale convert with the output's matrix and range instead of its
+/// BT.601 limited-range default. Sources that are not tagged are taken to be
+/// full range if they are RGB, and BT.601 limited range otherwise.
+unsafe fn set_color_details(
+    ctx: *mut sys::SwsContext,
+    decoder: *const sys::AVCodecContext,
+    output: *const sys::AVFrame,
+) {
+    unsafe {
+        let source_desc = sys::av_pix_fmt_desc_get((*decoder).pix_fmt);
+        let source_rgb =
+            !source_desc.is_null() && (*source_desc).flags & sys::AV_PIX_FMT_FLAG_RGB as u64 != 0;
+        let source_full = match (*decoder).color_range {
+            sys::AVColorRange::AVCOL_RANGE_JPEG => true,
+            sys::AVColorRange::AVCOL_RANGE_MPEG => false,
+            _ => source_rgb,
+        };
+        let output_full = (*output).color_range == sys::AVColorRange::AVCOL_RANGE_JPEG;
+
+        // swscale's coefficient tables are indexed by AVColorSpace, and fall
+        // back to BT.601 for anything unspecified.
+        let ret = sys::sws_setColorspaceDetails(
+            ctx,
+            sys::sws_getCoefficients((*decoder).colorspace as i32),
+            source_full as i32,
+            sys::sws_getCoefficients((*output).colorspace as i32),
+            output_full as i32,
+            0,
+            1 << 16,
+            1 << 16,
+        );
+        if ret < 0 {
+            warn!("[recorder] Scaler ignored the color space settings");
+        }
+    }
+}
+
+/// The largest rectangle with the aspect ratio of `width`x`height` that fits
+/// centered in `outer_width`x`outer_height`. Sizes and offsets are even so
+/// subsampled chroma planes line up.
+fn fit_inside(width: i32, height: i32, outer_width: i32, outer_height: i32) -> Rect {
+    let (fitted_width, fitted_height) =
+        if width as i64 * outer_height as i64 > height as i64 * outer_width as i64 {
+            (
+                outer_width,
+                (outer_width as i64 * height as i64 / width as i64) as i32,
+            )
+        } else {
+            (
+                (outer_height as i64 * width as i64 / height as i64) as i32,
+                outer_height,
+            )
+        };
+    let fitted_width = (fitted_width & !1).max(2);
+    let fitted_height = (fitted_height & !1).max(2);
+    Rect {
+        x: ((outer_width - fitted_width) / 2) & !1,
+        y: ((outer_height - fitted_height) / 2) & !1,
+        width: fitted_width,
+        height: fitted_height,
+    }
+}
+
+/// Cuts `region` out of a decoded frame in place, without copying pixels.
+pub(crate) unsafe fn crop_frame(
+    frame: *mut sys::AVFrame,
+    region: CaptureRegion,
+) -> Result<(), String> {
+    unsafe {
+        let right = (*frame).width as i64 - region.x as i64 - region.width as i64;
+        let bottom = (*frame).height as i64 - region.y as i64 - region.height as i64;
+        if region.x < 0 || region.y < 0 || right < 0 || bottom < 0 {
+            return Err(format!(
+                "Capture region {}x{}+{}+{} does not fit in the {}x{} screen",
+                region.width,
+                region.height,
+                region.x,
+                region.y,
+                (*frame).width,
+                (*frame).height
+            ));
+        }
+        (*frame).crop_left = region.x as usize;
+        (*frame).crop_top = region.y as usize;
+        (*frame).crop_right = right as usize;
+        (*frame).crop_bottom = bottom as usize;
+        if sys::av_frame_apply_cropping(frame, sys::AV_FRAME_CROP_UNALIGNED as i32) < 0 {
+            return Err("Failed to crop captured frame".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Plane pointers and strides addressing `rect` inside `frame`.
+unsafe fn plane_pointers(
+    frame: *mut sys::AVFrame,
+    rect: Rect,
+) -> Result<([*mut u8; 4], [i32; 4]), String> {
+    unsafe {
+        let desc = sys::av_pix_fmt_desc_get(std::mem::transmute((*frame).format));
+        if desc.is_null() {
+            return Err("Output frame has an unknown pixel format".to_string());
+        }
+        let mut data = [ptr::null_mut(); 4];
+        let mut linesize = [0; 4];
+        for plane in 0..4 {
+            if (*frame).data[plane].is_null() {
+                continue;
+            }
+            // Bytes per pixel in this plane, and whether it holds chroma.
+            let Some(index) = (0..(*desc).nb_components as usize)
+                .find(|&c| (*desc).comp[c].plane as usize == plane)
+            else {
+                continue;
+            };
+            let step = (*desc).comp[index].step as isize;
+            let chroma = index == 1 || index == 2;
+            let (shift_w, shift_h) = if chroma {
+                ((*desc).log2_chroma_w, (*desc).log2_chroma_h)
+            } else {
+                (0, 0)
+            };
+            let offset = (rect.y >> shift_h) as isize * (*frame).linesize[plane] as isize
+                + (rect.x >> shift_w) as isize * step;
+            data[plane] = (*frame).data[plane].offset(offset);
+            linesize[plane] = (*frame).linesize[plane];
+        }
+        Ok((data, linesize))
+    }
+}
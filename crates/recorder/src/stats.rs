@@ -0,0 +1,407 @@
+//! Runtime statistics of a recording session, for telling whether the
+//! machine keeps up with the chosen settings.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use storage::{ReplayBuffer, VIDEO_STREAM};
+
+/// How many recent samples stage times are taken over.
+const MAX_SAMPLES: usize = 600;
+
+/// Rates and bitrates are measured over this much recent time.
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+
+/// Statistics shared between the capture threads and whoever asks for them.
+pub(crate) type SharedStats = Arc<Mutex<SessionStats>>;
+
+/// How long one pipeline stage took per frame, over recent frames.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StageTimes {
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+/// One audio track.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AudioTrackStats {
+    pub stream: usize,
+    pub encoded_packets: u64,
+    pub bitrate_kbps: f64,
+    /// Audio captured but not yet handed to the encoder.
+    pub queued: Duration,
+    /// CPU time spent in the track's filters so far.
+    pub filter_cpu_time: Duration,
+}
+
+/// A snapshot of a session's statistics, from [`Recorder::stats`].
+///
+/// [`Recorder::stats`]: crate::recorder::Recorder::stats
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RecorderStats {
+    /// Time since the first frame was captured.
+    pub elapsed: Duration,
+    pub captured_frames: u64,
+    /// Video frames that came out of the encoder.
+    pub encoded_frames: u64,
+    /// Frames dropped for arriving in an interval that already had one.
+    pub dropped_frames: u64,
+    /// Frames repeated to fill intervals nothing arrived in.
+    pub repeated_frames: u64,
+    /// Frames per second over the last few seconds.
+    pub capture_fps: f64,
+    pub encode_fps: f64,
+    pub decode_time: StageTimes,
+    pub scale_time: StageTimes,
+    /// Video filters and encoder together.
+    pub encode_time: StageTimes,
+    /// CPU time spent in the video filters and overlays so far.
+    pub video_filter_cpu_time: Duration,
+    /// Video bitrate over the last few seconds.
+    pub video_bitrate_kbps: f64,
+    /// Frames inside the encoder that have not come out yet.
+    pub encoder_queue: u64,
+    /// What the replay buffer holds across all streams.
+    pub buffered_packets: usize,
+    pub buffered_bytes: u64,
+    pub audio: Vec<AudioTrackStats>,
+}
+
+impl RecorderStats {
+    /// Whether capturing at `fps` is too much for this machine: processing a
+    /// frame regularly takes longer than the frame interval, or frames
+    /// come in well below the rate asked for. Nothing counts during the
+    /// first [`RATE_WINDOW`], while encoders and devices are still starting.
+    pub fn is_overloaded(&self, fps: u32) -> bool {
+        if self.elapsed <= RATE_WINDOW {
+            return false;
+        }
+        let interval = Duration::from_secs_f64(1.0 / fps.max(1) as f64);
+        let frame_time = self.decode_time.p95 + self.scale_time.p95 + self.encode_time.p95;
+        frame_time > interval || self.capture_fps < fps as f64 * 0.9
+    }
+}
+
+impl fmt::Display for RecorderStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.1} fps captured, {:.1} fps encoded, {} of {} frames encoded ({} dropped, {} repeated), \
+             p95 decode {:?} / scale {:?} / encode {:?}, {:.0} kbps, {} frames in encoder, {} packets buffered",
+            self.capture_fps,
+            self.encode_fps,
+            self.encoded_frames,
+            self.captured_frames,
+            self.dropped_frames,
+            self.repeated_frames,
+            self.decode_time.p95,
+            self.scale_time.p95,
+            self.encode_time.p95,
+            self.video_bitrate_kbps,
+            self.encoder_queue,
+            self.buffered_packets
+        )
+    }
+}
+
+/// The most recent samples of a stage time.
+#[derive(Default)]
+struct Samples(VecDeque<Duration>);
+
+impl Samples {
+    fn push(&mut self, sample: Duration) {
+        if self.0.len() == MAX_SAMPLES {
+            self.0.pop_front();
+        }
+        self.0.push_back(sample);
+    }
+
+    fn times(&self) -> StageTimes {
+        let mut sorted: Vec<Duration> = self.0.iter().copied().collect();
+        sorted.sort_unstable();
+        let percentile = |p: usize| {
+            sorted
+                .get((sorted.len() * p / 100).min(sorted.len().saturating_sub(1)))
+                .copied()
+                .unwrap_or_default()
+        };
+        StageTimes {
+            p50: percentile(50),
+            p95: percentile(95),
+            p99: percentile(99),
+            max: sorted.last().copied().unwrap_or_default(),
+        }
+    }
+}
+
+/// Events of the last [`RATE_WINDOW`], each with a size in bytes.
+#[derive(Default)]
+struct RateWindow(VecDeque<(Instant, u64)>);
+
+impl RateWindow {
+    fn push(&mut self, at: Instant, bytes: u64) {
+        self.0.push_back((at, bytes));
+        self.prune(at);
+    }
+
+    fn prune(&mut self, now: Instant) {
+        while let Some(&(oldest, _)) = self.0.front() {
+            if now.saturating_duration_since(oldest) <= RATE_WINDOW {
+                break;
+            }
+            self.0.pop_front();
+        }
+    }
+
+    /// Events per second and kilobits per second over the last
+    /// [`RATE_WINDOW`], or over the time since `started` if that is shorter.
+    /// Measuring from the oldest event instead would overstate the rate
+    /// when events are sparse.
+    fn rates(&mut self, started: Instant, now: Instant) -> (f64, f64) {
+        self.prune(now);
+        let span = now
+            .saturating_duration_since(started)
+            .min(RATE_WINDOW)
+            .as_secs_f64();
+        if span <= 0.0 {
+            return (0.0, 0.0);
+        }
+        let (count, bytes) = self
+            .0
+            .iter()
+            .fold((0u64, 0u64), |(count, bytes), (_, size)| {
+                (count + 1, bytes + size)
+            });
+        (count as f64 / span, bytes as f64 * 8.0 / 1000.0 / span)
+    }
+}
+
+#[derive(Default)]
+struct AudioTrack {
+    packets: RateWindow,
+    encoded_packets: u64,
+    queued: Duration,
+    filter_cpu_time: Duration,
+}
+
+/// Counters and samples the capture threads update as they go.
+#[derive(Default)]
+pub(crate) struct SessionStats {
+    started: Option<Instant>,
+    captured_frames: u64,
+    encoded_frames: u64,
+    dropped_frames: u64,
+    repeated_frames: u64,
+    encoder_queue: u64,
+    captured: RateWindow,
+    encoded: RateWindow,
+    decode_time: Samples,
+    scale_time: Samples,
+    encode_time: Samples,
+    video_filter_cpu_time: Duration,
+    audio: Vec<(usize, AudioTrack)>,
+}
+
+impl SessionStats {
+    pub(crate) fn frame_captured(&mut self, at: Instant) {
+        self.started.get_or_insert(at);
+        self.captured_frames += 1;
+        self.captured.push(at, 0);
+    }
+
+    /// Times of one frame through decoding, scaling and encoding.
+    pub(crate) fn frame_processed(&mut self, decode: Duration, scale: Duration, encode: Duration) {
+        self.decode_time.push(decode);
+        self.scale_time.push(scale);
+        self.encode_time.push(encode);
+    }
+
+    /// Where the video encoder stands after a frame went in.
+    pub(crate) fn video_progress(
+        &mut self,
+        dropped: u64,
+        repeated: u64,
+        encoder_queue: u64,
+        filter_cpu_time: Duration,
+    ) {
+        self.dropped_frames = dropped;
+        self.repeated_frames = repeated;
+        self.encoder_queue = encoder_queue;
+        self.video_filter_cpu_time = filter_cpu_time;
+    }
+
+    /// Packets of `bytes` that came out of the encoder of `stream`.
+    pub(crate) fn packet_encoded(&mut self, stream: usize, bytes: u64) {
+        let now = Instant::now();
+        if stream == VIDEO_STREAM {
+            self.encoded_frames += 1;
+            self.encoded.push(now, bytes);
+        } else {
+            let track = self.audio_track(stream);
+            track.encoded_packets += 1;
+            track.packets.push(now, bytes);
+        }
+    }
+
+    /// Where an audio track stands after a frame went in.
+    pub(crate) fn audio_progress(
+        &mut self,
+        stream: usize,
+        queued: Duration,
+        filter_cpu_time: Duration,
+    ) {
+        let track = self.audio_track(stream);
+        track.queued = queued;
+        track.filter_cpu_time = filter_cpu_time;
+    }
+
+    fn audio_track(&mut self, stream: usize) -> &mut AudioTrack {
+        let index = match self.audio.iter().position(|(s, _)| *s == stream) {
+            Some(index) => index,
+            None => {
+                self.audio.push((stream, AudioTrack::default()));
+                self.audio.len() - 1
+            }
+        };
+        &mut self.audio[index].1
+    }
+
+    pub(crate) fn snapshot(&mut self, replay_buffer: &ReplayBuffer) -> RecorderStats {
+        let now = Instant::now();
+        let started = self.started.unwrap_or(now);
+        let (capture_fps, _) = self.captured.rates(started, now);
+        let (encode_fps, video_bitrate_kbps) = self.encoded.rates(started, now);
+        let (buffered_packets, buffered_bytes) = replay_buffer.usage();
+        RecorderStats {
+            elapsed: now.saturating_duration_since(started),
+            captured_frames: self.captured_frames,
+            encoded_frames: self.encoded_frames,
+            dropped_frames: self.dropped_frames,
+            repeated_frames: self.repeated_frames,
+            capture_fps,
+            encode_fps,
+            decode_time: self.decode_time.times(),
+            scale_time: self.scale_time.times(),
+            encode_time: self.encode_time.times(),
+            video_filter_cpu_time: self.video_filter_cpu_time,
+            video_bitrate_kbps,
+            encoder_queue: self.encoder_queue,
+            buffered_packets,
+            buffered_bytes,
+            audio: self
+                .audio
+                .iter_mut()
+                .map(|(stream, track)| AudioTrackStats {
+                    stream: *stream,
+                    encoded_packets: track.encoded_packets,
+                    bitrate_kbps: track.packets.rates(started, now).1,
+                    queued: track.queued,
+                    filter_cpu_time: track.filter_cpu_time,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn percentiles_of_samples() {
+        let mut samples = Samples::default();
+        assert_eq!(samples.times(), StageTimes::default());
+
+        for i in (1..=100).rev() {
+            samples.push(ms(i));
+        }
+        let times = samples.times();
+        assert_eq!(times.p50, ms(51));
+        assert_eq!(times.p95, ms(96));
+        assert_eq!(times.p99, ms(100));
+        assert_eq!(times.max, ms(100));
+    }
+
+    #[test]
+    fn samples_keep_only_the_most_recent() {
+        let mut samples = Samples::default();
+        for _ in 0..MAX_SAMPLES {
+            samples.push(ms(50));
+        }
+        for _ in 0..MAX_SAMPLES {
+            samples.push(ms(1));
+        }
+        assert_eq!(samples.times().max, ms(1));
+    }
+
+    #[test]
+    fn rates_cover_the_window() {
+        let started = Instant::now();
+        let mut window = RateWindow::default();
+        // 10 events a second of 1000 bytes each, for 10 seconds.
+        for i in 0..100 {
+            window.push(started + ms(i * 100), 1000);
+        }
+        let now = started + ms(10_000);
+        let (rate, kbps) = window.rates(started, now);
+        assert!((rate - 10.0).abs() < 0.5, "{}", rate);
+        assert!((kbps - 80.0).abs() < 4.0, "{}", kbps);
+    }
+
+    #[test]
+    fn rates_are_not_overstated_by_sparse_events() {
+        let started = Instant::now();
+        let mut window = RateWindow::default();
+        window.push(started + ms(4900), 0);
+        let (rate, _) = window.rates(started, started + ms(5000));
+        assert!((rate - 0.2).abs() < 0.01, "{}", rate);
+    }
+
+    #[test]
+    fn rates_drop_to_zero_once_events_stop() {
+        let started = Instant::now();
+        let mut window = RateWindow::default();
+        window.push(started + ms(1000), 0);
+        let (rate, _) = window.rates(started, started + ms(20_000));
+        assert_eq!(rate, 0.0);
+        assert!(window.0.is_empty());
+    }
+
+    #[test]
+    fn overload_is_only_judged_after_warming_up() {
+        let slow = RecorderStats {
+            elapsed: ms(1000),
+            capture_fps: 10.0,
+            encode_time: StageTimes {
+                p95: ms(100),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(!slow.is_overloaded(60));
+
+        let warmed_up = RecorderStats {
+            elapsed: RATE_WINDOW * 2,
+            ..slow.clone()
+        };
+        assert!(warmed_up.is_overloaded(60));
+
+        let keeping_up = RecorderStats {
+            capture_fps: 60.0,
+            encode_time: StageTimes {
+                p95: ms(5),
+                ..Default::default()
+            },
+            ..warmed_up
+        };
+        assert!(!keeping_up.is_overloaded(60));
+    }
+}
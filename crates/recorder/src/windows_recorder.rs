@@ -1,326 +1,227 @@
-use std::ptr;
-use std::sync::Arc;
+use std::time::Instant;
 
 use common::async_trait::async_trait;
 use common::log::{error, info};
-use common::tokio::sync::Mutex;
 
-use super::recorder::Recorder;
-use crate::codecpar::CodecParPtr;
-use common::avdict::AVDict;
-use common::cstring;
-use common::sys;
-use storage::ReplayBuffer;
+use super::recorder::{CaptureTarget, Recorder, RecorderConfig};
+use crate::capture::{CaptureSession, VideoInput, WindowWatch};
+use crate::stats::RecorderStats;
+use storage::{OverwritePolicy, SavedClip};
 
-type ArcM<T> = Arc<Mutex<T>>;
-
-pub struct WindowsRecorder {
-    width: u32,
-    height: u32,
-    fps: u32,
-    buffer_secs: u32,
-    stop_signal: ArcM<bool>,
-    replay_buffer: Arc<ReplayBuffer>,
-    codecpar: std::sync::Arc<std::sync::Mutex<Option<CodecParPtr>>>,
+/// The window gdigrab records, polled through user32 so the capture can
+/// follow it.
+#[cfg(target_os = "windows")]
+struct GdiWindow {
+    hwnd: isize,
 }
 
-// SAFETY: We ensure the pointer is only used while valid.
-unsafe impl Send for WindowsRecorder {}
-unsafe impl Sync for WindowsRecorder {}
-
-#[async_trait]
-impl Recorder for WindowsRecorder {
-    fn new(width: u32, height: u32, fps: u32, buffer_secs: u32, _output: String) -> Self {
-        // ffmpeg_next::init().expect("FFmpeg init failed");
-        unsafe { sys::avdevice_register_all() };
+#[cfg(target_os = "windows")]
+#[repr(C)]
+#[derive(Default)]
+struct Rect {
+    left: i32,
+    top: i32,
+    right: i32,
+    bottom: i32,
+}
 
-        let estimated_packets = (fps as usize) * (buffer_secs as usize) * 2;
-        let replay_buffer = Arc::new(ReplayBuffer::new(buffer_secs, estimated_packets));
+#[cfg(target_os = "windows")]
+#[link(name = "user32")]
+unsafe extern "system" {
+    fn EnumWindows(callback: extern "system" fn(isize, isize) -> i32, param: isize) -> i32;
+    fn GetWindowTextW(hwnd: isize, text: *mut u16, max_count: i32) -> i32;
+    fn GetWindowTextLengthW(hwnd: isize) -> i32;
+    fn FindWindowW(class_name: *const u16, window_name: *const u16) -> isize;
+    fn IsWindow(hwnd: isize) -> i32;
+    fn IsWindowVisible(hwnd: isize) -> i32;
+    fn IsIconic(hwnd: isize) -> i32;
+    fn GetClientRect(hwnd: isize, rect: *mut Rect) -> i32;
+}
 
-        Self {
-            width,
-            height,
-            fps,
-            buffer_secs,
-            stop_signal: Arc::new(Mutex::new(false)),
-            replay_buffer,
-            codecpar: std::sync::Arc::new(std::sync::Mutex::new(None)),
+#[cfg(target_os = "windows")]
+impl GdiWindow {
+    /// Finds the first visible window whose title contains `text`, ignoring
+    /// case, and returns it with its full title, which is what gdigrab
+    /// needs to find it again.
+    fn find(text: &str) -> Result<(Self, String), String> {
+        let needle = text.to_lowercase();
+        let title = visible_window_titles()
+            .into_iter()
+            .find(|title| title.to_lowercase().contains(&needle))
+            .ok_or_else(|| format!("No window title contains {:?}", text))?;
+        // Look the window up the way gdigrab will, so both agree on which
+        // one it is when several share the title.
+        let wide: Vec<u16> = title.encode_utf16().chain(std::iter::once(0)).collect();
+        let hwnd = unsafe { FindWindowW(std::ptr::null(), wide.as_ptr()) };
+        if hwnd == 0 {
+            return Err(format!("Window {:?} closed", title));
         }
-    }
-
-    async fn start(&mut self) {
-        *self.stop_signal.lock().await = false;
-
-        let stop = self.stop_signal.clone();
-        let buf = self.replay_buffer.clone();
-        let width = self.width;
-        let height = self.height;
-        let fps = self.fps;
-        let secs = self.buffer_secs;
-        let codecpar_arc = self.codecpar.clone();
-
-        common::tokio::task::spawn_blocking(move || {
-            capture_encode_loop_sys(width, height, fps, secs, buf, stop, codecpar_arc);
-        });
-
-        info!("[recorder] ffmpeg-sys capture thread started");
-    }
-
-    async fn stop(&mut self) {
-        *self.stop_signal.lock().await = true;
-    }
-
-    fn save(&self, final_output_path: &str) -> Result<(), String> {
-        let codecpar = self.codecpar.lock().unwrap();
-        let codecpar = codecpar.ok_or("Codec parameters not set")?.0;
-        info!("[recorder] Saving replay buffer to {}", final_output_path);
-        self.replay_buffer
-            .save_to_file(final_output_path, codecpar, 30)
-    }
-
-    fn get_output_path(&self) -> &str {
-        "output.mp4"
+        Ok((Self { hwnd }, title))
     }
 }
 
-#[allow(unused_assignments)]
-fn capture_encode_loop_sys(
-    width: u32,
-    height: u32,
-    fps: u32,
-    _buffer_secs: u32,
-    replay_buffer: Arc<ReplayBuffer>,
-    stop_signal: ArcM<bool>,
-    codecpar_arc: std::sync::Arc<std::sync::Mutex<Option<CodecParPtr>>>,
-) {
-    unsafe {
-        let mut fmt_ctx: *mut sys::AVFormatContext = ptr::null_mut();
-        let mut dec_ctx: *mut sys::AVCodecContext = ptr::null_mut();
-        let mut enc_ctx: *mut sys::AVCodecContext = ptr::null_mut();
-        let mut scaler_ctx: *mut sys::SwsContext = ptr::null_mut();
-        let mut packet: *mut sys::AVPacket = ptr::null_mut();
-        let mut decoded_frame: *mut sys::AVFrame = ptr::null_mut();
-        let mut scaled_frame: *mut sys::AVFrame = ptr::null_mut();
-
-        let input_format = sys::av_find_input_format(cstring!("gdigrab").as_ptr());
-        if input_format.is_null() {
-            error!("[recorder] Failed to find gdigrab input format");
-            return;
-        }
-
-        let mut dict = AVDict::new();
-        dict.set("framerate", &fps.to_string());
-        dict.set("video_size", &format!("{}x{}", width, height));
-
-        let url = cstring!("desktop");
-        if sys::avformat_open_input(&mut fmt_ctx, url.as_ptr(), input_format, dict.as_mut_ptr()) < 0
-        {
-            error!("[recorder] Failed to open gdigrab input");
-            return;
-        }
-
-        if sys::avformat_find_stream_info(fmt_ctx, ptr::null_mut()) < 0 {
-            error!("[recorder] Failed to find stream info");
-            sys::avformat_close_input(&mut fmt_ctx);
-            return;
-        }
-
-        let mut video_stream_index = -1;
-        for i in 0..(*fmt_ctx).nb_streams as i32 {
-            let stream = *(*fmt_ctx).streams.add(i as usize);
-            if (*(*stream).codecpar).codec_type == sys::AVMediaType::AVMEDIA_TYPE_VIDEO {
-                video_stream_index = i;
-                break;
+/// Titles of the visible top-level windows, topmost first.
+#[cfg(target_os = "windows")]
+fn visible_window_titles() -> Vec<String> {
+    extern "system" fn collect(hwnd: isize, param: isize) -> i32 {
+        let titles = unsafe { &mut *(param as *mut Vec<String>) };
+        unsafe {
+            let len = GetWindowTextLengthW(hwnd);
+            if IsWindowVisible(hwnd) != 0 && len > 0 {
+                let mut text = vec![0u16; len as usize + 1];
+                let copied = GetWindowTextW(hwnd, text.as_mut_ptr(), text.len() as i32);
+                titles.push(String::from_utf16_lossy(&text[..copied.max(0) as usize]));
             }
         }
+        1 // Keep enumerating
+    }
+    let mut titles: Vec<String> = Vec::new();
+    unsafe { EnumWindows(collect, &mut titles as *mut Vec<String> as isize) };
+    titles
+}
 
-        if video_stream_index == -1 {
-            error!("[recorder] Failed to find video stream");
-            sys::avformat_close_input(&mut fmt_ctx);
-            return;
-        }
-
-        let input_stream = *(*fmt_ctx).streams.add(video_stream_index as usize);
-        let input_codecpar = (*input_stream).codecpar;
-
-        let decoder = sys::avcodec_find_decoder((*input_codecpar).codec_id);
-        dec_ctx = sys::avcodec_alloc_context3(decoder);
-        sys::avcodec_parameters_to_context(dec_ctx, input_codecpar);
-        sys::avcodec_open2(dec_ctx, decoder, ptr::null_mut());
-
-        scaler_ctx = sys::sws_getContext(
-            (*dec_ctx).width,
-            (*dec_ctx).height,
-            (*dec_ctx).pix_fmt,
-            width as i32,
-            height as i32,
-            sys::AVPixelFormat::AV_PIX_FMT_YUV420P,
-            sys::SWS_BILINEAR as i32,
-            ptr::null_mut(),
-            ptr::null_mut(),
-            ptr::null_mut(),
-        );
-
-        let preferred_encoders = ["h264_nvenc", "libx264"];
-        let mut encoder: *const sys::AVCodec = ptr::null();
-        let mut chosen_encoder_name = "";
-
-        for name in preferred_encoders.iter() {
-            let c_name = cstring!(*name);
-            let found_encoder = sys::avcodec_find_encoder_by_name(c_name.as_ptr());
-            if !found_encoder.is_null() {
-                encoder = found_encoder;
-                chosen_encoder_name = *name;
-                info!("[recorder] Selected encoder: {}", chosen_encoder_name);
-                break;
+#[cfg(target_os = "windows")]
+impl WindowWatch for GdiWindow {
+    fn size(&self) -> Option<(u32, u32)> {
+        unsafe {
+            // gdigrab cannot read a minimized window, so treat it as closed.
+            if IsWindow(self.hwnd) == 0
+                || IsWindowVisible(self.hwnd) == 0
+                || IsIconic(self.hwnd) != 0
+            {
+                return None;
+            }
+            // gdigrab captures the client area.
+            let mut rect = Rect::default();
+            if GetClientRect(self.hwnd, &mut rect) == 0 {
+                return None;
             }
+            Some((
+                (rect.right - rect.left).max(0) as u32,
+                (rect.bottom - rect.top).max(0) as u32,
+            ))
         }
+    }
+}
 
-        if encoder.is_null() {
-            error!("[recorder] Could not find any suitable H.264 encoder.");
-            // Perform cleanup
-            sys::sws_freeContext(scaler_ctx);
-            sys::avcodec_free_context(&mut dec_ctx);
-            sys::avformat_close_input(&mut fmt_ctx);
-            return;
-        }
+#[cfg(target_os = "windows")]
+fn find_window(text: &str) -> Result<(Box<dyn WindowWatch>, String), String> {
+    let (window, title) = GdiWindow::find(text)?;
+    Ok((Box::new(window), title))
+}
 
-        enc_ctx = sys::avcodec_alloc_context3(encoder);
-        if enc_ctx.is_null() {
-            error!("[recorder] Failed to allocate encoder context");
-            // Perform cleanup
-            sys::sws_freeContext(scaler_ctx);
-            sys::avcodec_free_context(&mut dec_ctx);
-            sys::avformat_close_input(&mut fmt_ctx);
-            return;
-        }
+#[cfg(not(target_os = "windows"))]
+fn find_window(_text: &str) -> Result<(Box<dyn WindowWatch>, String), String> {
+    Err("gdigrab is only available on Windows".to_string())
+}
 
-        (*enc_ctx).width = width as i32;
-        (*enc_ctx).height = height as i32;
-        (*enc_ctx).pix_fmt = sys::AVPixelFormat::AV_PIX_FMT_YUV420P;
-        (*enc_ctx).time_base = sys::AVRational {
-            num: 1,
-            den: fps as i32,
-        };
-        (*enc_ctx).framerate = sys::AVRational {
-            num: fps as i32,
-            den: 1,
-        };
+pub struct WindowsRecorder {
+    session: CaptureSession,
+}
 
-        (*enc_ctx).gop_size = fps as i32;
-        (*enc_ctx).max_b_frames = 0;
+// SAFETY: We ensure the pointer is only used while valid.
+unsafe impl Send for WindowsRecorder {}
+unsafe impl Sync for WindowsRecorder {}
 
-        let mut enc_opts = AVDict::new();
+#[async_trait]
+impl Recorder for WindowsRecorder {
+    fn new(config: RecorderConfig) -> Self {
+        Self {
+            session: CaptureSession::new(config),
+        }
+    }
 
-        //Find best encoder
-        match chosen_encoder_name {
-            "h264_nvenc" => {
-                info!("[recorder] Applying h264_nvenc settings");
-                enc_opts.set("preset", "p5"); // p4 or p5 are good balances of speed/quality
-                enc_opts.set("tune", "ll"); // Low-latency tune
-                enc_opts.set("rc", "vbr"); // Variable bitrate
-                enc_opts.set("cq", "24");
-                (*enc_ctx).pix_fmt = sys::AVPixelFormat::AV_PIX_FMT_YUV420P;
+    async fn start(&mut self) {
+        let config = self.session.config();
+        let mut options = vec![("framerate".to_string(), config.fps.to_string())];
+        let mut window: Option<Box<dyn WindowWatch>> = None;
+        let url = match (&config.target, config.region) {
+            (CaptureTarget::WindowTitle(title), _) => {
+                // gdigrab matches the full window title and sizes the
+                // capture to the window itself.
+                match find_window(title) {
+                    Ok((found, full_title)) => {
+                        window = Some(found);
+                        format!("title={}", full_title)
+                    }
+                    Err(e) => {
+                        error!("[recorder] {}", e);
+                        return;
+                    }
+                }
             }
-            "libx264" => {
-                info!("[recorder] Applying libx264 settings");
-                enc_opts.set("preset", "veryfast");
-                enc_opts.set("tune", "zerolatency");
-                enc_opts.set("crf", "22");
+            // Rejected up front by `CaptureTarget::is_supported`.
+            (CaptureTarget::WindowClass(_) | CaptureTarget::WindowId(_), _) => {
+                error!("[recorder] gdigrab can only find windows by title");
+                return;
             }
-            _ => {}
-        }
-
-        if sys::avcodec_open2(enc_ctx, encoder, enc_opts.as_mut_ptr()) < 0 {
-            error!("[recorder] Failed to open encoder");
-            return;
-        }
-
-        {
-            let encoder_codecpar = sys::avcodec_parameters_alloc();
-            if sys::avcodec_parameters_from_context(encoder_codecpar, enc_ctx) >= 0 {
-                let mut lock = codecpar_arc.lock().unwrap();
-                *lock = Some(CodecParPtr(encoder_codecpar));
+            (CaptureTarget::Screen, Some(region)) => {
+                options.extend([
+                    (
+                        "video_size".to_string(),
+                        format!("{}x{}", region.width, region.height),
+                    ),
+                    ("offset_x".to_string(), region.x.to_string()),
+                    ("offset_y".to_string(), region.y.to_string()),
+                ]);
+                "desktop".to_string()
             }
-        }
-
-        // Allocate frames and packets
-        packet = sys::av_packet_alloc();
-        decoded_frame = sys::av_frame_alloc();
-        scaled_frame = sys::av_frame_alloc();
-        (*scaled_frame).width = width as i32;
-        (*scaled_frame).height = height as i32;
-        (*scaled_frame).format = sys::AVPixelFormat::AV_PIX_FMT_YUV420P as i32;
-        sys::av_frame_get_buffer(scaled_frame, 0);
-
-        let mut frame_index = 0i64;
-
-        // Capture Loop
-        while sys::av_read_frame(fmt_ctx, packet) >= 0 {
-            if *stop_signal.blocking_lock() {
-                sys::av_packet_unref(packet);
-                break;
+            (CaptureTarget::Screen, None) => {
+                options.push((
+                    "video_size".to_string(),
+                    format!("{}x{}", config.width, config.height),
+                ));
+                "desktop".to_string()
             }
+        };
+        let input = VideoInput {
+            format: "gdigrab",
+            url,
+            options,
+            crop: None,
+            window,
+            help: &[],
+        };
+        self.session.start(input).await;
+        info!("[recorder] gdigrab capture started");
+    }
 
-            if (*packet).stream_index == video_stream_index {
-                if sys::avcodec_send_packet(dec_ctx, packet) >= 0 {
-                    while sys::avcodec_receive_frame(dec_ctx, decoded_frame) >= 0 {
-                        //Scale
-                        sys::sws_scale(
-                            scaler_ctx,
-                            (*decoded_frame).data.as_ptr() as *const *const u8,
-                            (*decoded_frame).linesize.as_ptr(),
-                            0,
-                            (*dec_ctx).height,
-                            (*scaled_frame).data.as_ptr(),
-                            (*scaled_frame).linesize.as_ptr(),
-                        );
-
-                        (*scaled_frame).pts = frame_index;
-                        frame_index += 1;
+    async fn stop(&mut self) {
+        self.session.stop().await;
+    }
 
-                        //Encode
-                        if sys::avcodec_send_frame(enc_ctx, scaled_frame) >= 0 {
-                            loop {
-                                let mut enc_packet = sys::av_packet_alloc();
-                                let ret = sys::avcodec_receive_packet(enc_ctx, enc_packet);
-                                if ret == sys::AVERROR(sys::EAGAIN) || ret == sys::AVERROR_EOF {
-                                    sys::av_packet_free(&mut enc_packet);
-                                    break;
-                                } else if ret < 0 {
-                                    error!("[recorder] Error receiving packet from encoder");
-                                    sys::av_packet_free(&mut enc_packet);
-                                    break;
-                                }
+    fn save(
+        &self,
+        final_output_path: &str,
+        overwrite: OverwritePolicy,
+    ) -> Result<SavedClip, String> {
+        self.session.save(final_output_path, overwrite)
+    }
 
-                                let is_key =
-                                    ((*enc_packet).flags & sys::AV_PKT_FLAG_KEY as i32) != 0;
-                                let data = std::slice::from_raw_parts(
-                                    (*enc_packet).data,
-                                    (*enc_packet).size as usize,
-                                );
-                                replay_buffer.add_packet(data.to_vec(), is_key);
-                                sys::av_packet_unref(enc_packet);
-                                sys::av_packet_free(&mut enc_packet);
-                            }
-                        }
-                    }
-                }
-            }
-            sys::av_packet_unref(packet);
-        }
+    fn save_range(
+        &self,
+        final_output_path: &str,
+        start: Instant,
+        end: Instant,
+        overwrite: OverwritePolicy,
+    ) -> Result<SavedClip, String> {
+        self.session
+            .save_range(final_output_path, start, end, overwrite)
+    }
 
-        // Cleanup
-        sys::av_frame_free(&mut decoded_frame);
-        sys::av_frame_free(&mut scaled_frame);
-        sys::av_packet_free(&mut packet);
+    fn request_keyframe(&self) {
+        self.session.request_keyframe();
+    }
 
-        sys::sws_freeContext(scaler_ctx);
+    fn set_push_to_talk(&self, held: bool) {
+        self.session.set_push_to_talk(held);
+    }
 
-        sys::avcodec_free_context(&mut dec_ctx);
-        sys::avcodec_free_context(&mut enc_ctx);
+    fn stats(&self) -> RecorderStats {
+        self.session.stats()
+    }
 
-        sys::avformat_close_input(&mut fmt_ctx);
-        info!("[recorder] ffmpeg-sys capture thread stopped");
+    fn get_output_path(&self) -> &str {
+        "output.mp4"
     }
 }
@@ -0,0 +1,339 @@
+//! Finding and following a single X11 window, for window capture on Linux,
+//! and listing the monitors of the X screen.
+
+use x11rb::connection::Connection;
+use x11rb::protocol::randr::ConnectionExt as _;
+use x11rb::protocol::xproto::{AtomEnum, ConnectionExt, MapState, Window};
+use x11rb::rust_connection::RustConnection;
+
+use crate::capture::WindowWatch;
+use crate::devices::Display;
+use crate::recorder::{CaptureRegion, CaptureTarget};
+
+/// A window on the X server, kept on its own connection so the capture
+/// thread can poll it.
+pub struct X11Window {
+    conn: RustConnection,
+    id: Window,
+}
+
+impl X11Window {
+    /// Connects to `$DISPLAY` and looks up the window `target` names.
+    pub fn find(target: &CaptureTarget) -> Result<Self, String> {
+        let (conn, screen) = RustConnection::connect(None)
+            .map_err(|e| format!("Failed to connect to the X server: {}", e))?;
+        let root = conn.setup().roots[screen].root;
+
+        let id = match target {
+            CaptureTarget::Screen => root,
+            CaptureTarget::WindowId(id) => {
+                let id = Window::try_from(*id)
+                    .map_err(|_| format!("{:#x} is not an X11 window ID", id))?;
+                if !exists(&conn, id) {
+                    return Err(format!("No window with ID {:#x}", id));
+                }
+                id
+            }
+            CaptureTarget::WindowTitle(title) => {
+                let needle = title.to_lowercase();
+                candidates(&conn, root)?
+                    .into_iter()
+                    .find(|&w| {
+                        window_title(&conn, w).is_some_and(|t| t.to_lowercase().contains(&needle))
+                    })
+                    .ok_or_else(|| format!("No window with a title containing {:?}", title))?
+            }
+            CaptureTarget::WindowClass(class) => candidates(&conn, root)?
+                .into_iter()
+                .find(|&w| {
+                    window_class(&conn, w)
+                        .iter()
+                        .any(|c| c.eq_ignore_ascii_case(class))
+                })
+                .ok_or_else(|| format!("No window with class {:?}", class))?,
+        };
+        Ok(Self { conn, id })
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn title(&self) -> Option<String> {
+        window_title(&self.conn, self.id)
+    }
+}
+
+impl WindowWatch for X11Window {
+    fn size(&self) -> Option<(u32, u32)> {
+        // x11grab cannot read an unmapped window, so treat it as closed.
+        let attributes = self
+            .conn
+            .get_window_attributes(self.id)
+            .ok()?
+            .reply()
+            .ok()?;
+        if attributes.map_state != MapState::VIEWABLE {
+            return None;
+        }
+        let geometry = self.conn.get_geometry(self.id).ok()?.reply().ok()?;
+        Some((geometry.width as u32, geometry.height as u32))
+    }
+}
+
+/// Monitors of the default screen. Falls back to the whole screen when the
+/// server has no RandR, as under Xvfb.
+pub(crate) fn monitors() -> Result<Vec<Display>, String> {
+    let (conn, screen) = RustConnection::connect(None)
+        .map_err(|e| format!("Failed to connect to the X server: {}", e))?;
+    let screen = &conn.setup().roots[screen];
+
+    let monitors = conn
+        .randr_get_monitors(screen.root, true)
+        .ok()
+        .and_then(|cookie| cookie.reply().ok())
+        .map(|reply| reply.monitors)
+        .unwrap_or_default();
+    if monitors.is_empty() {
+        let (width, height) = (
+            screen.width_in_pixels as u32,
+            screen.height_in_pixels as u32,
+        );
+        return Ok(vec![Display {
+            name: "Screen".to_string(),
+            width,
+            height,
+            region: Some(CaptureRegion {
+                x: 0,
+                y: 0,
+                width,
+                height,
+            }),
+            device: None,
+            primary: true,
+        }]);
+    }
+
+    Ok(monitors
+        .into_iter()
+        .map(|monitor| {
+            let name = conn
+                .get_atom_name(monitor.name)
+                .ok()
+                .and_then(|cookie| cookie.reply().ok())
+                .map(|reply| String::from_utf8_lossy(&reply.name).into_owned())
+                .unwrap_or_else(|| "Monitor".to_string());
+            let (width, height) = (monitor.width as u32, monitor.height as u32);
+            Display {
+                name,
+                width,
+                height,
+                region: Some(CaptureRegion {
+                    x: monitor.x as i32,
+                    y: monitor.y as i32,
+                    width,
+                    height,
+                }),
+                device: None,
+                primary: monitor.primary,
+            }
+        })
+        .collect())
+}
+
+fn exists(conn: &RustConnection, window: Window) -> bool {
+    conn.get_window_attributes(window)
+        .ok()
+        .and_then(|cookie| cookie.reply().ok())
+        .is_some()
+}
+
+/// Top-level windows, as listed by the window manager. Without one, e.g.
+/// under Xvfb, every window in the tree is a candidate.
+fn candidates(conn: &RustConnection, root: Window) -> Result<Vec<Window>, String> {
+    let client_list = atom(conn, b"_NET_CLIENT_LIST")?;
+    let listed = conn
+        .get_property(false, root, client_list, AtomEnum::WINDOW, 0, u32::MAX)
+        .ok()
+        .and_then(|cookie| cookie.reply().ok())
+        .and_then(|reply| reply.value32().map(|ids| ids.collect::<Vec<_>>()))
+        .unwrap_or_default();
+    if !listed.is_empty() {
+        return Ok(listed);
+    }
+
+    let mut windows = Vec::new();
+    let mut pending = vec![root];
+    while let Some(window) = pending.pop() {
+        let children = conn
+            .query_tree(window)
+            .map_err(|e| e.to_string())?
+            .reply()
+            .map_err(|e| e.to_string())?
+            .children;
+        windows.extend(&children);
+        pending.extend(children);
+    }
+    Ok(windows)
+}
+
+fn atom(conn: &RustConnection, name: &[u8]) -> Result<u32, String> {
+    Ok(conn
+        .intern_atom(false, name)
+        .map_err(|e| e.to_string())?
+        .reply()
+        .map_err(|e| e.to_string())?
+        .atom)
+}
+
+fn string_property(conn: &RustConnection, window: Window, property: u32) -> Option<Vec<u8>> {
+    let reply = conn
+        .get_property(false, window, property, AtomEnum::ANY, 0, 1024)
+        .ok()?
+        .reply()
+        .ok()?;
+    if reply.value.is_empty() {
+        None
+    } else {
+        Some(reply.value)
+    }
+}
+
+/// The EWMH title if the window has one, its ICCCM name otherwise.
+fn window_title(conn: &RustConnection, window: Window) -> Option<String> {
+    let net_wm_name = atom(conn, b"_NET_WM_NAME").ok()?;
+    string_property(conn, window, net_wm_name)
+        .or_else(|| string_property(conn, window, AtomEnum::WM_NAME.into()))
+        .map(|name| String::from_utf8_lossy(&name).into_owned())
+}
+
+/// Instance and class name from `WM_CLASS`.
+fn window_class(conn: &RustConnection, window: Window) -> Vec<String> {
+    string_property(conn, window, AtomEnum::WM_CLASS.into())
+        .map(|value| {
+            value
+                .split(|&b| b == 0)
+                .filter(|part| !part.is_empty())
+                .map(|part| String::from_utf8_lossy(part).into_owned())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// These talk to a real X server, e.g. under `xvfb-run cargo test`, and are
+/// skipped when `$DISPLAY` is not set.
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use x11rb::protocol::xproto::{ConfigureWindowAux, CreateWindowAux, PropMode, WindowClass};
+    use x11rb::wrapper::ConnectionExt as _;
+
+    use super::*;
+
+    fn x_server() -> Option<(RustConnection, usize)> {
+        if std::env::var_os("DISPLAY").is_none() {
+            eprintln!("DISPLAY is not set, skipping X11 test");
+            return None;
+        }
+        Some(RustConnection::connect(None).expect("DISPLAY is set but unreachable"))
+    }
+
+    /// Waits until the server has handled everything sent on `conn`.
+    fn sync(conn: &RustConnection) {
+        conn.get_input_focus().unwrap().reply().unwrap();
+    }
+
+    /// Polls `check` for a while, since a window manager may handle
+    /// requests after the server has.
+    fn eventually(mut check: impl FnMut() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while Instant::now() < deadline {
+            if check() {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        check()
+    }
+
+    #[test]
+    fn finds_and_follows_a_window() {
+        let Some((conn, screen)) = x_server() else {
+            return;
+        };
+        let root = conn.setup().roots[screen].root;
+        let title = format!("Mebal test window {}", std::process::id());
+
+        let window = conn.generate_id().unwrap();
+        conn.create_window(
+            x11rb::COPY_DEPTH_FROM_PARENT,
+            window,
+            root,
+            0,
+            0,
+            320,
+            240,
+            0,
+            WindowClass::INPUT_OUTPUT,
+            0,
+            &CreateWindowAux::new(),
+        )
+        .unwrap();
+        conn.change_property8(
+            PropMode::REPLACE,
+            window,
+            AtomEnum::WM_NAME,
+            AtomEnum::STRING,
+            title.as_bytes(),
+        )
+        .unwrap();
+        conn.change_property8(
+            PropMode::REPLACE,
+            window,
+            AtomEnum::WM_CLASS,
+            AtomEnum::STRING,
+            b"mebal-test\0MebalTest\0",
+        )
+        .unwrap();
+        conn.map_window(window).unwrap();
+        sync(&conn);
+
+        let by_title = CaptureTarget::WindowTitle(title.to_uppercase());
+        assert!(eventually(
+            || X11Window::find(&by_title).is_ok_and(|found| found.id() == window)
+        ));
+        let by_class =
+            X11Window::find(&CaptureTarget::WindowClass("mebaltest".to_string())).unwrap();
+        assert_eq!(by_class.id(), window);
+        assert_eq!(by_class.title().as_deref(), Some(title.as_str()));
+
+        let watched = X11Window::find(&CaptureTarget::WindowId(window as u64)).unwrap();
+        assert!(eventually(|| watched.size() == Some((320, 240))));
+
+        conn.configure_window(window, &ConfigureWindowAux::new().width(400).height(300))
+            .unwrap();
+        sync(&conn);
+        assert!(eventually(|| watched.size() == Some((400, 300))));
+
+        conn.unmap_window(window).unwrap();
+        sync(&conn);
+        assert!(eventually(|| watched.size().is_none()));
+
+        conn.destroy_window(window).unwrap();
+        sync(&conn);
+        assert!(watched.size().is_none());
+        assert!(X11Window::find(&CaptureTarget::WindowId(window as u64)).is_err());
+    }
+
+    #[test]
+    fn lists_monitors() {
+        if x_server().is_none() {
+            return;
+        }
+        let monitors = monitors().unwrap();
+        assert!(!monitors.is_empty());
+        assert!(monitors.iter().all(|m| m.width > 0 && m.height > 0));
+        assert!(monitors.iter().filter(|m| m.primary).count() <= 1);
+    }
+}
@@ -0,0 +1,400 @@
+//! Lossless editing of saved clips. Packets are copied as-is, so cuts can
+//! only start on keyframes and concatenated clips must share codec settings.
+
+use common::log::info;
+use common::sys;
+use std::ffi::CStr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::in_use;
+use crate::mux::{InputFile, OutputFile, OverwritePolicy, Packet};
+
+const MICROSECONDS: sys::AVRational = sys::AVRational {
+    num: 1,
+    den: 1_000_000,
+};
+
+/// The range a trim actually produced, after snapping to keyframes.
+#[derive(Clone, Debug)]
+pub struct TrimResult {
+    pub path: PathBuf,
+    pub start: Duration,
+    pub duration: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct ConcatResult {
+    pub path: PathBuf,
+    pub duration: Duration,
+}
+
+/// Copies `start..end` of `input` into `output` without re-encoding.
+///
+/// The cut starts at the last keyframe at or before `start`, so the result may
+/// begin slightly earlier than asked. With `end` unset the clip runs to the end
+/// of the input.
+pub fn trim(
+    input: &Path,
+    output: &Path,
+    start: Duration,
+    end: Option<Duration>,
+    overwrite: OverwritePolicy,
+) -> Result<TrimResult, String> {
+    if end.is_some_and(|end| end <= start) {
+        return Err("Trim end must be after its start".to_string());
+    }
+
+    // The output is claimed by the muxer.
+    let _input_in_use = in_use::claim(input);
+
+    let mut source = InputFile::open(&input.to_string_lossy())?;
+    let video_index = source
+        .video_stream()
+        .ok_or_else(|| format!("{} has no video stream", input.display()))?;
+
+    let mut muxer = OutputFile::create(&output.to_string_lossy(), overwrite)?;
+    for i in 0..source.stream_count() {
+        muxer.add_stream(source.codecpar(i))?;
+    }
+    muxer.write_header()?;
+
+    let start_us = start.as_micros() as i64;
+    let end_us = end.map(|end| end.as_micros() as i64);
+    let packet = Packet::new()?;
+
+    unsafe {
+        let video_tb = source.time_base(video_index);
+        let seek_ts = sys::av_rescale_q(start_us, MICROSECONDS, video_tb);
+        if sys::av_seek_frame(
+            source.ctx,
+            video_index as i32,
+            seek_ts,
+            sys::AVSEEK_FLAG_BACKWARD as i32,
+        ) < 0
+        {
+            return Err("Failed to seek to trim start".to_string());
+        }
+
+        // Everything is shifted so the first kept keyframe lands at zero.
+        let mut cut_us: Option<i64> = None;
+        let mut last_us = 0i64;
+
+        while source.read_packet(packet.0) {
+            let index = (*packet.0).stream_index as usize;
+            let in_tb = source.time_base(index);
+            let dts = if (*packet.0).dts != sys::AV_NOPTS_VALUE {
+                (*packet.0).dts
+            } else {
+                (*packet.0).pts
+            };
+            let dts_us = sys::av_rescale_q(dts, in_tb, MICROSECONDS);
+
+            if cut_us.is_none() {
+                let is_key = (*packet.0).flags & sys::AV_PKT_FLAG_KEY as i32 != 0;
+                if index != video_index || !is_key {
+                    sys::av_packet_unref(packet.0);
+                    continue;
+                }
+                cut_us = Some(dts_us);
+            }
+            let cut = cut_us.unwrap();
+
+            // Packets are cut in decode order, so every frame kept still has
+            // its references.
+            if end_us.is_some_and(|end| dts_us >= end) {
+                sys::av_packet_unref(packet.0);
+                if index == video_index {
+                    break;
+                }
+                continue;
+            }
+            if dts_us < cut {
+                sys::av_packet_unref(packet.0);
+                continue;
+            }
+
+            let out_tb = muxer.time_base(index as i32);
+            shift_packet(packet.0, -sys::av_rescale_q(cut, MICROSECONDS, in_tb));
+            let end_ts = (*packet.0).pts.max((*packet.0).dts) + (*packet.0).duration;
+            last_us = last_us.max(sys::av_rescale_q(end_ts, in_tb, MICROSECONDS));
+            sys::av_packet_rescale_ts(packet.0, in_tb, out_tb);
+            (*packet.0).pos = -1;
+            muxer.write_packet(packet.0);
+            sys::av_packet_unref(packet.0);
+        }
+
+        let cut = cut_us.ok_or_else(|| "No keyframe found in trim range".to_string())?;
+        let (path, _) = muxer.finish()?;
+
+        let result = TrimResult {
+            path,
+            start: Duration::from_micros(cut.max(0) as u64),
+            duration: Duration::from_micros(last_us.max(0) as u64),
+        };
+        info!(
+            "[storage] Trimmed {} -> {} (start {:?}, duration {:?})",
+            input.display(),
+            result.path.display(),
+            result.start,
+            result.duration
+        );
+        Ok(result)
+    }
+}
+
+/// Joins `inputs` end to end into `output` without re-encoding.
+///
+/// Every input must have the same streams with identical codec parameters;
+/// otherwise an error naming the first mismatch is returned and no file is
+/// written. Timestamps are offset so playback is continuous across the joins.
+pub fn concat(
+    inputs: &[&Path],
+    output: &Path,
+    overwrite: OverwritePolicy,
+) -> Result<ConcatResult, String> {
+    if inputs.is_empty() {
+        return Err("Nothing to concatenate".to_string());
+    }
+
+    let _inputs_in_use: Vec<_> = inputs.iter().map(|p| in_use::claim(p)).collect();
+
+    let mut sources = inputs
+        .iter()
+        .map(|p| InputFile::open(&p.to_string_lossy()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for source in &sources[1..] {
+        check_compatible(&sources[0], source)?;
+    }
+
+    let mut muxer = OutputFile::create(&output.to_string_lossy(), overwrite)?;
+    for i in 0..sources[0].stream_count() {
+        muxer.add_stream(sources[0].codecpar(i))?;
+    }
+    muxer.write_header()?;
+
+    let packet = Packet::new()?;
+    let mut offset_us = 0i64;
+
+    unsafe {
+        for source in &mut sources {
+            // Each clip is rebased to zero before being appended.
+            let start_us = clip_start_us(source);
+            let mut clip_end_us = 0i64;
+
+            while source.read_packet(packet.0) {
+                let index = (*packet.0).stream_index as usize;
+                let in_tb = source.time_base(index);
+                let out_tb = muxer.time_base(index as i32);
+
+                let shift = sys::av_rescale_q(offset_us - start_us, MICROSECONDS, in_tb);
+                shift_packet(packet.0, shift);
+
+                let end_ts = (*packet.0).pts.max((*packet.0).dts) + (*packet.0).duration;
+                clip_end_us = clip_end_us.max(sys::av_rescale_q(end_ts, in_tb, MICROSECONDS));
+
+                sys::av_packet_rescale_ts(packet.0, in_tb, out_tb);
+                (*packet.0).pos = -1;
+                muxer.write_packet(packet.0);
+                sys::av_packet_unref(packet.0);
+            }
+
+            offset_us = offset_us.max(clip_end_us);
+        }
+    }
+
+    let (path, _) = muxer.finish()?;
+
+    let duration = Duration::from_micros(offset_us.max(0) as u64);
+    info!(
+        "[storage] Concatenated {} clips into {} ({:?})",
+        inputs.len(),
+        path.display(),
+        duration
+    );
+    Ok(ConcatResult { path, duration })
+}
+
+unsafe fn shift_packet(packet: *mut sys::AVPacket, shift: i64) {
+    unsafe {
+        if (*packet).pts != sys::AV_NOPTS_VALUE {
+            (*packet).pts += shift;
+        }
+        if (*packet).dts != sys::AV_NOPTS_VALUE {
+            (*packet).dts += shift;
+        }
+    }
+}
+
+/// Earliest decode timestamp of any stream, in microseconds.
+fn clip_start_us(source: &InputFile) -> i64 {
+    (0..source.stream_count())
+        .filter_map(|i| unsafe {
+            let stream = source.stream(i);
+            let start = (*stream).start_time;
+            (start != sys::AV_NOPTS_VALUE)
+                .then(|| sys::av_rescale_q(start, (*stream).time_base, MICROSECONDS))
+        })
+        .min()
+        .unwrap_or(0)
+}
+
+fn check_compatible(first: &InputFile, other: &InputFile) -> Result<(), String> {
+    let streams = |input: &InputFile| {
+        (0..input.stream_count())
+            .map(|i| unsafe { &*input.codecpar(i) })
+            .collect::<Vec<_>>()
+    };
+    compatible_streams(&streams(first), &streams(other)).map_err(|reason| {
+        format!(
+            "{} cannot be joined to {}: {}",
+            other.path(),
+            first.path(),
+            reason
+        )
+    })
+}
+
+/// Checks that streams `other` can follow streams `first` in one file.
+fn compatible_streams(
+    first: &[&sys::AVCodecParameters],
+    other: &[&sys::AVCodecParameters],
+) -> Result<(), String> {
+    if first.len() != other.len() {
+        return Err(format!("{} streams vs {}", other.len(), first.len()));
+    }
+
+    for (i, (a, b)) in first.iter().zip(other).enumerate() {
+        if a.codec_type != b.codec_type || a.codec_id != b.codec_id {
+            return Err(format!(
+                "stream {} is {} vs {}",
+                i,
+                codec_name(b.codec_id),
+                codec_name(a.codec_id)
+            ));
+        }
+        if a.width != b.width || a.height != b.height {
+            return Err(format!(
+                "stream {} is {}x{} vs {}x{}",
+                i, b.width, b.height, a.width, a.height
+            ));
+        }
+        if a.format != b.format {
+            return Err(format!("stream {} uses a different pixel/sample format", i));
+        }
+        if a.sample_rate != b.sample_rate || a.ch_layout.nb_channels != b.ch_layout.nb_channels {
+            return Err(format!(
+                "stream {} is {} Hz/{} ch vs {} Hz/{} ch",
+                i, b.sample_rate, b.ch_layout.nb_channels, a.sample_rate, a.ch_layout.nb_channels
+            ));
+        }
+        if extradata(a) != extradata(b) {
+            return Err(format!(
+                "stream {} was encoded with different settings (extradata differs)",
+                i
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn extradata(par: &sys::AVCodecParameters) -> &[u8] {
+    if par.extradata.is_null() || par.extradata_size <= 0 {
+        return &[];
+    }
+    unsafe { std::slice::from_raw_parts(par.extradata, par.extradata_size as usize) }
+}
+
+fn codec_name(id: sys::AVCodecID) -> String {
+    unsafe {
+        CStr::from_ptr(sys::avcodec_get_name(id))
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Codec parameters, freed on drop.
+    struct Params(*mut sys::AVCodecParameters);
+
+    impl Params {
+        fn video(width: i32, height: i32) -> Self {
+            let params = unsafe { sys::avcodec_parameters_alloc() };
+            assert!(!params.is_null());
+            unsafe {
+                (*params).codec_type = sys::AVMediaType::AVMEDIA_TYPE_VIDEO;
+                (*params).codec_id = sys::AVCodecID::AV_CODEC_ID_H264;
+                (*params).width = width;
+                (*params).height = height;
+                (*params).format = sys::AVPixelFormat::AV_PIX_FMT_YUV420P as i32;
+            }
+            Self(params)
+        }
+
+        fn audio(sample_rate: i32) -> Self {
+            let params = unsafe { sys::avcodec_parameters_alloc() };
+            assert!(!params.is_null());
+            unsafe {
+                (*params).codec_type = sys::AVMediaType::AVMEDIA_TYPE_AUDIO;
+                (*params).codec_id = sys::AVCodecID::AV_CODEC_ID_AAC;
+                (*params).sample_rate = sample_rate;
+                (*params).ch_layout.nb_channels = 2;
+            }
+            Self(params)
+        }
+
+        fn get(&self) -> &sys::AVCodecParameters {
+            unsafe { &*self.0 }
+        }
+    }
+
+    impl Drop for Params {
+        fn drop(&mut self) {
+            unsafe { sys::avcodec_parameters_free(&mut self.0) };
+        }
+    }
+
+    #[test]
+    fn identical_streams_are_compatible() {
+        let (video, audio) = (Params::video(1920, 1080), Params::audio(48000));
+        let streams = [video.get(), audio.get()];
+        assert!(compatible_streams(&streams, &streams).is_ok());
+    }
+
+    #[test]
+    fn differing_streams_are_rejected() {
+        let video = Params::video(1920, 1080);
+        let audio = Params::audio(48000);
+
+        let smaller = Params::video(1280, 720);
+        let other_rate = Params::audio(44100);
+        let hevc = Params::video(1920, 1080);
+        unsafe { (*hevc.0).codec_id = sys::AVCodecID::AV_CODEC_ID_HEVC };
+        let with_extradata = Params::video(1920, 1080);
+        unsafe {
+            // Freed along with the parameters.
+            let extradata = sys::av_mallocz(16) as *mut u8;
+            assert!(!extradata.is_null());
+            *extradata.add(3) = 1;
+            (*with_extradata.0).extradata = extradata;
+            (*with_extradata.0).extradata_size = 4;
+        }
+
+        let first = [video.get(), audio.get()];
+        let cases: [(&[&sys::AVCodecParameters], &str); 5] = [
+            (&[video.get()], "1 streams vs 2"),
+            (&[smaller.get(), audio.get()], "1280x720"),
+            (&[video.get(), other_rate.get()], "44100 Hz"),
+            (&[hevc.get(), audio.get()], "hevc"),
+            (&[with_extradata.get(), audio.get()], "extradata"),
+        ];
+        for (other, reason) in cases {
+            let error = compatible_streams(&first, other).unwrap_err();
+            assert!(error.contains(reason), "{:?} lacks {:?}", error, reason);
+        }
+    }
+}
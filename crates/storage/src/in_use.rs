@@ -0,0 +1,53 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
+
+/// Files currently being written or read by a save or export, with a count of
+/// how many operations hold each one.
+static IN_USE: LazyLock<Mutex<HashMap<PathBuf, usize>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Marks a file as busy until dropped. Retention never deletes a busy file.
+pub struct InUseGuard {
+    path: PathBuf,
+}
+
+/// Claims `path` for the lifetime of the returned guard. Several guards may
+/// hold the same path at once.
+pub fn claim(path: &Path) -> InUseGuard {
+    let path = normalize(path);
+    *IN_USE.lock().unwrap().entry(path.clone()).or_insert(0) += 1;
+    InUseGuard { path }
+}
+
+pub fn is_in_use(path: &Path) -> bool {
+    IN_USE.lock().unwrap().contains_key(&normalize(path))
+}
+
+impl Drop for InUseGuard {
+    fn drop(&mut self) {
+        let mut in_use = IN_USE.lock().unwrap();
+        if let Some(count) = in_use.get_mut(&self.path) {
+            *count -= 1;
+            if *count == 0 {
+                in_use.remove(&self.path);
+            }
+        }
+    }
+}
+
+/// Resolves `path` so that different spellings of the same file compare equal.
+/// Files that do not exist yet are resolved through their parent directory.
+fn normalize(path: &Path) -> PathBuf {
+    if let Ok(path) = path.canonicalize() {
+        return path;
+    }
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => Path::new(".")
+            .join(parent)
+            .canonicalize()
+            .map(|p| p.join(name))
+            .unwrap_or_else(|_| path.to_path_buf()),
+        _ => path.to_path_buf(),
+    }
+}
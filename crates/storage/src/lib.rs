@@ -1,17 +1,87 @@
+pub mod edit;
+pub mod in_use;
+pub mod library;
+pub mod mux;
+pub mod retention;
+pub mod template;
+pub mod thumbnail;
+pub mod verify;
+
 use common::log::{info, warn};
 use common::sys;
 use std::collections::VecDeque;
-use std::ffi::CString;
+use std::ffi::CStr;
+use std::path::{Path, PathBuf};
 use std::ptr;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
+use library::WallClock;
+use mux::OutputFile;
+pub use mux::OverwritePolicy;
+use verify::{ColorTags, VerificationReport};
+
+const MICROSECONDS: sys::AVRational = sys::AVRational {
+    num: 1,
+    den: 1_000_000,
+};
+
+/// Encoder timestamps of a packet. `pts` and `dts` differ once the encoder
+/// reorders frames, e.g. for B-frames.
+#[derive(Clone, Copy, Debug)]
+pub struct PacketTiming {
+    pub pts: i64,
+    pub dts: i64,
+    pub duration: i64,
+    pub time_base: sys::AVRational,
+}
+
+/// Stream that clips are cut on. Saved clips always start at one of its
+/// keyframes; other streams (audio) follow along.
+pub const VIDEO_STREAM: usize = 0;
+
+/// An encoded packet in decode order, as it came out of the encoder.
 #[derive(Clone)]
-#[repr(C)]
 pub struct TimestampedPacket {
+    pub stream: usize,
     pub data: Vec<u8>,
+    /// When the frame in this packet was captured.
     pub timestamp: Instant,
     pub is_keyframe: bool,
+    pub timing: PacketTiming,
+}
+
+impl TimestampedPacket {
+    fn is_video_keyframe(&self) -> bool {
+        self.stream == VIDEO_STREAM && self.is_keyframe
+    }
+}
+
+/// Describes a clip that was written to disk by [`ReplayBuffer::save_range_to_file`].
+#[derive(Clone, Debug)]
+pub struct SavedClip {
+    pub path: PathBuf,
+    /// Capture time of the first and last packet in the clip.
+    pub start: Instant,
+    pub end: Instant,
+    /// Wall-clock time of `start` and `end` in milliseconds since the Unix
+    /// epoch, read when the packets were taken from the buffer.
+    pub start_unix_ms: u64,
+    pub end_unix_ms: u64,
+    /// How much of the requested range had already left the replay buffer,
+    /// so the clip starts that much later than asked.
+    pub missing_pre_roll: Duration,
+    pub duration: Duration,
+    pub packet_count: usize,
+    pub size_bytes: u64,
+    pub width: u32,
+    pub height: u32,
+    /// Codec of the video stream, e.g. `h264`.
+    pub codec: String,
+    /// FFmpeg encoder that produced the video, e.g. `h264_nvenc`.
+    pub encoder: String,
+    /// Result of reading the finished file back.
+    pub verification: VerificationReport,
 }
 
 pub struct ReplayBuffer {
@@ -32,11 +102,33 @@ impl ReplayBuffer {
         buffer
     }
 
-    pub fn add_packet(&self, data: Vec<u8>, is_keyframe: bool) {
+    /// Number of packets held, and their size in bytes.
+    pub fn usage(&self) -> (usize, u64) {
+        let packets = self.packets.lock().unwrap();
+        let bytes = packets.iter().map(|p| p.data.len() as u64).sum();
+        (packets.len(), bytes)
+    }
+
+    /// Appends a packet in decode order and drops whole GOPs that have aged
+    /// out. Pruning only ever cuts right before a keyframe, so every packet
+    /// left still has the frames it references.
+    ///
+    /// `captured_at` is when the packet's frame was captured, which can be
+    /// well before it left the encoder.
+    pub fn add_packet(
+        &self,
+        stream: usize,
+        data: Vec<u8>,
+        is_keyframe: bool,
+        timing: PacketTiming,
+        captured_at: Instant,
+    ) {
         let packet = TimestampedPacket {
+            stream,
             data,
-            timestamp: Instant::now(),
+            timestamp: captured_at,
             is_keyframe,
+            timing,
         };
 
         let mut packets = self.packets.lock().unwrap();
@@ -54,7 +146,7 @@ impl ReplayBuffer {
             let last_keyframe_before_valid = packets
                 .iter()
                 .take(start_idx + 1)
-                .rposition(|p| p.is_keyframe);
+                .rposition(|p| p.is_video_keyframe());
 
             if let Some(prune_until_idx) = last_keyframe_before_valid {
                 if prune_until_idx > 0 {
@@ -68,7 +160,7 @@ impl ReplayBuffer {
                 }
             }
         } else {
-            if let Some(last_key_idx) = packets.iter().rposition(|p| p.is_keyframe) {
+            if let Some(last_key_idx) = packets.iter().rposition(|p| p.is_video_keyframe()) {
                 if last_key_idx > 0 {
                     packets.drain(0..last_key_idx);
                     info!(
@@ -85,105 +177,175 @@ impl ReplayBuffer {
     pub fn save_to_file(
         &self,
         output_path: &str,
-        codecpar: *mut sys::AVCodecParameters,
-        fps: u32,
-    ) -> Result<(), String> {
+        streams: &[Option<*mut sys::AVCodecParameters>],
+        encoder: &str,
+        overwrite: OverwritePolicy,
+    ) -> Result<SavedClip, String> {
         const REPLAY_DURATION_SECS: u64 = 15;
 
+        let end = Instant::now();
+        let start = end
+            .checked_sub(Duration::from_secs(REPLAY_DURATION_SECS))
+            .unwrap_or(end);
+        self.save_range_to_file(output_path, streams, encoder, start, end, overwrite)
+    }
+
+    /// Muxes every packet captured between `start` and `end` into `output_path`.
+    ///
+    /// The clip begins at the last keyframe at or before `start`, so it may
+    /// start slightly earlier than asked. If `start` has already been pruned
+    /// from the buffer, the clip starts at the oldest buffered keyframe and
+    /// [`SavedClip::missing_pre_roll`] says how much is lost. Packets newer
+    /// than `end` are left out, so a range whose end lies in the future must
+    /// only be saved once that moment has passed.
+    ///
+    /// The finished file is read back; one that fails the check is moved
+    /// aside to `<name>.failed` and an error returned.
+    ///
+    /// Packets keep their encoder timestamps, so reordered (B-frame) streams are
+    /// written with the original pts/dts, shifted to start at zero.
+    ///
+    /// `streams` holds the codec parameters for each buffer stream index, and
+    /// `encoder` names the encoder the video stream came from.
+    /// Streams without parameters, e.g. an audio device that failed to open,
+    /// are left out of the clip.
+    pub fn save_range_to_file(
+        &self,
+        output_path: &str,
+        streams: &[Option<*mut sys::AVCodecParameters>],
+        encoder: &str,
+        start: Instant,
+        end: Instant,
+        overwrite: OverwritePolicy,
+    ) -> Result<SavedClip, String> {
+        if end < start {
+            return Err("Save range ends before it starts".to_string());
+        }
+        let codecpar = streams
+            .get(VIDEO_STREAM)
+            .copied()
+            .flatten()
+            .ok_or_else(|| "Codec parameters not set".to_string())?;
+
         let packets_guard = self.packets.lock().unwrap();
+        let wall_clock = WallClock::now();
         if packets_guard.is_empty() {
             return Err("Replay buffer is empty".to_string());
         }
 
-        let replay_duration = Duration::from_secs(REPLAY_DURATION_SECS);
-        let cutoff_time = match Instant::now().checked_sub(replay_duration) {
-            Some(time) => time,
-            None => packets_guard.front().unwrap().timestamp,
-        };
+        if packets_guard.front().unwrap().timestamp > end {
+            return Err("Requested range is no longer in the replay buffer".to_string());
+        }
 
-        let window_start_index = packets_guard
-            .iter()
-            .position(|p| p.timestamp >= cutoff_time)
-            .unwrap_or(0);
+        let missing_pre_roll = packets_guard
+            .front()
+            .unwrap()
+            .timestamp
+            .saturating_duration_since(start);
+        if !missing_pre_roll.is_zero() {
+            warn!(
+                "[storage] Window start no longer buffered: {:?} of the requested range is lost",
+                missing_pre_roll
+            );
+        }
 
-        let first_keyframe_in_window = packets_guard
+        // Start on the keyframe the requested start depends on, like
+        // edit::trim does, or on the oldest one still buffered.
+        let final_slice_start = packets_guard
             .iter()
-            .skip(window_start_index)
-            .position(|p| p.is_keyframe);
-
-        let final_slice_start = match first_keyframe_in_window {
-            Some(relative_idx) => window_start_index + relative_idx,
-            None => packets_guard
-                .iter()
-                .rposition(|p| p.is_keyframe)
-                .unwrap_or(0),
-        };
-
-        let packets_to_save: Vec<TimestampedPacket> = packets_guard
+            .rposition(|p| p.is_video_keyframe() && p.timestamp <= start)
+            .or_else(|| {
+                packets_guard
+                    .iter()
+                    .position(|p| p.is_video_keyframe() && p.timestamp <= end)
+            })
+            .ok_or_else(|| "No keyframe in the requested range".to_string())?;
+
+        let mut packets_to_save: Vec<TimestampedPacket> = packets_guard
             .iter()
             .skip(final_slice_start)
+            .take_while(|p| p.timestamp <= end)
+            .filter(|p| streams.get(p.stream).copied().flatten().is_some())
             .cloned()
             .collect();
+        let following_pts = packets_guard
+            .iter()
+            .skip(final_slice_start)
+            .skip_while(|p| p.timestamp <= end)
+            .filter(|p| p.stream == VIDEO_STREAM)
+            .map(|p| p.timing.pts)
+            .min();
         drop(packets_guard);
 
-        unsafe {
-            let c_output_path =
-                CString::new(output_path).map_err(|_| "Invalid output path".to_string())?;
-            let mut format_ctx: *mut sys::AVFormatContext = ptr::null_mut();
-
-            sys::avformat_alloc_output_context2(
-                &mut format_ctx,
-                ptr::null_mut(),
-                ptr::null(),
-                c_output_path.as_ptr(),
-            );
-            if format_ctx.is_null() {
-                return Err("Failed to allocate output context".to_string());
-            }
-
-            let stream = sys::avformat_new_stream(format_ctx, ptr::null_mut());
-            if stream.is_null() {
-                sys::avformat_free_context(format_ctx);
-                return Err("Failed to create new stream".to_string());
-            }
+        if !packets_to_save
+            .first()
+            .is_some_and(|p| p.is_video_keyframe())
+        {
+            return Err("No packets in the requested range".to_string());
+        }
+        trim_reordered_edges(&mut packets_to_save, following_pts);
+
+        // Other streams were captured on the same clock but may have reached
+        // the buffer after the keyframe; drop what predates it.
+        let clip_start = packets_to_save[0].timestamp;
+        packets_to_save.retain(|p| p.stream == VIDEO_STREAM || p.timestamp >= clip_start);
+
+        let video_packets = || packets_to_save.iter().filter(|p| p.stream == VIDEO_STREAM);
+        let first = packets_to_save[0].timing;
+        let start_pts = video_packets().map(|p| p.timing.pts).min().unwrap();
+        let end_pts = video_packets()
+            .map(|p| p.timing.pts + p.timing.duration.max(0))
+            .max()
+            .unwrap();
+        let frame_count = video_packets().count();
+        let duration = Duration::from_micros(
+            unsafe { sys::av_rescale_q(end_pts - start_pts, first.time_base, MICROSECONDS) }.max(0)
+                as u64,
+        );
 
-            if sys::avcodec_parameters_copy((*stream).codecpar, codecpar) < 0 {
-                sys::avformat_free_context(format_ctx);
-                return Err("Failed to copy codec parameters".to_string());
-            }
+        let (width, height, codec) = unsafe {
+            let codec_name = CStr::from_ptr(sys::avcodec_get_name((*codecpar).codec_id));
+            (
+                (*codecpar).width as u32,
+                (*codecpar).height as u32,
+                codec_name.to_string_lossy().into_owned(),
+            )
+        };
 
-            if (*(*format_ctx).oformat).flags & sys::AVFMT_NOFILE == 0 {
-                if sys::avio_open(
-                    &mut (*format_ctx).pb,
-                    c_output_path.as_ptr(),
-                    sys::AVIO_FLAG_WRITE,
-                ) < 0
-                {
-                    sys::avformat_free_context(format_ctx);
-                    return Err("Failed to open output file".to_string());
-                }
+        let mut output = OutputFile::create(output_path, overwrite)?;
+        let mut output_index = vec![None; streams.len()];
+        for (index, codecpar) in streams.iter().enumerate() {
+            if let Some(codecpar) = codecpar {
+                output_index[index] = Some(output.add_stream(*codecpar)?);
             }
-
-            let mut opts: *mut sys::AVDictionary = ptr::null_mut();
-            sys::av_dict_set(
-                &mut opts,
-                CString::new("movflags").unwrap().as_ptr(),
-                CString::new("faststart").unwrap().as_ptr(),
-                0,
-            );
-
-            if sys::avformat_write_header(format_ctx, &mut opts) < 0 {
-                sys::avformat_free_context(format_ctx);
-                return Err("Failed to write header".to_string());
+        }
+        output.write_header()?;
+
+        // Each stream is rebased on its own first packet. Shifting by the
+        // first dts keeps dts >= 0 while pts stays ahead of it for reordered
+        // frames; streams that start later than the video keep that lead.
+        let mut offsets: Vec<Option<i64>> = vec![None; streams.len()];
+        for packet in &packets_to_save {
+            let offset = &mut offsets[packet.stream];
+            if offset.is_none() {
+                let lead = packet.timestamp.duration_since(clip_start);
+                let lead = unsafe {
+                    sys::av_rescale_q(
+                        lead.as_micros() as i64,
+                        MICROSECONDS,
+                        packet.timing.time_base,
+                    )
+                };
+                *offset = Some(packet.timing.dts - lead);
             }
+        }
 
-            let source_time_base = sys::AVRational {
-                num: 1,
-                den: fps as i32,
-            };
-            let mut pts_count: i64 = 0;
-
+        unsafe {
             for packet_to_save in &packets_to_save {
+                let stream_index = output_index[packet_to_save.stream].unwrap();
+                let stream_time_base = output.time_base(stream_index);
+                let offset = offsets[packet_to_save.stream].unwrap();
+
                 let mut av_packet = sys::av_packet_alloc();
                 if av_packet.is_null() {
                     continue;
@@ -201,35 +363,216 @@ impl ReplayBuffer {
                 } else {
                     0
                 };
-                (*av_packet).stream_index = (*stream).index;
-
-                (*av_packet).pts = pts_count;
-                (*av_packet).dts = pts_count;
-                (*av_packet).duration = 1;
+                (*av_packet).stream_index = stream_index;
 
-                pts_count += 1;
+                let timing = packet_to_save.timing;
+                (*av_packet).pts = timing.pts - offset;
+                (*av_packet).dts = timing.dts - offset;
+                (*av_packet).duration = timing.duration;
 
                 sys::av_packet_rescale_ts(
                     av_packet,
-                    source_time_base,    // From
-                    (*stream).time_base, // To
+                    timing.time_base, // From
+                    stream_time_base, // To
                 );
 
-                if sys::av_interleaved_write_frame(format_ctx, av_packet) < 0 {
+                if !output.write_packet(av_packet) {
                     warn!("[storage] Failed to write a packet during save.");
                 }
 
                 sys::av_packet_free(&mut av_packet);
             }
+        }
+        let failed_writes = output.failed_writes();
+        // Claimed until the clip has been verified and, if need be, moved
+        // aside.
+        let (path, _in_use) = output.finish()?;
+
+        // A clip that does not read back must not pass for a good one.
+        let verification = verify::verify(&path, frame_count, duration, failed_writes)
+            .map_err(|e| format!("{} ({})", e, quarantine(&path)))?;
+        let problems = verification.problems();
+        if !problems.is_empty() {
+            return Err(format!(
+                "Clip at {} failed verification: {} ({})",
+                path.display(),
+                problems.join("; "),
+                quarantine(&path)
+            ));
+        }
+
+        // Players fall back to guessing when tags are lost, which shifts
+        // colors but leaves the clip usable.
+        let written_color = unsafe { ColorTags::of(codecpar) };
+        if verification.color != written_color {
+            warn!(
+                "[storage] {} is tagged {:?}, expected {:?}",
+                path.display(),
+                verification.color,
+                written_color
+            );
+        }
+
+        let size_bytes = std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
 
-            sys::av_write_trailer(format_ctx);
-            if (*(*format_ctx).oformat).flags & sys::AVFMT_NOFILE == 0 {
-                sys::avio_closep(&mut (*format_ctx).pb);
+        info!(
+            "[storage] Saved and verified replay at {} ({} frames, {:?})",
+            path.display(),
+            verification.probed_frames,
+            verification.probed_duration
+        );
+        let clip_end = packets_to_save.last().unwrap().timestamp;
+        Ok(SavedClip {
+            path,
+            start: clip_start,
+            end: clip_end,
+            start_unix_ms: wall_clock.unix_ms(clip_start),
+            end_unix_ms: wall_clock.unix_ms(clip_end),
+            missing_pre_roll,
+            duration,
+            packet_count: packets_to_save.len(),
+            size_bytes,
+            width,
+            height,
+            codec,
+            encoder: encoder.to_string(),
+            verification,
+        })
+    }
+}
+
+/// Moves a clip that failed verification to `<name>.failed`, or deletes it
+/// if that is not possible, and says what happened to it.
+fn quarantine(path: &Path) -> String {
+    let mut failed = path.as_os_str().to_owned();
+    failed.push(".failed");
+    let failed = PathBuf::from(failed);
+    match std::fs::rename(path, &failed) {
+        Ok(()) => format!("kept as {}", failed.display()),
+        Err(rename_error) => match std::fs::remove_file(path) {
+            Ok(()) => "deleted".to_string(),
+            Err(e) => {
+                warn!(
+                    "[storage] Failed to move {} aside ({}) or delete it ({})",
+                    path.display(),
+                    rename_error,
+                    e
+                );
+                format!("left at {}", path.display())
             }
-            sys::avformat_free_context(format_ctx);
+        },
+    }
+}
+
+/// Drops video packets at either end of a saved range that would decode or
+/// display wrongly once the neighbouring packets are gone.
+///
+/// At the start, frames that follow the opening keyframe in decode order but
+/// display before it belong to the previous GOP. At the end, packets are
+/// removed from the back until every packet left over displays after
+/// everything kept, so playback has no holes. Removing from the back never
+/// takes away a frame that a kept packet references.
+fn trim_reordered_edges(packets: &mut Vec<TimestampedPacket>, following_pts: Option<i64>) {
+    let key_pts = packets[0].timing.pts;
+    let mut index = 0;
+    packets.retain(|p| {
+        index += 1;
+        p.stream != VIDEO_STREAM || index == 1 || p.timing.pts >= key_pts
+    });
+
+    let Some(mut next_pts) = following_pts else {
+        return;
+    };
+    while let Some(last) = packets.iter().rposition(|p| p.stream == VIDEO_STREAM) {
+        if last == 0 {
+            break;
+        }
+        let kept_max = packets
+            .iter()
+            .filter(|p| p.stream == VIDEO_STREAM)
+            .map(|p| p.timing.pts)
+            .max()
+            .unwrap();
+        if kept_max < next_pts {
+            break;
         }
+        let dropped = packets.remove(last);
+        next_pts = next_pts.min(dropped.timing.pts);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(stream: usize, pts: i64, dts: i64) -> TimestampedPacket {
+        TimestampedPacket {
+            stream,
+            data: Vec::new(),
+            timestamp: Instant::now(),
+            is_keyframe: false,
+            timing: PacketTiming {
+                pts,
+                dts,
+                duration: 1,
+                time_base: sys::AVRational { num: 1, den: 30 },
+            },
+        }
+    }
+
+    fn video_pts(packets: &[TimestampedPacket]) -> Vec<i64> {
+        packets
+            .iter()
+            .filter(|p| p.stream == VIDEO_STREAM)
+            .map(|p| p.timing.pts)
+            .collect()
+    }
+
+    /// Decode order of `I3 B1 B2 P6 B4 B5` opening on the keyframe, with
+    /// an audio packet in between.
+    fn open_gop() -> Vec<TimestampedPacket> {
+        let mut keyframe = packet(VIDEO_STREAM, 3, 0);
+        keyframe.is_keyframe = true;
+        vec![
+            keyframe,
+            packet(VIDEO_STREAM, 1, 1),
+            packet(1, 0, 0),
+            packet(VIDEO_STREAM, 2, 2),
+            packet(VIDEO_STREAM, 6, 3),
+            packet(VIDEO_STREAM, 4, 4),
+            packet(VIDEO_STREAM, 5, 5),
+        ]
+    }
+
+    #[test]
+    fn drops_frames_displayed_before_the_opening_keyframe() {
+        let mut packets = open_gop();
+        trim_reordered_edges(&mut packets, None);
+        assert_eq!(video_pts(&packets), vec![3, 6, 4, 5]);
+        assert_eq!(packets.iter().filter(|p| p.stream == 1).count(), 1);
+    }
+
+    #[test]
+    fn keeps_the_end_when_the_next_frame_displays_later() {
+        let mut packets = open_gop();
+        trim_reordered_edges(&mut packets, Some(9));
+        assert_eq!(video_pts(&packets), vec![3, 6, 4, 5]);
+    }
+
+    #[test]
+    fn drops_trailing_frames_that_would_leave_a_hole() {
+        // Cut before B5: the kept P6 would display after the missing 5.
+        let mut packets = open_gop();
+        packets.pop();
+        trim_reordered_edges(&mut packets, Some(5));
+        assert_eq!(video_pts(&packets), vec![3]);
+    }
 
-        info!("[storage] Successfully saved replay to {}", output_path);
-        Ok(())
+    #[test]
+    fn never_drops_the_opening_keyframe() {
+        let mut packets = open_gop();
+        packets.truncate(1);
+        trim_reordered_edges(&mut packets, Some(0));
+        assert_eq!(video_pts(&packets), vec![3]);
     }
 }
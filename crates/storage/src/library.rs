@@ -0,0 +1,429 @@
+use common::log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::SavedClip;
+
+const INDEX_DIR: &str = ".mebal";
+const INDEX_FILE: &str = "library.json";
+const THUMBNAIL_DIR: &str = "thumbnails";
+
+/// Tag marking a clip the user wants to keep regardless of retention limits.
+pub const STARRED_TAG: &str = "starred";
+
+/// A point of interest inside a clip, relative to its first frame.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ClipMarker {
+    pub offset_ms: u64,
+    pub label: String,
+}
+
+/// One saved clip as recorded in the library index.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ClipEntry {
+    pub id: u64,
+    pub path: PathBuf,
+    /// Wall-clock capture range, in milliseconds since the Unix epoch.
+    pub start_unix_ms: u64,
+    pub end_unix_ms: u64,
+    pub duration_ms: u64,
+    pub size_bytes: u64,
+    pub width: u32,
+    pub height: u32,
+    /// FFmpeg encoder the clip was recorded with, e.g. `h264_nvenc`.
+    pub encoder: String,
+    #[serde(default)]
+    pub markers: Vec<ClipMarker>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub thumbnail: Option<PathBuf>,
+}
+
+impl ClipEntry {
+    pub fn is_starred(&self) -> bool {
+        self.tags.iter().any(|t| t == STARRED_TAG)
+    }
+}
+
+/// Filter for [`ClipLibrary::search`]. Unset fields match everything.
+#[derive(Clone, Debug, Default)]
+pub struct ClipQuery {
+    /// Case-insensitive substring matched against the path, tags and marker labels.
+    pub text: Option<String>,
+    /// Every tag listed here must be present on the clip.
+    pub tags: Vec<String>,
+    pub since_unix_ms: Option<u64>,
+    pub until_unix_ms: Option<u64>,
+    pub min_duration_ms: Option<u64>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct IndexFile {
+    next_id: u64,
+    clips: Vec<ClipEntry>,
+}
+
+/// JSON-backed index of saved clips, shared by the GUI and command-line tools.
+///
+/// The index lives in `<root>/.mebal/library.json` and is rewritten atomically
+/// after every change.
+pub struct ClipLibrary {
+    root: PathBuf,
+    index: Mutex<IndexFile>,
+}
+
+impl ClipLibrary {
+    /// Opens the library rooted at `root`, creating an empty index if none exists.
+    pub fn open(root: &Path) -> Result<Self, String> {
+        let index_path = root.join(INDEX_DIR).join(INDEX_FILE);
+        let index = match fs::read_to_string(&index_path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|e| format!("Corrupt library index {}: {}", index_path.display(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => IndexFile {
+                next_id: 1,
+                clips: Vec::new(),
+            },
+            Err(e) => {
+                return Err(format!(
+                    "Failed to read library index {}: {}",
+                    index_path.display(),
+                    e
+                ));
+            }
+        };
+
+        info!(
+            "[storage] Opened clip library at {} ({} clips)",
+            index_path.display(),
+            index.clips.len()
+        );
+        Ok(Self {
+            root: root.to_path_buf(),
+            index: Mutex::new(index),
+        })
+    }
+
+    /// Directory the library indexes clips in.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Directory where thumbnails for this library are kept.
+    pub fn thumbnail_dir(&self) -> PathBuf {
+        self.root.join(INDEX_DIR).join(THUMBNAIL_DIR)
+    }
+
+    /// Records a freshly saved clip and returns its library entry.
+    pub fn add(&self, clip: &SavedClip, thumbnail: Option<PathBuf>) -> Result<ClipEntry, String> {
+        let mut index = self.index.lock().unwrap();
+        let entry = ClipEntry {
+            id: index.next_id,
+            path: clip.path.clone(),
+            start_unix_ms: clip.start_unix_ms,
+            end_unix_ms: clip.end_unix_ms,
+            duration_ms: clip.duration.as_millis() as u64,
+            size_bytes: clip.size_bytes,
+            width: clip.width,
+            height: clip.height,
+            encoder: clip.encoder.clone(),
+            markers: Vec::new(),
+            tags: Vec::new(),
+            thumbnail,
+        };
+
+        // Saving over an existing file replaces its old entry.
+        index.clips.retain(|c| c.path != entry.path);
+        index.clips.push(entry.clone());
+        index.next_id += 1;
+        self.persist(&index)?;
+        Ok(entry)
+    }
+
+    pub fn get(&self, id: u64) -> Option<ClipEntry> {
+        let index = self.index.lock().unwrap();
+        index.clips.iter().find(|c| c.id == id).cloned()
+    }
+
+    pub fn find_by_path(&self, path: &Path) -> Option<ClipEntry> {
+        let index = self.index.lock().unwrap();
+        let canonical = path.canonicalize().ok();
+        index
+            .clips
+            .iter()
+            .find(|c| {
+                c.path == path || (canonical.is_some() && c.path.canonicalize().ok() == canonical)
+            })
+            .cloned()
+    }
+
+    /// Returns every clip, newest first.
+    pub fn list(&self) -> Vec<ClipEntry> {
+        self.search(&ClipQuery::default())
+    }
+
+    /// Returns the clips matching `query`, newest first.
+    pub fn search(&self, query: &ClipQuery) -> Vec<ClipEntry> {
+        let index = self.index.lock().unwrap();
+        let text = query.text.as_ref().map(|t| t.to_lowercase());
+
+        let mut results: Vec<ClipEntry> = index
+            .clips
+            .iter()
+            .filter(|c| query.tags.iter().all(|t| c.tags.contains(t)))
+            .filter(|c| query.since_unix_ms.is_none_or(|t| c.end_unix_ms >= t))
+            .filter(|c| query.until_unix_ms.is_none_or(|t| c.start_unix_ms <= t))
+            .filter(|c| query.min_duration_ms.is_none_or(|d| c.duration_ms >= d))
+            .filter(|c| match &text {
+                Some(text) => {
+                    c.path.to_string_lossy().to_lowercase().contains(text)
+                        || c.tags.iter().any(|t| t.to_lowercase().contains(text))
+                        || c.markers
+                            .iter()
+                            .any(|m| m.label.to_lowercase().contains(text))
+                }
+                None => true,
+            })
+            .cloned()
+            .collect();
+
+        results.sort_by(|a, b| b.start_unix_ms.cmp(&a.start_unix_ms));
+        results
+    }
+
+    pub fn add_tag(&self, id: u64, tag: &str) -> Result<ClipEntry, String> {
+        self.update(id, |clip| {
+            if !clip.tags.iter().any(|t| t == tag) {
+                clip.tags.push(tag.to_string());
+            }
+        })
+    }
+
+    pub fn remove_tag(&self, id: u64, tag: &str) -> Result<ClipEntry, String> {
+        self.update(id, |clip| clip.tags.retain(|t| t != tag))
+    }
+
+    pub fn set_starred(&self, id: u64, starred: bool) -> Result<ClipEntry, String> {
+        if starred {
+            self.add_tag(id, STARRED_TAG)
+        } else {
+            self.remove_tag(id, STARRED_TAG)
+        }
+    }
+
+    pub fn add_marker(&self, id: u64, offset_ms: u64, label: &str) -> Result<ClipEntry, String> {
+        self.update(id, |clip| {
+            clip.markers.push(ClipMarker {
+                offset_ms,
+                label: label.to_string(),
+            });
+            clip.markers.sort_by_key(|m| m.offset_ms);
+        })
+    }
+
+    /// Removes a clip from the index. With `delete_files`, the clip and its
+    /// thumbnail are removed from disk as well.
+    pub fn delete(&self, id: u64, delete_files: bool) -> Result<ClipEntry, String> {
+        let mut index = self.index.lock().unwrap();
+        let position = index
+            .clips
+            .iter()
+            .position(|c| c.id == id)
+            .ok_or_else(|| format!("No clip with id {}", id))?;
+
+        if delete_files {
+            let clip = &index.clips[position];
+            if let Err(e) = fs::remove_file(&clip.path) {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    return Err(format!("Failed to delete {}: {}", clip.path.display(), e));
+                }
+            }
+            if let Some(thumbnail) = &clip.thumbnail {
+                if let Err(e) = fs::remove_file(thumbnail) {
+                    warn!(
+                        "[storage] Failed to delete thumbnail {}: {}",
+                        thumbnail.display(),
+                        e
+                    );
+                }
+            }
+        }
+
+        let removed = index.clips.remove(position);
+        self.persist(&index)?;
+        Ok(removed)
+    }
+
+    fn update(&self, id: u64, f: impl FnOnce(&mut ClipEntry)) -> Result<ClipEntry, String> {
+        let mut index = self.index.lock().unwrap();
+        let clip = index
+            .clips
+            .iter_mut()
+            .find(|c| c.id == id)
+            .ok_or_else(|| format!("No clip with id {}", id))?;
+        f(clip);
+        let updated = clip.clone();
+        self.persist(&index)?;
+        Ok(updated)
+    }
+
+    fn persist(&self, index: &IndexFile) -> Result<(), String> {
+        let dir = self.root.join(INDEX_DIR);
+        fs::create_dir_all(&dir)
+            .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+
+        let json = serde_json::to_string_pretty(index)
+            .map_err(|e| format!("Failed to serialize library index: {}", e))?;
+
+        let tmp_path = dir.join(format!("{}.tmp", INDEX_FILE));
+        fs::write(&tmp_path, json)
+            .map_err(|e| format!("Failed to write {}: {}", tmp_path.display(), e))?;
+        fs::rename(&tmp_path, dir.join(INDEX_FILE))
+            .map_err(|e| format!("Failed to replace library index: {}", e))
+    }
+}
+
+/// Converts a capture-time `Instant` into milliseconds since the Unix epoch.
+pub fn instant_to_unix_ms(instant: Instant) -> u64 {
+    WallClock::now().unix_ms(instant)
+}
+
+/// The wall clock read once, so capture times converted against it keep
+/// their spacing even if the system clock is stepped in between.
+#[derive(Clone, Copy, Debug)]
+pub struct WallClock {
+    instant: Instant,
+    wall: SystemTime,
+}
+
+impl WallClock {
+    pub fn now() -> Self {
+        Self {
+            instant: Instant::now(),
+            wall: SystemTime::now(),
+        }
+    }
+
+    /// Milliseconds since the Unix epoch at capture time `instant`.
+    pub fn unix_ms(&self, instant: Instant) -> u64 {
+        let wall = if instant <= self.instant {
+            self.wall - (self.instant - instant)
+        } else {
+            self.wall + (instant - self.instant)
+        };
+        wall.duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// An empty directory of its own under the system temp directory.
+    pub(crate) fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mebal-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    pub(crate) fn entry(id: u64, path: PathBuf, start_unix_ms: u64) -> ClipEntry {
+        ClipEntry {
+            id,
+            path,
+            start_unix_ms,
+            end_unix_ms: start_unix_ms + 10_000,
+            duration_ms: 10_000,
+            size_bytes: 0,
+            width: 1920,
+            height: 1080,
+            encoder: "libx264".to_string(),
+            markers: Vec::new(),
+            tags: Vec::new(),
+            thumbnail: None,
+        }
+    }
+
+    /// Opens a library at `root` whose index holds `clips`.
+    pub(crate) fn library_with(root: &Path, clips: Vec<ClipEntry>) -> ClipLibrary {
+        let library = ClipLibrary::open(root).unwrap();
+        {
+            let mut index = library.index.lock().unwrap();
+            index.next_id = clips.iter().map(|c| c.id).max().unwrap_or(0) + 1;
+            index.clips = clips;
+            library.persist(&index).unwrap();
+        }
+        ClipLibrary::open(root).unwrap()
+    }
+
+    fn ids(clips: Vec<ClipEntry>) -> Vec<u64> {
+        clips.into_iter().map(|c| c.id).collect()
+    }
+
+    #[test]
+    fn search_filters_and_sorts_newest_first() {
+        let root = temp_dir("library-search");
+        let mut tagged = entry(1, root.join("raid.mp4"), 1_000);
+        tagged.tags = vec!["boss".to_string(), STARRED_TAG.to_string()];
+        let mut marked = entry(2, root.join("match.mp4"), 3_000);
+        marked.markers.push(ClipMarker {
+            offset_ms: 500,
+            label: "Triple Kill".to_string(),
+        });
+        let mut long = entry(3, root.join("Stream.mkv"), 2_000);
+        long.duration_ms = 60_000;
+        let library = library_with(&root, vec![tagged, marked, long]);
+
+        assert_eq!(ids(library.list()), vec![2, 3, 1]);
+
+        let text = |text: &str| ClipQuery {
+            text: Some(text.to_string()),
+            ..Default::default()
+        };
+        assert_eq!(ids(library.search(&text("stream"))), vec![3]);
+        assert_eq!(ids(library.search(&text("BOSS"))), vec![1]);
+        assert_eq!(ids(library.search(&text("triple"))), vec![2]);
+        assert!(library.search(&text("nothing")).is_empty());
+
+        let tags = ClipQuery {
+            tags: vec!["boss".to_string(), STARRED_TAG.to_string()],
+            ..Default::default()
+        };
+        assert_eq!(ids(library.search(&tags)), vec![1]);
+        let tags = ClipQuery {
+            tags: vec!["boss".to_string(), "other".to_string()],
+            ..Default::default()
+        };
+        assert!(library.search(&tags).is_empty());
+
+        let range = ClipQuery {
+            since_unix_ms: Some(11_500),
+            until_unix_ms: Some(2_500),
+            ..Default::default()
+        };
+        assert_eq!(ids(library.search(&range)), vec![3]);
+
+        let long_only = ClipQuery {
+            min_duration_ms: Some(30_000),
+            ..Default::default()
+        };
+        assert_eq!(ids(library.search(&long_only)), vec![3]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn wall_clock_keeps_capture_spacing() {
+        let clock = WallClock::now();
+        let start = Instant::now();
+        let end = start + std::time::Duration::from_millis(12_345);
+        assert_eq!(clock.unix_ms(end) - clock.unix_ms(start), 12_345);
+        let earlier = start - std::time::Duration::from_secs(1);
+        assert_eq!(clock.unix_ms(start) - clock.unix_ms(earlier), 1_000);
+    }
+}
@@ -0,0 +1,361 @@
+use common::sys;
+use std::ffi::CString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::in_use::{self, InUseGuard};
+
+/// An opened media file with its stream info probed. Closed on drop.
+pub struct InputFile {
+    pub(crate) ctx: *mut sys::AVFormatContext,
+    path: String,
+}
+
+impl InputFile {
+    pub fn open(path: &str) -> Result<Self, String> {
+        let c_path = CString::new(path).map_err(|_| "Invalid input path".to_string())?;
+        let mut ctx: *mut sys::AVFormatContext = ptr::null_mut();
+
+        unsafe {
+            if sys::avformat_open_input(&mut ctx, c_path.as_ptr(), ptr::null(), ptr::null_mut()) < 0
+            {
+                return Err(format!("Failed to open {}", path));
+            }
+            let input = Self {
+                ctx,
+                path: path.to_string(),
+            };
+            if sys::avformat_find_stream_info(input.ctx, ptr::null_mut()) < 0 {
+                return Err(format!("Failed to find stream info in {}", path));
+            }
+            Ok(input)
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn stream_count(&self) -> usize {
+        unsafe { (*self.ctx).nb_streams as usize }
+    }
+
+    pub fn stream(&self, index: usize) -> *mut sys::AVStream {
+        unsafe { *(*self.ctx).streams.add(index) }
+    }
+
+    pub fn codecpar(&self, index: usize) -> *mut sys::AVCodecParameters {
+        unsafe { (*self.stream(index)).codecpar }
+    }
+
+    pub fn time_base(&self, index: usize) -> sys::AVRational {
+        unsafe { (*self.stream(index)).time_base }
+    }
+
+    /// Index of the first video stream, if any.
+    pub fn video_stream(&self) -> Option<usize> {
+        (0..self.stream_count()).find(|&i| unsafe {
+            (*self.codecpar(i)).codec_type == sys::AVMediaType::AVMEDIA_TYPE_VIDEO
+        })
+    }
+
+    /// Reads the next packet into `packet`. Returns false at end of file.
+    pub fn read_packet(&mut self, packet: *mut sys::AVPacket) -> bool {
+        unsafe { sys::av_read_frame(self.ctx, packet) >= 0 }
+    }
+}
+
+impl Drop for InputFile {
+    fn drop(&mut self) {
+        unsafe {
+            sys::avformat_close_input(&mut self.ctx);
+        }
+    }
+}
+
+/// Owned `AVPacket`, freed on drop.
+pub(crate) struct Packet(pub(crate) *mut sys::AVPacket);
+
+impl Packet {
+    pub(crate) fn new() -> Result<Self, String> {
+        let packet = unsafe { sys::av_packet_alloc() };
+        if packet.is_null() {
+            return Err("Failed to allocate packet".to_string());
+        }
+        Ok(Self(packet))
+    }
+}
+
+impl Drop for Packet {
+    fn drop(&mut self) {
+        unsafe { sys::av_packet_free(&mut self.0) };
+    }
+}
+
+/// What to do when a clip's final path already exists.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverwritePolicy {
+    /// Replace the existing file.
+    Overwrite,
+    /// Keep the existing file and save as `name-1.ext`, `name-2.ext`, ...
+    #[default]
+    UniqueName,
+    /// Keep the existing file and fail the save.
+    Fail,
+}
+
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A file being muxed. Streams are added first, then the header is written,
+/// packets are written and [`OutputFile::finish`] writes the trailer.
+///
+/// Data goes to a hidden temporary file next to the final path, which is
+/// synced and renamed into place only once the trailer has been written. An
+/// unfinished file is removed on drop, so a failed save never leaves a
+/// truncated clip behind. Both paths are claimed through [`in_use`] from
+/// creation on, so retention cannot delete either while the file is written.
+pub struct OutputFile {
+    ctx: *mut sys::AVFormatContext,
+    path: PathBuf,
+    temp_path: PathBuf,
+    policy: OverwritePolicy,
+    failed_writes: usize,
+    _in_use: [InUseGuard; 2],
+}
+
+impl OutputFile {
+    pub fn create(path: &str, policy: OverwritePolicy) -> Result<Self, String> {
+        let final_path = PathBuf::from(path);
+        if policy == OverwritePolicy::Fail && final_path.exists() {
+            return Err(format!("{} already exists", path));
+        }
+
+        let file_name = final_path
+            .file_name()
+            .ok_or_else(|| "Output path has no file name".to_string())?
+            .to_string_lossy()
+            .into_owned();
+        let temp_path = final_path.with_file_name(format!(
+            ".{}.{}-{}.part",
+            file_name,
+            std::process::id(),
+            TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        // The container is picked from the final name; only the bytes go to
+        // the temporary file.
+        let c_path = CString::new(path).map_err(|_| "Invalid output path".to_string())?;
+        let c_temp_path = CString::new(temp_path.to_string_lossy().as_bytes())
+            .map_err(|_| "Invalid output path".to_string())?;
+        let mut ctx: *mut sys::AVFormatContext = ptr::null_mut();
+
+        unsafe {
+            sys::avformat_alloc_output_context2(
+                &mut ctx,
+                ptr::null_mut(),
+                ptr::null(),
+                c_path.as_ptr(),
+            );
+            if ctx.is_null() {
+                return Err("Failed to allocate output context".to_string());
+            }
+            if (*(*ctx).oformat).flags & sys::AVFMT_NOFILE != 0 {
+                sys::avformat_free_context(ctx);
+                return Err(format!("Unsupported output format for {}", path));
+            }
+            // The mp4 faststart pass reopens the file by its URL.
+            sys::av_free((*ctx).url as *mut std::ffi::c_void);
+            (*ctx).url = sys::av_strdup(c_temp_path.as_ptr());
+
+            let output = Self {
+                ctx,
+                _in_use: [in_use::claim(&temp_path), in_use::claim(&final_path)],
+                path: final_path,
+                temp_path,
+                policy,
+                failed_writes: 0,
+            };
+
+            if sys::avio_open(&mut (*ctx).pb, c_temp_path.as_ptr(), sys::AVIO_FLAG_WRITE) < 0 {
+                return Err("Failed to open output file".to_string());
+            }
+            Ok(output)
+        }
+    }
+
+    /// The path the clip is meant to end up at. With
+    /// [`OverwritePolicy::UniqueName`] the actual path is returned by
+    /// [`OutputFile::finish`].
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Adds a stream with a copy of `codecpar` and returns its index.
+    pub fn add_stream(&mut self, codecpar: *const sys::AVCodecParameters) -> Result<i32, String> {
+        unsafe {
+            let stream = sys::avformat_new_stream(self.ctx, ptr::null());
+            if stream.is_null() {
+                return Err("Failed to create new stream".to_string());
+            }
+            if sys::avcodec_parameters_copy((*stream).codecpar, codecpar) < 0 {
+                return Err("Failed to copy codec parameters".to_string());
+            }
+            // Let the muxer pick its own tag for the target container.
+            (*(*stream).codecpar).codec_tag = 0;
+            Ok((*stream).index)
+        }
+    }
+
+    pub fn write_header(&mut self) -> Result<(), String> {
+        unsafe {
+            let mut opts: *mut sys::AVDictionary = ptr::null_mut();
+            sys::av_dict_set(
+                &mut opts,
+                CString::new("movflags").unwrap().as_ptr(),
+                CString::new("faststart").unwrap().as_ptr(),
+                0,
+            );
+            let ret = sys::avformat_write_header(self.ctx, &mut opts);
+            sys::av_dict_free(&mut opts);
+            if ret < 0 {
+                return Err("Failed to write header".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// Time base chosen by the muxer for `index`. Only final after
+    /// [`OutputFile::write_header`].
+    pub fn time_base(&self, index: i32) -> sys::AVRational {
+        unsafe { (*(*(*self.ctx).streams.add(index as usize))).time_base }
+    }
+
+    /// Writes a packet whose timestamps are already in the output stream's
+    /// time base. Failures are counted rather than aborting the file.
+    pub fn write_packet(&mut self, packet: *mut sys::AVPacket) -> bool {
+        let ok = unsafe { sys::av_interleaved_write_frame(self.ctx, packet) >= 0 };
+        if !ok {
+            self.failed_writes += 1;
+        }
+        ok
+    }
+
+    pub fn failed_writes(&self) -> usize {
+        self.failed_writes
+    }
+
+    /// Writes the trailer, syncs the data to disk and moves the file to its
+    /// final path. Returns the path the clip was saved at, with a claim on it
+    /// that was taken before the file got there.
+    pub fn finish(mut self) -> Result<(PathBuf, InUseGuard), String> {
+        unsafe {
+            if sys::av_write_trailer(self.ctx) < 0 {
+                return Err("Failed to write trailer".to_string());
+            }
+            self.close_io();
+        }
+
+        fs::File::open(&self.temp_path)
+            .and_then(|f| f.sync_all())
+            .map_err(|e| format!("Failed to sync {}: {}", self.temp_path.display(), e))?;
+
+        let (final_path, guard) = self.move_into_place()?;
+
+        // Make the rename itself durable.
+        if let Some(dir) = final_path.parent() {
+            if let Ok(dir) = fs::File::open(Path::new(".").join(dir)) {
+                let _ = dir.sync_all();
+            }
+        }
+        Ok((final_path, guard))
+    }
+
+    fn move_into_place(&self) -> Result<(PathBuf, InUseGuard), String> {
+        match self.policy {
+            OverwritePolicy::Overwrite => {
+                let guard = in_use::claim(&self.path);
+                fs::rename(&self.temp_path, &self.path).map_err(|e| {
+                    format!("Failed to move clip to {}: {}", self.path.display(), e)
+                })?;
+                Ok((self.path.clone(), guard))
+            }
+            OverwritePolicy::Fail => {
+                let guard = in_use::claim(&self.path);
+                self.rename_no_clobber(&self.path)?
+                    .ok_or_else(|| format!("{} already exists", self.path.display()))?;
+                Ok((self.path.clone(), guard))
+            }
+            OverwritePolicy::UniqueName => {
+                for n in 0u32.. {
+                    let candidate = if n == 0 {
+                        self.path.clone()
+                    } else {
+                        numbered_path(&self.path, n)
+                    };
+                    // Claimed before the clip appears there.
+                    let guard = in_use::claim(&candidate);
+                    if self.rename_no_clobber(&candidate)?.is_some() {
+                        return Ok((candidate, guard));
+                    }
+                }
+                unreachable!()
+            }
+        }
+    }
+
+    /// Moves the temporary file to `target` unless something already exists
+    /// there. Returns `None` when the target is taken.
+    fn rename_no_clobber(&self, target: &Path) -> Result<Option<()>, String> {
+        // A hard link fails atomically if the target exists, unlike rename.
+        match fs::hard_link(&self.temp_path, target) {
+            Ok(()) => {
+                let _ = fs::remove_file(&self.temp_path);
+                return Ok(Some(()));
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => return Ok(None),
+            Err(_) => {}
+        }
+
+        // Filesystems without hard links fall back to check-then-rename.
+        if target.exists() {
+            return Ok(None);
+        }
+        fs::rename(&self.temp_path, target)
+            .map(Some)
+            .map_err(|e| format!("Failed to move clip to {}: {}", target.display(), e))
+    }
+
+    unsafe fn close_io(&mut self) {
+        unsafe {
+            if !(*self.ctx).pb.is_null() {
+                sys::avio_closep(&mut (*self.ctx).pb);
+            }
+        }
+    }
+}
+
+impl Drop for OutputFile {
+    fn drop(&mut self) {
+        unsafe {
+            self.close_io();
+            sys::avformat_free_context(self.ctx);
+        }
+        // Only left behind when the clip was never finished.
+        let _ = fs::remove_file(&self.temp_path);
+    }
+}
+
+/// `clip.mp4` -> `clip-3.mp4`
+fn numbered_path(path: &Path, n: u32) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let name = match path.extension() {
+        Some(ext) => format!("{}-{}.{}", stem, n, ext.to_string_lossy()),
+        None => format!("{}-{}", stem, n),
+    };
+    path.with_file_name(name)
+}
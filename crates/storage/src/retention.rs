@@ -0,0 +1,374 @@
+use common::log::{error, info, warn};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+
+use crate::in_use;
+use crate::library::ClipLibrary;
+
+/// Limits applied to the clips directory. Unset limits are not enforced.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RetentionPolicy {
+    pub max_total_bytes: Option<u64>,
+    pub max_age: Option<Duration>,
+    /// Starred clips are never deleted and do not count towards the size limit.
+    pub keep_starred: bool,
+}
+
+impl RetentionPolicy {
+    pub fn is_unlimited(&self) -> bool {
+        self.max_total_bytes.is_none() && self.max_age.is_none()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SweepReport {
+    pub deleted: Vec<PathBuf>,
+    pub freed_bytes: u64,
+    pub remaining_bytes: u64,
+}
+
+struct ClipFile {
+    id: u64,
+    path: PathBuf,
+    size: u64,
+    modified: SystemTime,
+}
+
+/// Enforces a [`RetentionPolicy`] on the clips of a [`ClipLibrary`], oldest
+/// clips first.
+///
+/// Only files recorded in the library index are ever deleted, so videos the
+/// user put next to the clips are left alone. Files claimed through
+/// [`in_use`] are skipped, so clips that are still being written or exported
+/// survive until a later sweep.
+pub struct RetentionSweeper {
+    library: Arc<ClipLibrary>,
+    policy: RetentionPolicy,
+}
+
+impl RetentionSweeper {
+    /// Sweeps the clips indexed in `library`, refusing libraries whose root
+    /// fails [`check_root`].
+    pub fn new(library: Arc<ClipLibrary>, policy: RetentionPolicy) -> Result<Self, String> {
+        check_root(library.root())?;
+        Ok(Self { library, policy })
+    }
+
+    /// Runs [`RetentionSweeper::sweep`] every `interval` on a background thread
+    /// until the returned handle is stopped or dropped.
+    pub fn spawn(self, interval: Duration) -> SweeperHandle {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_flag = stop.clone();
+
+        let thread = std::thread::spawn(move || {
+            info!(
+                "[storage] Retention sweeper started for {} ({:?})",
+                self.library.root().display(),
+                self.policy
+            );
+            while !stop_flag.load(Ordering::Relaxed) {
+                let report = self.sweep();
+                if !report.deleted.is_empty() {
+                    info!(
+                        "[storage] Retention sweep deleted {} clips, freed {} bytes, {} bytes remain",
+                        report.deleted.len(),
+                        report.freed_bytes,
+                        report.remaining_bytes
+                    );
+                }
+
+                let mut waited = Duration::ZERO;
+                while waited < interval && !stop_flag.load(Ordering::Relaxed) {
+                    let step = Duration::from_millis(500).min(interval - waited);
+                    std::thread::sleep(step);
+                    waited += step;
+                }
+            }
+            info!("[storage] Retention sweeper stopped");
+        });
+
+        SweeperHandle {
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// Deletes expired clips, then the oldest remaining ones until the
+    /// library fits within the size limit.
+    pub fn sweep(&self) -> SweepReport {
+        let mut report = SweepReport::default();
+        if self.policy.is_unlimited() {
+            return report;
+        }
+
+        let mut clips = self.indexed_clips();
+        clips.sort_by_key(|c| c.modified);
+
+        let mut total: u64 = clips.iter().map(|c| c.size).sum();
+        let now = SystemTime::now();
+
+        for clip in &clips {
+            let expired = self.policy.max_age.is_some_and(|max_age| {
+                now.duration_since(clip.modified)
+                    .is_ok_and(|age| age > max_age)
+            });
+            let over_size = self.policy.max_total_bytes.is_some_and(|max| total > max);
+            if !expired && !over_size {
+                continue;
+            }
+
+            if in_use::is_in_use(&clip.path) {
+                info!(
+                    "[storage] Retention skipped {}: clip is in use",
+                    clip.path.display()
+                );
+                continue;
+            }
+
+            let reason = if expired {
+                "older than max age"
+            } else {
+                "over size limit"
+            };
+            match self.library.delete(clip.id, true) {
+                Ok(_) => {
+                    info!(
+                        "[storage] Retention deleted {} ({} bytes, {})",
+                        clip.path.display(),
+                        clip.size,
+                        reason
+                    );
+                    total -= clip.size;
+                    report.freed_bytes += clip.size;
+                    report.deleted.push(clip.path.clone());
+                }
+                Err(e) => error!(
+                    "[storage] Retention failed to delete {}: {}",
+                    clip.path.display(),
+                    e
+                ),
+            }
+        }
+
+        report.remaining_bytes = total;
+        report
+    }
+
+    /// Clips in the index that may be deleted: starred ones are left out
+    /// when the policy keeps them, as are entries pointing outside the
+    /// library root or at files that are gone.
+    fn indexed_clips(&self) -> Vec<ClipFile> {
+        let root = self.library.root();
+        self.library
+            .list()
+            .into_iter()
+            .filter(|entry| !(self.policy.keep_starred && entry.is_starred()))
+            .filter(|entry| {
+                let inside = entry.path.starts_with(root);
+                if !inside {
+                    warn!(
+                        "[storage] Retention ignores {}: outside {}",
+                        entry.path.display(),
+                        root.display()
+                    );
+                }
+                inside
+            })
+            .filter_map(|entry| {
+                let metadata = fs::metadata(&entry.path).ok()?;
+                Some(ClipFile {
+                    id: entry.id,
+                    path: entry.path,
+                    size: metadata.len(),
+                    modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Refuses directories that hold far more than Mebal's clips: the
+/// filesystem root, the working directory and the user's home directory.
+pub fn check_root(root: &Path) -> Result<(), String> {
+    let refuse = |what: &str| {
+        Err(format!(
+            "Refusing to apply retention to {} ({}); save clips into a dedicated directory",
+            root.display(),
+            what
+        ))
+    };
+
+    if root.as_os_str().is_empty() || root == Path::new(".") {
+        return refuse("the working directory");
+    }
+    let canonical = fs::canonicalize(root).unwrap_or_else(|_| root.to_path_buf());
+    if canonical.parent().is_none() {
+        return refuse("a filesystem root");
+    }
+    if std::env::current_dir()
+        .and_then(fs::canonicalize)
+        .is_ok_and(|cwd| cwd == canonical)
+    {
+        return refuse("the working directory");
+    }
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"));
+    if home
+        .map(PathBuf::from)
+        .map(|home| fs::canonicalize(&home).unwrap_or(home))
+        .is_some_and(|home| home == canonical)
+    {
+        return refuse("the home directory");
+    }
+    Ok(())
+}
+
+/// Stops the background sweeper when dropped.
+pub struct SweeperHandle {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl SweeperHandle {
+    pub fn stop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                warn!("[storage] Retention sweeper thread panicked");
+            }
+        }
+    }
+}
+
+impl Drop for SweeperHandle {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::library::STARRED_TAG;
+    use crate::library::tests::{entry, library_with, temp_dir};
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    /// Writes a file of `size` bytes last modified `age` ago.
+    fn write_clip(path: &Path, size: usize, age: Duration) {
+        fs::write(path, vec![0u8; size]).unwrap();
+        let file = fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(SystemTime::now() - age).unwrap();
+    }
+
+    #[test]
+    fn sweep_deletes_only_indexed_unstarred_clips() {
+        let root = temp_dir("retention-size");
+        let (old, new, starred, user) = (
+            root.join("old.mp4"),
+            root.join("new.mp4"),
+            root.join("starred.mp4"),
+            root.join("holiday.mp4"),
+        );
+        write_clip(&old, 100, 3 * DAY);
+        write_clip(&new, 100, DAY);
+        write_clip(&starred, 100, 5 * DAY);
+        // Not in the index, and older than everything else.
+        write_clip(&user, 100, 10 * DAY);
+
+        let mut starred_entry = entry(3, starred.clone(), 0);
+        starred_entry.tags.push(STARRED_TAG.to_string());
+        let library = Arc::new(library_with(
+            &root,
+            vec![
+                entry(1, old.clone(), 0),
+                entry(2, new.clone(), 0),
+                starred_entry,
+            ],
+        ));
+        let policy = RetentionPolicy {
+            max_total_bytes: Some(150),
+            max_age: None,
+            keep_starred: true,
+        };
+        let report = RetentionSweeper::new(library.clone(), policy)
+            .unwrap()
+            .sweep();
+
+        assert_eq!(report.deleted, vec![old.clone()]);
+        assert_eq!(report.freed_bytes, 100);
+        assert_eq!(report.remaining_bytes, 100);
+        assert!(!old.exists());
+        assert!(new.exists() && starred.exists() && user.exists());
+        let remaining: Vec<u64> = library.list().iter().map(|c| c.id).collect();
+        assert_eq!(remaining.len(), 2);
+        assert!(remaining.contains(&2) && remaining.contains(&3));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn sweep_deletes_expired_clips() {
+        let root = temp_dir("retention-age");
+        let (old, new, user) = (
+            root.join("old.mp4"),
+            root.join("new.mkv"),
+            root.join("old-user.mp4"),
+        );
+        write_clip(&old, 10, 3 * DAY);
+        write_clip(&new, 10, Duration::ZERO);
+        write_clip(&user, 10, 30 * DAY);
+
+        let library = Arc::new(library_with(
+            &root,
+            vec![entry(1, old.clone(), 0), entry(2, new.clone(), 0)],
+        ));
+        let policy = RetentionPolicy {
+            max_total_bytes: None,
+            max_age: Some(2 * DAY),
+            keep_starred: false,
+        };
+        let report = RetentionSweeper::new(library, policy).unwrap().sweep();
+
+        assert_eq!(report.deleted, vec![old.clone()]);
+        assert!(!old.exists());
+        assert!(new.exists() && user.exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn sweep_skips_clips_in_use() {
+        let root = temp_dir("retention-in-use");
+        let clip = root.join("busy.mp4");
+        write_clip(&clip, 10, 3 * DAY);
+        let library = Arc::new(library_with(&root, vec![entry(1, clip.clone(), 0)]));
+        let policy = RetentionPolicy {
+            max_total_bytes: Some(0),
+            max_age: None,
+            keep_starred: false,
+        };
+
+        let _in_use = in_use::claim(&clip);
+        let report = RetentionSweeper::new(library, policy).unwrap().sweep();
+        assert!(report.deleted.is_empty());
+        assert!(clip.exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn rejects_broad_roots() {
+        assert!(check_root(Path::new("/")).is_err());
+        assert!(check_root(Path::new(".")).is_err());
+        assert!(check_root(Path::new("")).is_err());
+        assert!(check_root(&std::env::current_dir().unwrap()).is_err());
+        if let Some(home) = std::env::var_os("HOME") {
+            assert!(check_root(Path::new(&home)).is_err());
+        }
+        assert!(check_root(&std::env::temp_dir().join("mebal-clips")).is_ok());
+    }
+}
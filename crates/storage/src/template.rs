@@ -0,0 +1,231 @@
+use chrono::{DateTime, Local};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use crate::library::instant_to_unix_ms;
+
+const VARIABLES: [&str; 7] = [
+    "video_dir",
+    "date",
+    "time",
+    "app",
+    "counter",
+    "ext",
+    "duration",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Literal(String),
+    Variable(String),
+}
+
+/// An output path such as `{video_dir}/{date}/{app}_{time}_{counter}.{ext}`,
+/// expanded separately for every saved clip.
+///
+/// Supported variables:
+/// - `{video_dir}`: the user's video directory
+/// - `{date}`, `{time}`: local capture start, as `2024-05-31` and `21-04-59`
+/// - `{app}`: what is being recorded
+/// - `{counter}`: number of the save within this session, starting at 1
+/// - `{ext}`: container extension
+/// - `{duration}`: requested clip length in whole seconds
+#[derive(Clone, Debug)]
+pub struct OutputTemplate {
+    parts: Vec<Part>,
+    video_dir: PathBuf,
+    app: String,
+    ext: String,
+}
+
+impl OutputTemplate {
+    /// Parses `template`, rejecting unknown variables and unbalanced braces.
+    pub fn new(template: &str, video_dir: &Path) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+
+        while let Some(open) = rest.find(['{', '}']) {
+            if rest.as_bytes()[open] == b'}' {
+                return Err(format!("Unmatched '}}' in output template: {}", template));
+            }
+            if open > 0 {
+                parts.push(Part::Literal(rest[..open].to_string()));
+            }
+            let close = rest[open..]
+                .find('}')
+                .map(|i| open + i)
+                .ok_or_else(|| format!("Unclosed '{{' in output template: {}", template))?;
+            let name = &rest[open + 1..close];
+            if !VARIABLES.contains(&name) {
+                return Err(format!(
+                    "Unknown variable {{{}}} in output template (expected one of {})",
+                    name,
+                    VARIABLES.map(|v| format!("{{{}}}", v)).join(", ")
+                ));
+            }
+            parts.push(Part::Variable(name.to_string()));
+            rest = &rest[close + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+
+        if parts.is_empty() {
+            return Err("Output template is empty".to_string());
+        }
+
+        Ok(Self {
+            parts,
+            video_dir: video_dir.to_path_buf(),
+            app: "mebal".to_string(),
+            ext: "mp4".to_string(),
+        })
+    }
+
+    pub fn with_app(mut self, app: &str) -> Self {
+        self.app = app.to_string();
+        self
+    }
+
+    pub fn with_ext(mut self, ext: &str) -> Self {
+        self.ext = ext.trim_start_matches('.').to_string();
+        self
+    }
+
+    /// Builds the path for a clip whose capture starts at `start`.
+    pub fn expand(&self, start: Instant, duration: Duration, counter: u64) -> PathBuf {
+        let wall = UNIX_EPOCH + Duration::from_millis(instant_to_unix_ms(start));
+        let local: DateTime<Local> = wall.into();
+
+        let mut path = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(text) => path.push_str(text),
+                Part::Variable(name) => path.push_str(&match name.as_str() {
+                    "video_dir" => self.video_dir.to_string_lossy().into_owned(),
+                    "date" => local.format("%Y-%m-%d").to_string(),
+                    "time" => local.format("%H-%M-%S").to_string(),
+                    "app" => sanitize(&self.app),
+                    "counter" => format!("{:03}", counter),
+                    "ext" => sanitize(&self.ext),
+                    "duration" => duration.as_secs().to_string(),
+                    _ => unreachable!("variables are checked in OutputTemplate::new"),
+                }),
+            }
+        }
+        PathBuf::from(path)
+    }
+
+    /// The deepest directory that is the same for every expansion, which is
+    /// where all clips from this template end up.
+    pub fn root_dir(&self) -> PathBuf {
+        let mut fixed = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(text) => fixed.push_str(text),
+                Part::Variable(name) if name == "video_dir" => {
+                    fixed.push_str(&self.video_dir.to_string_lossy())
+                }
+                Part::Variable(_) => break,
+            }
+        }
+
+        // Cut back to the last complete directory.
+        let dir = match fixed.rfind(['/', std::path::MAIN_SEPARATOR]) {
+            Some(end) => &fixed[..end],
+            None => "",
+        };
+        if dir.is_empty() && fixed.starts_with(['/', std::path::MAIN_SEPARATOR]) {
+            PathBuf::from(&fixed[..1])
+        } else if dir.is_empty() {
+            PathBuf::from(".")
+        } else {
+            PathBuf::from(dir)
+        }
+    }
+}
+
+/// Keeps values substituted into a file name from adding path components or
+/// characters that are invalid on Windows.
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveDate, NaiveTime};
+
+    fn template(template: &str) -> OutputTemplate {
+        OutputTemplate::new(template, Path::new("/videos")).unwrap()
+    }
+
+    #[test]
+    fn expands_variables() {
+        let path = template("{video_dir}/{app}_{counter}_{duration}s.{ext}")
+            .with_app("Game")
+            .with_ext(".mkv")
+            .expand(Instant::now(), Duration::from_millis(90_500), 7);
+        assert_eq!(path, PathBuf::from("/videos/Game_007_90s.mkv"));
+    }
+
+    #[test]
+    fn expands_date_and_time() {
+        let path = template("{date}_{time}").expand(Instant::now(), Duration::ZERO, 1);
+        let name = path.to_string_lossy();
+        let (date, time) = name.split_once('_').unwrap();
+        assert!(
+            NaiveDate::parse_from_str(date, "%Y-%m-%d").is_ok(),
+            "{}",
+            date
+        );
+        assert!(
+            NaiveTime::parse_from_str(time, "%H-%M-%S").is_ok(),
+            "{}",
+            time
+        );
+    }
+
+    #[test]
+    fn sanitizes_substituted_values() {
+        let path = template("{app}.{ext}").with_app("a/b\\c:d*?").expand(
+            Instant::now(),
+            Duration::ZERO,
+            1,
+        );
+        assert_eq!(path, PathBuf::from("a_b_c_d__.mp4"));
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        for invalid in ["", "{nope}.mp4", "{date.mp4", "date}.mp4"] {
+            assert!(
+                OutputTemplate::new(invalid, Path::new("/videos")).is_err(),
+                "{:?} was accepted",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn root_dir_is_the_fixed_prefix() {
+        let cases = [
+            ("{video_dir}/{date}/{app}.{ext}", "/videos"),
+            ("{video_dir}/Mebal {date}/{app}.{ext}", "/videos"),
+            ("/clips/fixed/{counter}.mp4", "/clips/fixed"),
+            ("/clips/{date}.mp4", "/clips"),
+            ("/{date}.mp4", "/"),
+            ("{date}.mp4", "."),
+        ];
+        for (input, root) in cases {
+            assert_eq!(template(input).root_dir(), PathBuf::from(root), "{}", input);
+        }
+    }
+}
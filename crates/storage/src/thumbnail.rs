@@ -0,0 +1,201 @@
+use common::log::info;
+use common::sys;
+use std::ffi::CString;
+use std::path::Path;
+use std::ptr;
+
+const THUMBNAIL_WIDTH: i32 = 320;
+
+/// Owns every FFmpeg object used while grabbing a thumbnail so early returns
+/// cannot leak them.
+struct ThumbnailContext {
+    fmt_ctx: *mut sys::AVFormatContext,
+    dec_ctx: *mut sys::AVCodecContext,
+    enc_ctx: *mut sys::AVCodecContext,
+    scaler_ctx: *mut sys::SwsContext,
+    packet: *mut sys::AVPacket,
+    frame: *mut sys::AVFrame,
+    scaled_frame: *mut sys::AVFrame,
+}
+
+impl Drop for ThumbnailContext {
+    fn drop(&mut self) {
+        unsafe {
+            sys::av_frame_free(&mut self.frame);
+            sys::av_frame_free(&mut self.scaled_frame);
+            sys::av_packet_free(&mut self.packet);
+            if !self.scaler_ctx.is_null() {
+                sys::sws_freeContext(self.scaler_ctx);
+            }
+            sys::avcodec_free_context(&mut self.dec_ctx);
+            sys::avcodec_free_context(&mut self.enc_ctx);
+            if !self.fmt_ctx.is_null() {
+                sys::avformat_close_input(&mut self.fmt_ctx);
+            }
+        }
+    }
+}
+
+/// Decodes the first video frame of `clip_path` and writes it to
+/// `thumbnail_path` as a JPEG, 320 pixels wide.
+pub fn generate(clip_path: &Path, thumbnail_path: &Path) -> Result<(), String> {
+    let c_input = CString::new(clip_path.to_string_lossy().as_bytes())
+        .map_err(|_| "Invalid clip path".to_string())?;
+
+    let mut ctx = ThumbnailContext {
+        fmt_ctx: ptr::null_mut(),
+        dec_ctx: ptr::null_mut(),
+        enc_ctx: ptr::null_mut(),
+        scaler_ctx: ptr::null_mut(),
+        packet: ptr::null_mut(),
+        frame: ptr::null_mut(),
+        scaled_frame: ptr::null_mut(),
+    };
+
+    let jpeg = unsafe {
+        if sys::avformat_open_input(
+            &mut ctx.fmt_ctx,
+            c_input.as_ptr(),
+            ptr::null(),
+            ptr::null_mut(),
+        ) < 0
+        {
+            return Err(format!("Failed to open {}", clip_path.display()));
+        }
+
+        if sys::avformat_find_stream_info(ctx.fmt_ctx, ptr::null_mut()) < 0 {
+            return Err("Failed to find stream info".to_string());
+        }
+
+        let stream_index = sys::av_find_best_stream(
+            ctx.fmt_ctx,
+            sys::AVMediaType::AVMEDIA_TYPE_VIDEO,
+            -1,
+            -1,
+            ptr::null_mut(),
+            0,
+        );
+        if stream_index < 0 {
+            return Err("Clip has no video stream".to_string());
+        }
+
+        let codecpar = (*(*(*ctx.fmt_ctx).streams.add(stream_index as usize))).codecpar;
+        let decoder = sys::avcodec_find_decoder((*codecpar).codec_id);
+        if decoder.is_null() {
+            return Err("No decoder for clip codec".to_string());
+        }
+        ctx.dec_ctx = sys::avcodec_alloc_context3(decoder);
+        if ctx.dec_ctx.is_null()
+            || sys::avcodec_parameters_to_context(ctx.dec_ctx, codecpar) < 0
+            || sys::avcodec_open2(ctx.dec_ctx, decoder, ptr::null_mut()) < 0
+        {
+            return Err("Failed to open decoder".to_string());
+        }
+
+        ctx.packet = sys::av_packet_alloc();
+        ctx.frame = sys::av_frame_alloc();
+        ctx.scaled_frame = sys::av_frame_alloc();
+        if ctx.packet.is_null() || ctx.frame.is_null() || ctx.scaled_frame.is_null() {
+            return Err("Failed to allocate packet or frames".to_string());
+        }
+
+        if !decode_first_frame(&ctx, stream_index) {
+            return Err("Clip contains no decodable frame".to_string());
+        }
+
+        let src_width = (*ctx.frame).width;
+        let src_height = (*ctx.frame).height;
+        let width = THUMBNAIL_WIDTH.min(src_width) & !1;
+        let height = ((src_height as i64 * width as i64 / src_width.max(1) as i64) as i32) & !1;
+
+        ctx.scaler_ctx = sys::sws_getContext(
+            src_width,
+            src_height,
+            std::mem::transmute::<i32, sys::AVPixelFormat>((*ctx.frame).format),
+            width,
+            height,
+            sys::AVPixelFormat::AV_PIX_FMT_YUVJ420P,
+            sys::SWS_BICUBIC as i32,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            ptr::null(),
+        );
+        if ctx.scaler_ctx.is_null() {
+            return Err("Failed to create thumbnail scaler".to_string());
+        }
+
+        (*ctx.scaled_frame).width = width;
+        (*ctx.scaled_frame).height = height;
+        (*ctx.scaled_frame).format = sys::AVPixelFormat::AV_PIX_FMT_YUVJ420P as i32;
+        if sys::av_frame_get_buffer(ctx.scaled_frame, 0) < 0 {
+            return Err("Failed to allocate thumbnail frame".to_string());
+        }
+
+        sys::sws_scale(
+            ctx.scaler_ctx,
+            (*ctx.frame).data.as_ptr() as *const *const u8,
+            (*ctx.frame).linesize.as_ptr(),
+            0,
+            src_height,
+            (*ctx.scaled_frame).data.as_ptr(),
+            (*ctx.scaled_frame).linesize.as_ptr(),
+        );
+
+        let encoder = sys::avcodec_find_encoder(sys::AVCodecID::AV_CODEC_ID_MJPEG);
+        if encoder.is_null() {
+            return Err("MJPEG encoder not available".to_string());
+        }
+        ctx.enc_ctx = sys::avcodec_alloc_context3(encoder);
+        if ctx.enc_ctx.is_null() {
+            return Err("Failed to allocate MJPEG encoder".to_string());
+        }
+        (*ctx.enc_ctx).width = width;
+        (*ctx.enc_ctx).height = height;
+        (*ctx.enc_ctx).pix_fmt = sys::AVPixelFormat::AV_PIX_FMT_YUVJ420P;
+        (*ctx.enc_ctx).time_base = sys::AVRational { num: 1, den: 1 };
+        if sys::avcodec_open2(ctx.enc_ctx, encoder, ptr::null_mut()) < 0 {
+            return Err("Failed to open MJPEG encoder".to_string());
+        }
+
+        (*ctx.scaled_frame).pts = 0;
+        if sys::avcodec_send_frame(ctx.enc_ctx, ctx.scaled_frame) < 0
+            || sys::avcodec_send_frame(ctx.enc_ctx, ptr::null()) < 0
+        {
+            return Err("Failed to encode thumbnail".to_string());
+        }
+
+        sys::av_packet_unref(ctx.packet);
+        if sys::avcodec_receive_packet(ctx.enc_ctx, ctx.packet) < 0 {
+            return Err("Failed to encode thumbnail".to_string());
+        }
+        std::slice::from_raw_parts((*ctx.packet).data, (*ctx.packet).size as usize).to_vec()
+    };
+
+    if let Some(parent) = thumbnail_path.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+    }
+    std::fs::write(thumbnail_path, jpeg)
+        .map_err(|e| format!("Failed to write {}: {}", thumbnail_path.display(), e))?;
+
+    info!("[storage] Wrote thumbnail {}", thumbnail_path.display());
+    Ok(())
+}
+
+/// Reads packets until the decoder yields a frame in `ctx.frame`.
+unsafe fn decode_first_frame(ctx: &ThumbnailContext, stream_index: i32) -> bool {
+    unsafe {
+        while sys::av_read_frame(ctx.fmt_ctx, ctx.packet) >= 0 {
+            let is_video = (*ctx.packet).stream_index == stream_index;
+            let sent = is_video && sys::avcodec_send_packet(ctx.dec_ctx, ctx.packet) >= 0;
+            sys::av_packet_unref(ctx.packet);
+            if sent && sys::avcodec_receive_frame(ctx.dec_ctx, ctx.frame) >= 0 {
+                return true;
+            }
+        }
+
+        // Short clips may only produce their frame once the decoder is drained.
+        sys::avcodec_send_packet(ctx.dec_ctx, ptr::null());
+        sys::avcodec_receive_frame(ctx.dec_ctx, ctx.frame) >= 0
+    }
+}
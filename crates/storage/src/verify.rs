@@ -0,0 +1,140 @@
+//! Reads a finished clip back to confirm it holds what the muxer was given.
+
+use common::sys;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::mux::{InputFile, Packet};
+
+const MICROSECONDS: sys::AVRational = sys::AVRational {
+    num: 1,
+    den: 1_000_000,
+};
+
+/// How a video stream says its pixels are to be interpreted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ColorTags {
+    pub space: sys::AVColorSpace,
+    pub range: sys::AVColorRange,
+    pub primaries: sys::AVColorPrimaries,
+    pub transfer: sys::AVColorTransferCharacteristic,
+}
+
+impl ColorTags {
+    /// # Safety
+    /// `codecpar` must point to valid codec parameters.
+    pub unsafe fn of(codecpar: *const sys::AVCodecParameters) -> Self {
+        unsafe {
+            Self {
+                space: (*codecpar).color_space,
+                range: (*codecpar).color_range,
+                primaries: (*codecpar).color_primaries,
+                transfer: (*codecpar).color_trc,
+            }
+        }
+    }
+}
+
+/// What was written to a clip compared with what probing it found.
+#[derive(Clone, Debug)]
+pub struct VerificationReport {
+    pub path: PathBuf,
+    pub expected_frames: usize,
+    pub probed_frames: usize,
+    pub expected_duration: Duration,
+    pub probed_duration: Duration,
+    /// Packets the muxer rejected while the clip was written.
+    pub failed_writes: usize,
+    /// Color tags players will see on the video stream.
+    pub color: ColorTags,
+}
+
+impl VerificationReport {
+    /// Human-readable descriptions of every mismatch. Empty when the clip is
+    /// intact.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.failed_writes > 0 {
+            problems.push(format!("{} packets failed to write", self.failed_writes));
+        }
+        if self.probed_frames != self.expected_frames {
+            problems.push(format!(
+                "found {} frames, expected {}",
+                self.probed_frames, self.expected_frames
+            ));
+        }
+        if self.probed_duration.abs_diff(self.expected_duration) > self.duration_tolerance() {
+            problems.push(format!(
+                "duration is {:?}, expected {:?}",
+                self.probed_duration, self.expected_duration
+            ));
+        }
+        problems
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.problems().is_empty()
+    }
+
+    /// Containers disagree on whether the last frame's duration counts, so
+    /// allow one frame of slack plus rounding.
+    fn duration_tolerance(&self) -> Duration {
+        let frame = self
+            .expected_duration
+            .checked_div(self.expected_frames.max(1) as u32)
+            .unwrap_or_default();
+        frame + Duration::from_millis(10)
+    }
+}
+
+/// Reopens `path`, probes its stream info and counts the video frames in it.
+///
+/// Returns an error only when the file cannot be read at all; mismatches are
+/// reported through [`VerificationReport::problems`].
+pub fn verify(
+    path: &Path,
+    expected_frames: usize,
+    expected_duration: Duration,
+    failed_writes: usize,
+) -> Result<VerificationReport, String> {
+    let mut input = InputFile::open(&path.to_string_lossy())?;
+    let video_index = input
+        .video_stream()
+        .ok_or_else(|| format!("{} has no video stream", path.display()))?;
+
+    let packet = Packet::new()?;
+    let mut probed_frames = 0usize;
+    while input.read_packet(packet.0) {
+        unsafe {
+            if (*packet.0).stream_index as usize == video_index {
+                probed_frames += 1;
+            }
+            sys::av_packet_unref(packet.0);
+        }
+    }
+
+    // Prefer the video stream's own duration; the container's also covers
+    // audio, which can run a little past the last frame.
+    let probed_duration_us = unsafe {
+        let stream = input.stream(video_index);
+        let container = (*input.ctx).duration;
+        if (*stream).duration != sys::AV_NOPTS_VALUE {
+            sys::av_rescale_q((*stream).duration, (*stream).time_base, MICROSECONDS)
+        } else if container != sys::AV_NOPTS_VALUE {
+            // AV_TIME_BASE is already microseconds.
+            container
+        } else {
+            0
+        }
+    };
+
+    Ok(VerificationReport {
+        path: path.to_path_buf(),
+        expected_frames,
+        probed_frames,
+        expected_duration,
+        probed_duration: Duration::from_micros(probed_duration_us.max(0) as u64),
+        failed_writes,
+        color: unsafe { ColorTags::of(input.codecpar(video_index)) },
+    })
+}
@@ -6,39 +6,267 @@ use dioxus::{
 pub use env_logger;
 use log::{debug, error, info, warn};
 use rdev::{listen, EventType, Key};
+use recorder::audio::{AudioCodec, AudioConfig};
+use recorder::clock::{FramePacing, FrameTimestamps};
 use recorder::create_recorder;
+use recorder::devices::{self, Devices};
+use recorder::encoder::{CodecFamily, ColorRange, ColorSpace, EncoderConfig, RateControl};
+use recorder::filter::{validate_video_filter, AudioFilters, NoiseReduction};
+use recorder::overlay::{ClockOverlay, LogoOverlay, OverlayPosition, Overlays, TextOverlay};
+use recorder::recorder::{CaptureRegion, CaptureTarget, Recorder, RecorderConfig};
+use recorder::save_scheduler::SaveScheduler;
+use recorder::scaler::{FitMode, ScaleAlgorithm};
+use recorder::stats::RecorderStats;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use storage::library::ClipLibrary;
+use storage::retention::{self, RetentionPolicy, RetentionSweeper};
+use storage::template::OutputTemplate;
+use storage::{thumbnail, OverwritePolicy, SavedClip};
 
 static CSS: Asset = asset!("/assets/main.css");
 
+/// How long a finished save range waits for an overlapping hotkey press
+/// before it is written to disk.
+const SAVE_COALESCE_WINDOW: Duration = Duration::from_secs(3);
+
+const RETENTION_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often the recorder's statistics are read for the status display.
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+const DEFAULT_OUTPUT_TEMPLATE: &str = "{video_dir}/mebal_{date}_{time}_{counter}.{ext}";
+
+/// Latest statistics of the running recorder, written by the recording
+/// thread and read by the status display.
+#[derive(Clone, Default)]
+struct LiveStats(Arc<std::sync::Mutex<Option<RecorderStats>>>);
+
 #[derive(PartialEq, Debug, Clone)]
 struct RecordingConfig {
     resolution: Signal<String>,
+    fit_mode: Signal<String>,
+    scale_algorithm: Signal<String>,
+    capture_region: Signal<String>,
+    capture_target: Signal<String>,
+    capture_window: Signal<String>,
+    display_device: Signal<String>,
     fps: Signal<String>,
+    frame_pacing: Signal<String>,
+    frame_timestamps: Signal<String>,
+    codec: Signal<String>,
+    encoders: Signal<String>,
+    color_space: Signal<String>,
+    color_range: Signal<String>,
+    rate_mode: Signal<String>,
+    quality: Signal<String>,
+    bitrate_kbps: Signal<String>,
+    max_bitrate_kbps: Signal<String>,
+    vbv_kbits: Signal<String>,
+    gop_length: Signal<String>,
+    b_frames: Signal<String>,
+    video_filter: Signal<String>,
+    overlay_text: Signal<String>,
+    overlay_text_position: Signal<String>,
+    overlay_logo: Signal<String>,
+    overlay_logo_position: Signal<String>,
+    overlay_logo_opacity: Signal<String>,
+    overlay_clock: Signal<String>,
+    overlay_font: Signal<String>,
+    system_audio: Signal<String>,
+    audio_codec: Signal<String>,
+    audio_bitrate_kbps: Signal<String>,
+    system_gain_db: Signal<String>,
+    microphone: Signal<String>,
+    mic_gain_db: Signal<String>,
+    mic_noise_reduction: Signal<String>,
+    mic_rnn_model: Signal<String>,
+    mic_compressor: Signal<bool>,
+    mic_loudnorm: Signal<bool>,
+    push_to_talk_key: Signal<String>,
     output_path: Signal<String>,
+    if_exists: Signal<String>,
     buffer_secs: Signal<String>,
+    pre_roll_secs: Signal<String>,
+    post_roll_secs: Signal<String>,
     hotkey: Signal<String>,
+    retention_max_gb: Signal<String>,
+    retention_max_days: Signal<String>,
+    keep_starred: Signal<bool>,
     listener_started: Signal<bool>,
 }
 
+/// Plain copy of the form values taken when the buffer is started.
+#[derive(Debug, Clone)]
+struct RecordingSettings {
+    resolution: String,
+    fit_mode: String,
+    scale_algorithm: String,
+    capture_region: String,
+    capture_target: String,
+    capture_window: String,
+    display_device: String,
+    fps: String,
+    frame_pacing: String,
+    frame_timestamps: String,
+    codec: String,
+    encoders: String,
+    color_space: String,
+    color_range: String,
+    rate_mode: String,
+    quality: String,
+    bitrate_kbps: String,
+    max_bitrate_kbps: String,
+    vbv_kbits: String,
+    gop_length: String,
+    b_frames: String,
+    video_filter: String,
+    overlay_text: String,
+    overlay_text_position: String,
+    overlay_logo: String,
+    overlay_logo_position: String,
+    overlay_logo_opacity: String,
+    overlay_clock: String,
+    overlay_font: String,
+    system_audio: String,
+    audio_codec: String,
+    audio_bitrate_kbps: String,
+    system_gain_db: String,
+    microphone: String,
+    mic_gain_db: String,
+    mic_noise_reduction: String,
+    mic_rnn_model: String,
+    mic_compressor: bool,
+    mic_loudnorm: bool,
+    push_to_talk_key: String,
+    output_path: String,
+    if_exists: String,
+    buffer_secs: String,
+    pre_roll_secs: String,
+    post_roll_secs: String,
+    hotkey: String,
+    retention_max_gb: String,
+    retention_max_days: String,
+    keep_starred: bool,
+}
+
 impl RecordingConfig {
     fn new() -> Self {
-        // Get user's video directory or fallback to home/Downloads
-        let video_dir = get_user_video_directory();
-        let default_output = video_dir
-            .join("mebal_recording.mp4")
-            .to_string_lossy()
-            .to_string();
-
         Self {
             resolution: Signal::new("1920x1080".to_string()),
+            fit_mode: Signal::new("stretch".to_string()),
+            scale_algorithm: Signal::new("bilinear".to_string()),
+            capture_region: Signal::new(String::new()),
+            capture_target: Signal::new("screen".to_string()),
+            capture_window: Signal::new(String::new()),
+            display_device: Signal::new(String::new()),
             fps: Signal::new("60".to_string()),
-            output_path: Signal::new(default_output),
+            frame_pacing: Signal::new("constant".to_string()),
+            frame_timestamps: Signal::new("capture_clock".to_string()),
+            codec: Signal::new("h264".to_string()),
+            encoders: Signal::new(String::new()),
+            color_space: Signal::new("bt709".to_string()),
+            color_range: Signal::new("limited".to_string()),
+            rate_mode: Signal::new("quality".to_string()),
+            quality: Signal::new(String::new()),
+            bitrate_kbps: Signal::new("8000".to_string()),
+            max_bitrate_kbps: Signal::new("12000".to_string()),
+            vbv_kbits: Signal::new(String::new()),
+            gop_length: Signal::new(String::new()),
+            b_frames: Signal::new("0".to_string()),
+            video_filter: Signal::new(String::new()),
+            overlay_text: Signal::new(String::new()),
+            overlay_text_position: Signal::new("bottom_left".to_string()),
+            overlay_logo: Signal::new(String::new()),
+            overlay_logo_position: Signal::new("top_right".to_string()),
+            overlay_logo_opacity: Signal::new("0.8".to_string()),
+            overlay_clock: Signal::new("off".to_string()),
+            overlay_font: Signal::new(String::new()),
+            system_audio: Signal::new(
+                if AudioConfig::system_default().is_supported() {
+                    "system"
+                } else {
+                    "off"
+                }
+                .to_string(),
+            ),
+            audio_codec: Signal::new("aac".to_string()),
+            audio_bitrate_kbps: Signal::new("160".to_string()),
+            system_gain_db: Signal::new("0".to_string()),
+            microphone: Signal::new("off".to_string()),
+            mic_gain_db: Signal::new("0".to_string()),
+            mic_noise_reduction: Signal::new("off".to_string()),
+            mic_rnn_model: Signal::new(String::new()),
+            mic_compressor: Signal::new(false),
+            mic_loudnorm: Signal::new(false),
+            push_to_talk_key: Signal::new(String::new()),
+            output_path: Signal::new(DEFAULT_OUTPUT_TEMPLATE.to_string()),
+            if_exists: Signal::new("number".to_string()),
             buffer_secs: Signal::new("30".to_string()),
+            pre_roll_secs: Signal::new("15".to_string()),
+            post_roll_secs: Signal::new("0".to_string()),
             hotkey: Signal::new("F3".to_string()),
+            retention_max_gb: Signal::new(String::new()),
+            retention_max_days: Signal::new(String::new()),
+            keep_starred: Signal::new(true),
             listener_started: Signal::new(false),
         }
     }
+
+    fn snapshot(&self) -> RecordingSettings {
+        RecordingSettings {
+            resolution: self.resolution.read().clone(),
+            fit_mode: self.fit_mode.read().clone(),
+            scale_algorithm: self.scale_algorithm.read().clone(),
+            capture_region: self.capture_region.read().clone(),
+            capture_target: self.capture_target.read().clone(),
+            capture_window: self.capture_window.read().clone(),
+            display_device: self.display_device.read().clone(),
+            fps: self.fps.read().clone(),
+            frame_pacing: self.frame_pacing.read().clone(),
+            frame_timestamps: self.frame_timestamps.read().clone(),
+            codec: self.codec.read().clone(),
+            encoders: self.encoders.read().clone(),
+            color_space: self.color_space.read().clone(),
+            color_range: self.color_range.read().clone(),
+            rate_mode: self.rate_mode.read().clone(),
+            quality: self.quality.read().clone(),
+            bitrate_kbps: self.bitrate_kbps.read().clone(),
+            max_bitrate_kbps: self.max_bitrate_kbps.read().clone(),
+            vbv_kbits: self.vbv_kbits.read().clone(),
+            gop_length: self.gop_length.read().clone(),
+            b_frames: self.b_frames.read().clone(),
+            video_filter: self.video_filter.read().clone(),
+            overlay_text: self.overlay_text.read().clone(),
+            overlay_text_position: self.overlay_text_position.read().clone(),
+            overlay_logo: self.overlay_logo.read().clone(),
+            overlay_logo_position: self.overlay_logo_position.read().clone(),
+            overlay_logo_opacity: self.overlay_logo_opacity.read().clone(),
+            overlay_clock: self.overlay_clock.read().clone(),
+            overlay_font: self.overlay_font.read().clone(),
+            system_audio: self.system_audio.read().clone(),
+            audio_codec: self.audio_codec.read().clone(),
+            audio_bitrate_kbps: self.audio_bitrate_kbps.read().clone(),
+            system_gain_db: self.system_gain_db.read().clone(),
+            microphone: self.microphone.read().clone(),
+            mic_gain_db: self.mic_gain_db.read().clone(),
+            mic_noise_reduction: self.mic_noise_reduction.read().clone(),
+            mic_rnn_model: self.mic_rnn_model.read().clone(),
+            mic_compressor: *self.mic_compressor.read(),
+            mic_loudnorm: *self.mic_loudnorm.read(),
+            push_to_talk_key: self.push_to_talk_key.read().clone(),
+            output_path: self.output_path.read().clone(),
+            if_exists: self.if_exists.read().clone(),
+            buffer_secs: self.buffer_secs.read().clone(),
+            pre_roll_secs: self.pre_roll_secs.read().clone(),
+            post_roll_secs: self.post_roll_secs.read().clone(),
+            hotkey: self.hotkey.read().clone(),
+            retention_max_gb: self.retention_max_gb.read().clone(),
+            retention_max_days: self.retention_max_days.read().clone(),
+            keep_starred: *self.keep_starred.read(),
+        }
+    }
 }
 
 fn get_user_video_directory() -> PathBuf {
@@ -158,6 +386,23 @@ fn main() {
 
 pub fn app() -> Element {
     let _config = use_context_provider(|| RecordingConfig::new());
+    let _stats = use_context_provider(LiveStats::default);
+    let _devices = use_context_provider(|| {
+        let devices = devices::probe();
+        for format in &devices.input_formats {
+            debug!(
+                "[recorder] Input device {} ({:?}): {}",
+                format.name, format.kind, format.description
+            );
+        }
+        info!(
+            "[recorder] Found {} displays, {} audio sources and {} encoders",
+            devices.displays.len(),
+            devices.audio_sources.len(),
+            devices.encoders.len()
+        );
+        devices
+    });
 
     rsx! {
         document::Stylesheet { href: CSS }
@@ -165,10 +410,18 @@ pub fn app() -> Element {
             h1 { class: "app-title", "Mebal Configuration" }
             div { class: "config-form",
                 ResolutionInput {}
+                CaptureTargetInput {}
                 FpsInput {}
+                EncoderInput {}
+                RateControlInput {}
+                VideoFilterInput {}
+                OverlayInput {}
+                AudioInput {}
                 BufferSecondsInput {}
+                SaveWindowInput {}
                 HotkeyInput {}
                 OutputPathInput {}
+                RetentionInput {}
                 StartBufferButton {}
                 StatusDisplay {}
             }
@@ -179,8 +432,47 @@ pub fn app() -> Element {
 #[component]
 fn ResolutionInput() -> Element {
     let mut res = use_context::<RecordingConfig>().resolution;
+    let mut fit_mode = use_context::<RecordingConfig>().fit_mode;
+    let mut scale_algorithm = use_context::<RecordingConfig>().scale_algorithm;
+    let mut capture_region = use_context::<RecordingConfig>().capture_region;
+    let mut display_device = use_context::<RecordingConfig>().display_device;
+    let displays = use_context::<Devices>().displays;
+    let mut selected_display = use_signal(String::new);
+    let display_options: Vec<(String, String)> = displays
+        .iter()
+        .enumerate()
+        .map(|(i, display)| {
+            let primary = if display.primary { ", primary" } else { "" };
+            (
+                i.to_string(),
+                format!(
+                    "{} ({}x{}{})",
+                    display.name, display.width, display.height, primary
+                ),
+            )
+        })
+        .collect();
     rsx! {
         div { class: "form-group",
+            label { "Display:" }
+            select {
+                value: "{selected_display}",
+                onchange: move |e| {
+                    let display = e.value().parse::<usize>().ok().and_then(|i| displays.get(i));
+                    capture_region.set(
+                        display
+                            .and_then(|d| d.region)
+                            .map(|r| format!("{}x{}+{}+{}", r.width, r.height, r.x, r.y))
+                            .unwrap_or_default(),
+                    );
+                    display_device.set(display.and_then(|d| d.device.clone()).unwrap_or_default());
+                    selected_display.set(e.value());
+                },
+                option { value: "", "Main display" }
+                for (value, label) in display_options {
+                    option { key: "{value}", value: "{value}", "{label}" }
+                }
+            }
             label { "Resolution:" }
             select {
                 value: "{res}",
@@ -191,6 +483,88 @@ fn ResolutionInput() -> Element {
                 option { value: "3840x2160", "4K (3840x2160)" }
             }
             small { class: "form-help", "Select your desired recording resolution" }
+            label { "Aspect Ratio:" }
+            select {
+                value: "{fit_mode}",
+                onchange: move |e| fit_mode.set(e.value()),
+                option { value: "stretch", "Stretch to fill" }
+                option { value: "letterbox", "Fit with black bars" }
+                option { value: "crop", "Fill and crop edges" }
+            }
+            label { "Scaling Quality:" }
+            select {
+                value: "{scale_algorithm}",
+                onchange: move |e| scale_algorithm.set(e.value()),
+                option { value: "fast_bilinear", "Fast bilinear (lowest CPU)" }
+                option { value: "bilinear", "Bilinear" }
+                option { value: "bicubic", "Bicubic (sharper)" }
+                option { value: "lanczos", "Lanczos (sharpest)" }
+                option { value: "area", "Area (large downscales)" }
+                option { value: "point", "Nearest neighbour (pixel art)" }
+            }
+            label { "Capture Region:" }
+            input {
+                r#type: "text",
+                value: "{capture_region}",
+                oninput: move |e| capture_region.set(e.value()),
+                placeholder: "Whole screen"
+            }
+            small { class: "form-help", "WIDTHxHEIGHT+X+Y, e.g. 2560x1440+640+0. Scaled to the resolution above" }
+        }
+    }
+}
+
+#[component]
+fn CaptureTargetInput() -> Element {
+    let mut capture_target = use_context::<RecordingConfig>().capture_target;
+    let mut capture_window = use_context::<RecordingConfig>().capture_window;
+    let placeholder = match capture_target.read().as_str() {
+        "title" => "Part of the window title",
+        "class" => "e.g. firefox",
+        "id" => "e.g. 0x3a00004",
+        _ => "",
+    };
+    // Only what this platform's recorder can capture is offered.
+    let targets = [
+        ("screen", "Screen", CaptureTarget::Screen),
+        (
+            "title",
+            "Window by title",
+            CaptureTarget::WindowTitle(String::new()),
+        ),
+        (
+            "class",
+            "Window by class",
+            CaptureTarget::WindowClass(String::new()),
+        ),
+        ("id", "Window by ID", CaptureTarget::WindowId(0)),
+    ]
+    .into_iter()
+    .filter(|(_, _, target)| target.is_supported())
+    .map(|(value, label, _)| (value, label))
+    .collect::<Vec<_>>();
+    let follows_windows = targets.len() > 1;
+    rsx! {
+        div { class: "form-group",
+            label { "Capture:" }
+            select {
+                value: "{capture_target}",
+                onchange: move |e| capture_target.set(e.value()),
+                for (value, label) in targets {
+                    option { value: "{value}", "{label}" }
+                }
+            }
+            if *capture_target.read() != "screen" {
+                input {
+                    r#type: "text",
+                    value: "{capture_window}",
+                    oninput: move |e| capture_window.set(e.value()),
+                    placeholder: "{placeholder}"
+                }
+            }
+            if follows_windows {
+                small { class: "form-help", "A window is followed when it moves or resizes, and recording stops when it closes" }
+            }
         }
     }
 }
@@ -198,6 +572,8 @@ fn ResolutionInput() -> Element {
 #[component]
 fn FpsInput() -> Element {
     let mut fps = use_context::<RecordingConfig>().fps;
+    let mut frame_pacing = use_context::<RecordingConfig>().frame_pacing;
+    let mut frame_timestamps = use_context::<RecordingConfig>().frame_timestamps;
     rsx! {
         div { class: "form-group",
             label { "Frame Rate (FPS):" }
@@ -210,6 +586,418 @@ fn FpsInput() -> Element {
                 option { value: "120", "120 FPS (High)" }
             }
             small { class: "form-help", "Higher FPS = smoother video but larger files" }
+            label { "Frame Pacing:" }
+            select {
+                value: "{frame_pacing}",
+                onchange: move |e| frame_pacing.set(e.value()),
+                option { value: "constant", "Constant (repeat frames to fill gaps)" }
+                option { value: "variable", "Variable (leave gaps)" }
+            }
+            label { "Frame Timestamps:" }
+            select {
+                value: "{frame_timestamps}",
+                onchange: move |e| frame_timestamps.set(e.value()),
+                option { value: "capture_clock", "Capture time (recommended)" }
+                option { value: "input_pts", "Capture device timestamps" }
+            }
+            small { class: "form-help", "Frames that arrive too early are dropped either way" }
+        }
+    }
+}
+
+#[component]
+fn EncoderInput() -> Element {
+    let mut codec = use_context::<RecordingConfig>().codec;
+    let mut encoders = use_context::<RecordingConfig>().encoders;
+    let mut color_space = use_context::<RecordingConfig>().color_space;
+    let mut color_range = use_context::<RecordingConfig>().color_range;
+    let family = parse_codec(&codec.read()).unwrap_or_default();
+    let available: Vec<(String, String)> = use_context::<Devices>()
+        .encoders_for(family)
+        .map(|encoder| {
+            let kind = if encoder.hardware {
+                "hardware"
+            } else {
+                "software"
+            };
+            (encoder.name.clone(), format!("{} ({})", encoder.name, kind))
+        })
+        .collect();
+    rsx! {
+        div { class: "form-group",
+            label { "Codec:" }
+            select {
+                value: "{codec}",
+                onchange: move |e| {
+                    codec.set(e.value());
+                    encoders.set(String::new());
+                },
+                option { value: "h264", "H.264 (Most compatible)" }
+                option { value: "hevc", "HEVC / H.265" }
+                option { value: "av1", "AV1" }
+                option { value: "vp9", "VP9" }
+            }
+            label { "Encoder:" }
+            select {
+                value: "{encoders}",
+                onchange: move |e| encoders.set(e.value()),
+                option { value: "", "Automatic" }
+                for (name, label) in available {
+                    option { key: "{name}", value: "{name}", "{label}" }
+                }
+            }
+            small { class: "form-help", "Encoders in this FFmpeg build. Automatic tries hardware first and falls back to software" }
+            label { "Color Space:" }
+            select {
+                value: "{color_space}",
+                onchange: move |e| color_space.set(e.value()),
+                option { value: "bt709", "BT.709 (HD, recommended)" }
+                option { value: "bt601", "BT.601 (SD)" }
+            }
+            label { "Color Range:" }
+            select {
+                value: "{color_range}",
+                onchange: move |e| color_range.set(e.value()),
+                option { value: "limited", "Limited (16-235, most compatible)" }
+                option { value: "full", "Full (0-255)" }
+            }
+        }
+    }
+}
+
+#[component]
+fn VideoFilterInput() -> Element {
+    let mut video_filter = use_context::<RecordingConfig>().video_filter;
+    rsx! {
+        div { class: "form-group",
+            label { "Video Filters:" }
+            input {
+                r#type: "text",
+                value: "{video_filter}",
+                oninput: move |e| video_filter.set(e.value()),
+                placeholder: "None"
+            }
+            small { class: "form-help", "FFmpeg filter chain applied to every frame, e.g. hqdn3d,unsharp. It must keep the output size" }
+        }
+    }
+}
+
+#[component]
+fn OverlayInput() -> Element {
+    let config = use_context::<RecordingConfig>();
+    let mut overlay_text = config.overlay_text;
+    let mut overlay_text_position = config.overlay_text_position;
+    let mut overlay_logo = config.overlay_logo;
+    let mut overlay_logo_position = config.overlay_logo_position;
+    let mut overlay_logo_opacity = config.overlay_logo_opacity;
+    let mut overlay_clock = config.overlay_clock;
+    let mut overlay_font = config.overlay_font;
+    let positions = vec![
+        ("top_left", "Top left"),
+        ("top_right", "Top right"),
+        ("bottom_left", "Bottom left"),
+        ("bottom_right", "Bottom right"),
+    ];
+    rsx! {
+        div { class: "form-group",
+            label { "Watermark Text:" }
+            input {
+                r#type: "text",
+                value: "{overlay_text}",
+                oninput: move |e| overlay_text.set(e.value()),
+                placeholder: "None"
+            }
+            small { class: "form-help", "Variables: {{hostname}}, {{user}}, {{os}}, {{build_id}}" }
+            if !overlay_text.read().trim().is_empty() {
+                select {
+                    value: "{overlay_text_position}",
+                    onchange: move |e| overlay_text_position.set(e.value()),
+                    for (value, label) in positions.clone() {
+                        option { key: "{value}", value: "{value}", "{label}" }
+                    }
+                }
+            }
+            label { "Logo Image:" }
+            input {
+                r#type: "text",
+                value: "{overlay_logo}",
+                oninput: move |e| overlay_logo.set(e.value()),
+                placeholder: "None"
+            }
+            if !overlay_logo.read().trim().is_empty() {
+                select {
+                    value: "{overlay_logo_position}",
+                    onchange: move |e| overlay_logo_position.set(e.value()),
+                    for (value, label) in positions.clone() {
+                        option { key: "{value}", value: "{value}", "{label}" }
+                    }
+                }
+                label { "Logo Opacity:" }
+                input {
+                    r#type: "number",
+                    value: "{overlay_logo_opacity}",
+                    oninput: move |e| overlay_logo_opacity.set(e.value()),
+                    min: "0",
+                    max: "1",
+                    step: "0.1"
+                }
+            }
+            label { "Clock:" }
+            select {
+                value: "{overlay_clock}",
+                onchange: move |e| overlay_clock.set(e.value()),
+                option { value: "off", "Off" }
+                for (value, label) in positions {
+                    option { key: "{value}", value: "{value}", "{label}" }
+                }
+            }
+            label { "Overlay Font:" }
+            input {
+                r#type: "text",
+                value: "{overlay_font}",
+                oninput: move |e| overlay_font.set(e.value()),
+                placeholder: "System default"
+            }
+            small { class: "form-help", "Path to a .ttf or .otf file, needed when FFmpeg has no fontconfig" }
+        }
+    }
+}
+
+#[component]
+fn RateControlInput() -> Element {
+    let config = use_context::<RecordingConfig>();
+    let mut rate_mode = config.rate_mode;
+    let mut quality = config.quality;
+    let mut bitrate_kbps = config.bitrate_kbps;
+    let mut max_bitrate_kbps = config.max_bitrate_kbps;
+    let mut vbv_kbits = config.vbv_kbits;
+    let mut gop_length = config.gop_length;
+    let mut b_frames = config.b_frames;
+    let mode = rate_mode.read().clone();
+    rsx! {
+        div { class: "form-group",
+            label { "Rate Control:" }
+            select {
+                value: "{rate_mode}",
+                onchange: move |e| rate_mode.set(e.value()),
+                option { value: "quality", "Constant quality (CRF/CQ)" }
+                option { value: "cbr", "Constant bitrate (CBR)" }
+                option { value: "vbr", "Variable bitrate (VBR)" }
+            }
+            if mode == "quality" {
+                label { "Quality Level:" }
+                input {
+                    r#type: "number",
+                    value: "{quality}",
+                    oninput: move |e| quality.set(e.value()),
+                    min: "0",
+                    max: "63",
+                    placeholder: "Encoder default"
+                }
+            } else {
+                label { if mode == "cbr" { "Bitrate (kbps):" } else { "Target Bitrate (kbps):" } }
+                input {
+                    r#type: "number",
+                    value: "{bitrate_kbps}",
+                    oninput: move |e| bitrate_kbps.set(e.value()),
+                    min: "100",
+                    step: "500"
+                }
+                if mode == "vbr" {
+                    label { "Max Bitrate (kbps):" }
+                    input {
+                        r#type: "number",
+                        value: "{max_bitrate_kbps}",
+                        oninput: move |e| max_bitrate_kbps.set(e.value()),
+                        min: "100",
+                        step: "500"
+                    }
+                }
+                label { "VBV Buffer (kbit):" }
+                input {
+                    r#type: "number",
+                    value: "{vbv_kbits}",
+                    oninput: move |e| vbv_kbits.set(e.value()),
+                    min: "100",
+                    placeholder: "One second"
+                }
+            }
+            label { "Keyframe Interval (frames):" }
+            input {
+                r#type: "number",
+                value: "{gop_length}",
+                oninput: move |e| gop_length.set(e.value()),
+                min: "1",
+                placeholder: "Same as FPS"
+            }
+            label { "B-Frames:" }
+            input {
+                r#type: "number",
+                value: "{b_frames}",
+                oninput: move |e| b_frames.set(e.value()),
+                min: "0",
+                max: "16"
+            }
+            small { class: "form-help", "Lower quality numbers look better but make bigger files" }
+        }
+    }
+}
+
+#[component]
+fn AudioInput() -> Element {
+    let config = use_context::<RecordingConfig>();
+    let mut system_audio = config.system_audio;
+    let mut audio_codec = config.audio_codec;
+    let mut audio_bitrate_kbps = config.audio_bitrate_kbps;
+    let mut system_gain_db = config.system_gain_db;
+    let mut microphone = config.microphone;
+    let mut mic_gain_db = config.mic_gain_db;
+    let mut push_to_talk_key = config.push_to_talk_key;
+    let mut mic_noise_reduction = config.mic_noise_reduction;
+    let mut mic_rnn_model = config.mic_rnn_model;
+    let mut mic_compressor = config.mic_compressor;
+    let mut mic_loudnorm = config.mic_loudnorm;
+    let sources = use_context::<Devices>().audio_sources;
+    let source_option = |source: &devices::AudioSource| {
+        let default = if source.is_default { ", default" } else { "" };
+        (
+            format!("device:{}:{}", source.input_format, source.device),
+            format!(
+                "{} ({}{})",
+                source.description, source.input_format, default
+            ),
+        )
+    };
+    let monitors: Vec<(String, String)> = sources
+        .iter()
+        .filter(|source| source.is_monitor)
+        .map(source_option)
+        .collect();
+    let inputs: Vec<(String, String)> = sources
+        .iter()
+        .filter(|source| !source.is_monitor)
+        .map(source_option)
+        .collect();
+    // The default output is named after the sound server it is recorded
+    // through, and only offered where that server's input format exists.
+    let default_output = match AudioConfig::system_default() {
+        config if !config.is_supported() => None,
+        config if config.input_format == "pulse" => {
+            Some("Default output (PulseAudio/PipeWire)".to_string())
+        }
+        config => Some(format!("Default output ({})", config.input_format)),
+    };
+    rsx! {
+        div { class: "form-group",
+            label { "System Audio:" }
+            select {
+                value: "{system_audio}",
+                onchange: move |e| system_audio.set(e.value()),
+                if let Some(label) = default_output {
+                    option { value: "system", "{label}" }
+                }
+                for (value, label) in monitors {
+                    option { key: "{value}", value: "{value}", "{label}" }
+                }
+                option { value: "test_tone", "Test tone" }
+                option { value: "off", "Off" }
+            }
+            if *system_audio.read() != "off" {
+                label { "System Audio Gain (dB):" }
+                input {
+                    r#type: "number",
+                    value: "{system_gain_db}",
+                    oninput: move |e| system_gain_db.set(e.value()),
+                    min: "-30",
+                    max: "30",
+                    step: "1"
+                }
+            }
+            label { "Microphone:" }
+            select {
+                value: "{microphone}",
+                onchange: move |e| microphone.set(e.value()),
+                option { value: "default", "Default input" }
+                for (value, label) in inputs {
+                    option { key: "{value}", value: "{value}", "{label}" }
+                }
+                option { value: "off", "Off" }
+            }
+            if *microphone.read() != "off" {
+                label { "Microphone Gain (dB):" }
+                input {
+                    r#type: "number",
+                    value: "{mic_gain_db}",
+                    oninput: move |e| mic_gain_db.set(e.value()),
+                    min: "-30",
+                    max: "30",
+                    step: "1"
+                }
+                label { "Push-To-Talk Key:" }
+                select {
+                    value: "{push_to_talk_key}",
+                    onchange: move |e| push_to_talk_key.set(e.value()),
+                    option { value: "", "Off (always on)" }
+                    option { value: "V", "V Key" }
+                    option { value: "B", "B Key" }
+                    option { value: "F9", "F9" }
+                    option { value: "F10", "F10" }
+                    option { value: "F11", "F11" }
+                    option { value: "F12", "F12" }
+                }
+                label { "Microphone Noise Reduction:" }
+                select {
+                    value: "{mic_noise_reduction}",
+                    onchange: move |e| mic_noise_reduction.set(e.value()),
+                    option { value: "off", "Off" }
+                    option { value: "fft", "Light (afftdn)" }
+                    option { value: "rnn", "Speech model (arnndn)" }
+                }
+                if *mic_noise_reduction.read() == "rnn" {
+                    label { "RNNoise Model File:" }
+                    input {
+                        r#type: "text",
+                        value: "{mic_rnn_model}",
+                        oninput: move |e| mic_rnn_model.set(e.value()),
+                        placeholder: "/path/to/model.rnnn"
+                    }
+                }
+                label {
+                    input {
+                        r#type: "checkbox",
+                        checked: *mic_compressor.read(),
+                        onchange: move |e| mic_compressor.set(e.checked()),
+                    }
+                    " Compress microphone"
+                }
+                label {
+                    input {
+                        r#type: "checkbox",
+                        checked: *mic_loudnorm.read(),
+                        onchange: move |e| mic_loudnorm.set(e.checked()),
+                    }
+                    " Normalize microphone loudness"
+                }
+            }
+            if *system_audio.read() != "off" || *microphone.read() != "off" {
+                label { "Audio Codec:" }
+                select {
+                    value: "{audio_codec}",
+                    onchange: move |e| audio_codec.set(e.value()),
+                    option { value: "aac", "AAC" }
+                    option { value: "opus", "Opus" }
+                }
+                label { "Audio Bitrate (kbps):" }
+                input {
+                    r#type: "number",
+                    value: "{audio_bitrate_kbps}",
+                    oninput: move |e| audio_bitrate_kbps.set(e.value()),
+                    min: "32",
+                    max: "512",
+                    step: "32"
+                }
+            }
+            small { class: "form-help", "System audio and microphone are recorded to separate tracks" }
         }
     }
 }
@@ -233,6 +1021,35 @@ fn BufferSecondsInput() -> Element {
     }
 }
 
+#[component]
+fn SaveWindowInput() -> Element {
+    let mut pre_roll_secs = use_context::<RecordingConfig>().pre_roll_secs;
+    let mut post_roll_secs = use_context::<RecordingConfig>().post_roll_secs;
+    rsx! {
+        div { class: "form-group",
+            label { "Seconds Before Hotkey:" }
+            input {
+                r#type: "number",
+                value: "{pre_roll_secs}",
+                oninput: move |e| pre_roll_secs.set(e.value()),
+                min: "1",
+                max: "300",
+                step: "1"
+            }
+            label { "Seconds After Hotkey:" }
+            input {
+                r#type: "number",
+                value: "{post_roll_secs}",
+                oninput: move |e| post_roll_secs.set(e.value()),
+                min: "0",
+                max: "300",
+                step: "1"
+            }
+            small { class: "form-help", "Both together must fit in the buffer duration" }
+        }
+    }
+}
+
 #[component]
 fn HotkeyInput() -> Element {
     let mut hotkey = use_context::<RecordingConfig>().hotkey;
@@ -264,38 +1081,73 @@ fn HotkeyInput() -> Element {
 #[component]
 fn OutputPathInput() -> Element {
     let mut output_path = use_context::<RecordingConfig>().output_path;
+    let mut if_exists = use_context::<RecordingConfig>().if_exists;
     rsx! {
         div { class: "form-group",
-            label { "Output File Path:" }
+            label { "Output File Name:" }
             input {
                 r#type: "text",
                 value: "{output_path}",
                 oninput: move |e| output_path.set(e.value()),
-                placeholder: "Path where recordings will be saved"
+                placeholder: DEFAULT_OUTPUT_TEMPLATE
             }
-            button {
-                r#type: "button",
-                onclick: move |_| {
-                    let video_dir = get_user_video_directory();
-                    let timestamp = chrono::Utc::now().format("%Y%m%d_%H%M%S");
-                    let new_path = video_dir.join(format!("mebal_{}.mp4", timestamp)).to_string_lossy().to_string();
-                    output_path.set(new_path);
-                },
-                "Generate New Filename"
+            small { class: "form-help",
+                "Available: {{video_dir}} {{date}} {{time}} {{app}} {{counter}} {{ext}} {{duration}}"
+            }
+            label { "If The File Exists:" }
+            select {
+                value: "{if_exists}",
+                onchange: move |e| if_exists.set(e.value()),
+                option { value: "number", "Add a number (clip-1.mp4)" }
+                option { value: "overwrite", "Overwrite it" }
+                option { value: "fail", "Don't save" }
             }
-            small { class: "form-help", "Files will be saved to your Videos folder by default" }
+            small { class: "form-help", "Each save gets its own file, in your Videos folder by default" }
+        }
+    }
+}
+
+#[component]
+fn RetentionInput() -> Element {
+    let mut max_gb = use_context::<RecordingConfig>().retention_max_gb;
+    let mut max_days = use_context::<RecordingConfig>().retention_max_days;
+    let mut keep_starred = use_context::<RecordingConfig>().keep_starred;
+    rsx! {
+        div { class: "form-group",
+            label { "Keep At Most (GB):" }
+            input {
+                r#type: "number",
+                value: "{max_gb}",
+                oninput: move |e| max_gb.set(e.value()),
+                min: "1",
+                placeholder: "Unlimited"
+            }
+            label { "Delete Clips Older Than (days):" }
+            input {
+                r#type: "number",
+                value: "{max_days}",
+                oninput: move |e| max_days.set(e.value()),
+                min: "1",
+                placeholder: "Never"
+            }
+            label {
+                input {
+                    r#type: "checkbox",
+                    checked: *keep_starred.read(),
+                    onchange: move |e| keep_starred.set(e.checked()),
+                }
+                " Never delete starred clips"
+            }
+            small { class: "form-help", "Oldest clips in the output folder are deleted first" }
         }
     }
 }
 
 #[component]
 fn StartBufferButton() -> Element {
-    let mut listener_started = use_context::<RecordingConfig>().listener_started;
-    let resolution_sig = use_context::<RecordingConfig>().resolution;
-    let fps_sig = use_context::<RecordingConfig>().fps;
-    let output_path_sig = use_context::<RecordingConfig>().output_path;
-    let buffer_secs_sig = use_context::<RecordingConfig>().buffer_secs;
-    let hotkey_sig = use_context::<RecordingConfig>().hotkey;
+    let config = use_context::<RecordingConfig>();
+    let live_stats = use_context::<LiveStats>();
+    let mut listener_started = config.listener_started;
 
     rsx! {
         div { class: "form-group",
@@ -303,13 +1155,7 @@ fn StartBufferButton() -> Element {
                 class: if *listener_started.read() { "button-stop" } else { "button-start" },
                 onclick: move |_| {
                     if !*listener_started.read() {
-                        let resolution = resolution_sig.read().clone();
-                        let fps = fps_sig.read().clone();
-                        let output_path = output_path_sig.read().clone();
-                        let buffer_secs = buffer_secs_sig.read().clone();
-                        let hotkey = hotkey_sig.read().clone();
-
-                        if let Err(e) = start_recording(&resolution, &fps, &output_path, &buffer_secs, &hotkey) {
+                        if let Err(e) = start_recording(config.snapshot(), live_stats.clone()) {
                             error!("Failed to start recording: {}", e);
                         } else {
                             listener_started.set(true);
@@ -329,6 +1175,23 @@ fn StartBufferButton() -> Element {
 fn StatusDisplay() -> Element {
     let listener_started = use_context::<RecordingConfig>().listener_started;
     let hotkey = use_context::<RecordingConfig>().hotkey;
+    let fps = use_context::<RecordingConfig>().fps;
+    let live_stats = use_context::<LiveStats>();
+    let mut stats = use_signal(|| None::<RecorderStats>);
+    use_future(move || {
+        let live_stats = live_stats.clone();
+        async move {
+            loop {
+                tokio::time::sleep(STATS_INTERVAL).await;
+                let latest = live_stats.0.lock().unwrap().clone();
+                stats.set(latest);
+            }
+        }
+    });
+    let overloaded = stats
+        .read()
+        .as_ref()
+        .is_some_and(|stats| stats.is_overloaded(fps.read().parse().unwrap_or(60)));
 
     rsx! {
         div { class: "status-display",
@@ -336,6 +1199,22 @@ fn StatusDisplay() -> Element {
                 div { class: "status-active",
                     "🔴 Recording active - Press {hotkey} to save buffer"
                 }
+                if let Some(stats) = stats.read().as_ref() {
+                    div { class: "form-help",
+                        "{stats.capture_fps:.1} fps captured, {stats.encode_fps:.1} fps encoded, "
+                        "{stats.dropped_frames} dropped, {stats.repeated_frames} repeated, "
+                        "{stats.video_bitrate_kbps:.0} kbps"
+                    }
+                    div { class: "form-help",
+                        "Per frame (p95): decode {stats.decode_time.p95:?}, "
+                        "scale {stats.scale_time.p95:?}, encode {stats.encode_time.p95:?}"
+                    }
+                    if overloaded {
+                        div { class: "form-help",
+                            "⚠️ This machine is not keeping up. Try a lower resolution, frame rate or a faster encoder"
+                        }
+                    }
+                }
             } else {
                 div { class: "status-inactive",
                     "⚫ Click 'Start Buffer' to begin recording"
@@ -345,23 +1224,61 @@ fn StatusDisplay() -> Element {
     }
 }
 
-fn start_recording(
-    resolution: &str,
-    fps: &str,
-    output_path: &str,
-    buffer_secs: &str,
-    hotkey_str: &str,
-) -> anyhow::Result<()> {
+fn start_recording(settings: RecordingSettings, live_stats: LiveStats) -> anyhow::Result<()> {
     // Validate hotkey
-    let target_key = string_to_key(hotkey_str)
-        .ok_or_else(|| anyhow::anyhow!("Invalid hotkey: {}", hotkey_str))?;
+    let target_key = string_to_key(&settings.hotkey)
+        .ok_or_else(|| anyhow::anyhow!("Invalid hotkey: {}", settings.hotkey))?;
+
+    let (pre_roll, post_roll) = parse_save_window(
+        &settings.pre_roll_secs,
+        &settings.post_roll_secs,
+        &settings.buffer_secs,
+    )?;
+    let retention = parse_retention(
+        &settings.retention_max_gb,
+        &settings.retention_max_days,
+        settings.keep_starred,
+    )?;
+    let overwrite = parse_overwrite_policy(&settings.if_exists)?;
+    let encoder = parse_encoder(&settings)?;
+    let overlays = parse_overlays(&settings)?;
+    let video_filter = parse_video_filter(&settings, &encoder, &overlays)?;
+    let region = parse_region(&settings.capture_region)?;
+    let (fit, scaler) = parse_scaling(&settings.fit_mode, &settings.scale_algorithm)?;
+    let (timestamps, pacing) =
+        parse_frame_timing(&settings.frame_timestamps, &settings.frame_pacing)?;
+    let target = parse_capture_target(&settings.capture_target, &settings.capture_window)?;
+    let (system_audio, microphone) = parse_audio(&settings)?;
+    let push_to_talk_key = match settings.push_to_talk_key.as_str() {
+        "" => None,
+        key => Some(
+            string_to_key(key)
+                .ok_or_else(|| anyhow::anyhow!("Invalid push-to-talk key: {}", key))?,
+        ),
+    };
+    if push_to_talk_key == Some(target_key) {
+        return Err(anyhow::anyhow!(
+            "The push-to-talk key must differ from the save hotkey"
+        ));
+    }
+    let template = OutputTemplate::new(&settings.output_path, &get_user_video_directory())
+        .map_err(|e| anyhow::anyhow!(e))?;
+    // The library and the retention sweeper both live where the template
+    // puts clips.
+    let clips_dir = template.root_dir();
+    if !retention.is_unlimited() {
+        retention::check_root(&clips_dir).map_err(|e| anyhow::anyhow!(e))?;
+    }
 
-    // Clone all inputs into owned strings for the spawned task
-    let resolution = resolution.to_string();
-    let fps = fps.to_string();
-    let buffer_secs = buffer_secs.to_string();
-    let output_path_for_thread = output_path.to_string();
-    let hotkey_display = hotkey_str.to_string();
+    let RecordingSettings {
+        resolution,
+        display_device,
+        fps,
+        output_path: output_path_for_thread,
+        buffer_secs,
+        hotkey: hotkey_display,
+        ..
+    } = settings;
 
     std::thread::spawn(move || {
         // Create a new Tokio runtime for this thread
@@ -412,23 +1329,65 @@ fn start_recording(
                 hotkey_display
             );
 
-            let mut recorder = create_recorder(
+            let mut recorder = create_recorder(RecorderConfig {
                 width,
                 height,
-                fps_val,
-                buffer_secs_val,
-                output_path_for_thread.clone(),
-            );
+                fit,
+                scaler,
+                region,
+                target,
+                display: Some(display_device).filter(|device| !device.is_empty()),
+                fps: fps_val,
+                timestamps,
+                pacing,
+                buffer_secs: buffer_secs_val,
+                output: output_path_for_thread.clone(),
+                encoder,
+                video_filter,
+                overlays,
+                system_audio,
+                microphone: microphone.map(|mic| AudioConfig {
+                    push_to_talk: push_to_talk_key.is_some(),
+                    ..mic
+                }),
+            });
 
             recorder.start().await;
+            let recorder: Arc<dyn Recorder> = Arc::from(recorder);
+
+            // Publish statistics for the status display, and warn when the
+            // settings get too heavy for this machine.
+            let stats_recorder = recorder.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(STATS_INTERVAL);
+                let mut was_overloaded = false;
+                loop {
+                    interval.tick().await;
+                    let stats = stats_recorder.stats();
+                    let overloaded = stats.is_overloaded(fps_val);
+                    if overloaded && !was_overloaded {
+                        warn!("[recorder] Not keeping up with {} fps: {}", fps_val, stats);
+                    }
+                    was_overloaded = overloaded;
+                    *live_stats.0.lock().unwrap() = Some(stats);
+                }
+            });
 
             // Create a channel for hotkey events
             let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
-            let output_path_for_listener = output_path_for_thread.clone();
 
             // Spawn the key listener in a blocking task
+            let listener_recorder = recorder.clone();
             tokio::task::spawn_blocking(move || {
                 let result = listen(move |event| {
+                    if let EventType::KeyPress(key) | EventType::KeyRelease(key) = event.event_type
+                    {
+                        if Some(key) == push_to_talk_key {
+                            let held = matches!(event.event_type, EventType::KeyPress(_));
+                            listener_recorder.set_push_to_talk(held);
+                        }
+                    }
+
                     // Only process key press events to avoid crashes
                     if let EventType::KeyPress(key) = event.event_type {
                         debug!("[recorder] Key pressed: {:?}", key);
@@ -439,7 +1398,8 @@ fn start_recording(
                                 "[recorder] Hotkey {} pressed: sending save signal...",
                                 hotkey_display
                             );
-                            if let Err(e) = tx.send(output_path_for_listener.clone()) {
+                            let pressed_at = Instant::now();
+                            if let Err(e) = tx.send(pressed_at) {
                                 error!("[recorder] Failed to send save signal: {}", e);
                             }
                         }
@@ -453,16 +1413,81 @@ fn start_recording(
                 }
             });
 
-            // Handle hotkey events in the async context
-            while let Some(output_path) = rx.recv().await {
-                info!("[recorder] Processing hotkey event: saving buffer...");
-                match recorder.save(&output_path) {
-                    Ok(()) => {
-                        info!("[recorder] ✅ Successfully saved buffer to {}", output_path);
-                    }
+            // Hotkey presses only queue a save; the scheduler waits out the
+            // post-roll and merges presses whose ranges overlap, as long as the
+            // merged clip's start is still buffered when the save runs.
+            let max_clip = Duration::from_secs(buffer_secs_val as u64)
+                .saturating_sub(SAVE_COALESCE_WINDOW);
+            let (scheduler, mut outcomes) = SaveScheduler::new(
+                recorder,
+                SAVE_COALESCE_WINDOW,
+                max_clip,
+                template.clone(),
+                overwrite,
+            );
+            let library = match ClipLibrary::open(&clips_dir) {
+                Ok(library) => Some(Arc::new(library)),
+                Err(e) => {
+                    error!("[storage] Clip library unavailable: {}", e);
+                    None
+                }
+            };
+
+            // Kept alive for as long as the buffer runs.
+            let _sweeper = match library.clone() {
+                Some(_) if retention.is_unlimited() => None,
+                Some(library) => match RetentionSweeper::new(library, retention) {
+                    Ok(sweeper) => Some(sweeper.spawn(RETENTION_SWEEP_INTERVAL)),
                     Err(e) => {
-                        error!("[recorder] ❌ Failed to save buffer: {}", e);
+                        error!("[storage] Retention disabled: {}", e);
+                        None
                     }
+                },
+                None => {
+                    if !retention.is_unlimited() {
+                        warn!("[storage] Retention disabled: it only deletes clips in the library");
+                    }
+                    None
+                }
+            };
+            tokio::spawn(async move {
+                while let Some(outcome) = outcomes.recv().await {
+                    match outcome.result {
+                        Ok(clip) => {
+                            info!(
+                                "[recorder] ✅ Successfully saved buffer to {} (requests {:?})",
+                                clip.path.display(),
+                                outcome.request_ids
+                            );
+                            if !clip.missing_pre_roll.is_zero() {
+                                warn!(
+                                    "[recorder] Clip starts {:?} late: that part had already left the buffer",
+                                    clip.missing_pre_roll
+                                );
+                            }
+                            if let Some(library) = library.clone() {
+                                tokio::task::spawn_blocking(move || record_clip(&library, &clip));
+                            }
+                        }
+                        Err(e) => error!(
+                            "[recorder] ❌ Failed to save buffer for requests {:?}: {}",
+                            outcome.request_ids, e
+                        ),
+                    }
+                }
+            });
+
+            while let Some(pressed_at) = rx.recv().await {
+                let start = pressed_at.checked_sub(pre_roll).unwrap_or(pressed_at);
+                let end = pressed_at + post_roll;
+                info!(
+                    "[recorder] Processing hotkey event: saving {:?} before and {:?} after...",
+                    pre_roll, post_roll
+                );
+
+                match scheduler.request(start, end) {
+                    Ok(id) => debug!("[recorder] Hotkey save request id {}", id),
+                    Err(e) => error!("[recorder] ❌ Failed to queue save: {}", e),
                 }
                 info!("[recorder] Continuing to record...");
             }
@@ -472,6 +1497,467 @@ fn start_recording(
     Ok(())
 }
 
+/// Adds a saved clip to the library along with a thumbnail of its first frame.
+fn record_clip(library: &ClipLibrary, clip: &SavedClip) {
+    let stem = clip
+        .path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_else(|| "clip".to_string());
+    let thumbnail_path = library.thumbnail_dir().join(format!(
+        "{}_{}.jpg",
+        stem,
+        chrono::Utc::now().timestamp_millis()
+    ));
+
+    let thumbnail = match thumbnail::generate(&clip.path, &thumbnail_path) {
+        Ok(()) => Some(thumbnail_path),
+        Err(e) => {
+            warn!("[storage] No thumbnail for {}: {}", clip.path.display(), e);
+            None
+        }
+    };
+
+    match library.add(clip, thumbnail) {
+        Ok(entry) => info!(
+            "[storage] Added clip {} to library: {}",
+            entry.id,
+            entry.path.display()
+        ),
+        Err(e) => error!("[storage] Failed to index {}: {}", clip.path.display(), e),
+    }
+}
+
+fn parse_codec(codec: &str) -> anyhow::Result<CodecFamily> {
+    match codec {
+        "h264" => Ok(CodecFamily::H264),
+        "hevc" => Ok(CodecFamily::Hevc),
+        "av1" => Ok(CodecFamily::Av1),
+        "vp9" => Ok(CodecFamily::Vp9),
+        other => Err(anyhow::anyhow!("Invalid codec: {}", other)),
+    }
+}
+
+fn parse_encoder(settings: &RecordingSettings) -> anyhow::Result<EncoderConfig> {
+    let codec = parse_codec(&settings.codec)?;
+    let encoders = settings
+        .encoders
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+        .collect();
+
+    let rate_control = match settings.rate_mode.as_str() {
+        "quality" => parse_optional(&settings.quality, "quality level")?.map(RateControl::Quality),
+        "cbr" => Some(RateControl::Cbr {
+            bitrate_kbps: parse_number(&settings.bitrate_kbps, "bitrate")?,
+        }),
+        "vbr" => Some(RateControl::Vbr {
+            target_kbps: parse_number(&settings.bitrate_kbps, "target bitrate")?,
+            max_kbps: parse_number(&settings.max_bitrate_kbps, "max bitrate")?,
+        }),
+        other => return Err(anyhow::anyhow!("Invalid rate control mode: {}", other)),
+    };
+    let vbv_buffer_kbits = match settings.rate_mode.as_str() {
+        "quality" => None,
+        _ => parse_optional(&settings.vbv_kbits, "VBV buffer size")?,
+    };
+
+    let config = EncoderConfig {
+        codec,
+        encoders,
+        rate_control,
+        vbv_buffer_kbits,
+        gop_length: parse_optional(&settings.gop_length, "keyframe interval")?,
+        b_frames: parse_number(&settings.b_frames, "B-frame count")?,
+        color_space: match settings.color_space.as_str() {
+            "bt709" => ColorSpace::Bt709,
+            "bt601" => ColorSpace::Bt601,
+            other => return Err(anyhow::anyhow!("Invalid color space: {}", other)),
+        },
+        color_range: match settings.color_range.as_str() {
+            "limited" => ColorRange::Limited,
+            "full" => ColorRange::Full,
+            other => return Err(anyhow::anyhow!("Invalid color range: {}", other)),
+        },
+        ..Default::default()
+    };
+    config.validate().map_err(|e| anyhow::anyhow!(e))?;
+    Ok(config)
+}
+
+/// Returns the video filter chain. It is checked together with the overlays
+/// against the output size and format, so a bad chain fails here rather than
+/// once capture starts.
+fn parse_video_filter(
+    settings: &RecordingSettings,
+    encoder: &EncoderConfig,
+    overlays: &Overlays,
+) -> anyhow::Result<Option<String>> {
+    let chain = Some(settings.video_filter.trim()).filter(|chain| !chain.is_empty());
+    let Some(graph) = overlays.graph(chain).map_err(|e| anyhow::anyhow!(e))? else {
+        return Ok(None);
+    };
+    let (width, height) = parse_resolution(&settings.resolution)?;
+    let fps = parse_number(&settings.fps, "FPS")?;
+    validate_video_filter(&graph, width, height, fps, encoder).map_err(|e| anyhow::anyhow!(e))?;
+    Ok(chain.map(str::to_string))
+}
+
+/// Returns the watermark text, logo and clock overlays. Text is sized to
+/// the output height.
+fn parse_overlays(settings: &RecordingSettings) -> anyhow::Result<Overlays> {
+    let (_, height) = parse_resolution(&settings.resolution)?;
+    let font_size = (height / 30).max(12);
+    let text = match settings.overlay_text.trim() {
+        "" => None,
+        template => Some(TextOverlay {
+            template: template.to_string(),
+            position: parse_overlay_position(&settings.overlay_text_position)?,
+            font_size,
+        }),
+    };
+    let logo = match settings.overlay_logo.trim() {
+        "" => None,
+        image => Some(LogoOverlay {
+            image: PathBuf::from(image),
+            position: parse_overlay_position(&settings.overlay_logo_position)?,
+            opacity: settings.overlay_logo_opacity.trim().parse().map_err(|_| {
+                anyhow::anyhow!("Invalid logo opacity: {}", settings.overlay_logo_opacity)
+            })?,
+        }),
+    };
+    let clock = match settings.overlay_clock.as_str() {
+        "off" => None,
+        position => Some(ClockOverlay {
+            position: parse_overlay_position(position)?,
+            font_size,
+        }),
+    };
+    let overlays = Overlays {
+        text,
+        logo,
+        clock,
+        font_file: Some(settings.overlay_font.trim())
+            .filter(|font| !font.is_empty())
+            .map(PathBuf::from),
+        build_id: option_env!("MEBAL_BUILD_ID")
+            .unwrap_or(env!("CARGO_PKG_VERSION"))
+            .to_string(),
+    };
+    overlays.validate().map_err(|e| anyhow::anyhow!(e))?;
+    Ok(overlays)
+}
+
+fn parse_overlay_position(position: &str) -> anyhow::Result<OverlayPosition> {
+    match position {
+        "top_left" => Ok(OverlayPosition::TopLeft),
+        "top_right" => Ok(OverlayPosition::TopRight),
+        "bottom_left" => Ok(OverlayPosition::BottomLeft),
+        "bottom_right" => Ok(OverlayPosition::BottomRight),
+        _ => Err(anyhow::anyhow!("Invalid overlay position: {}", position)),
+    }
+}
+
+/// Returns the system audio and microphone tracks, each `None` when off.
+fn parse_audio(
+    settings: &RecordingSettings,
+) -> anyhow::Result<(Option<AudioConfig>, Option<AudioConfig>)> {
+    let system = match settings.system_audio.as_str() {
+        "off" => None,
+        "system" => {
+            let config = AudioConfig::system_default();
+            if !config.is_supported() {
+                return Err(anyhow::anyhow!(
+                    "Recording the default output through {} is not supported on {}",
+                    config.input_format,
+                    std::env::consts::OS
+                ));
+            }
+            Some(config)
+        }
+        "test_tone" => Some(AudioConfig::test_tone()),
+        other => Some(parse_audio_device(other, AudioConfig::system_default())?),
+    };
+    let microphone = match settings.microphone.as_str() {
+        "off" => None,
+        "default" => Some(AudioConfig::default_microphone()),
+        other => Some(parse_audio_device(
+            other,
+            AudioConfig::default_microphone(),
+        )?),
+    };
+    let codec = match settings.audio_codec.as_str() {
+        "aac" => AudioCodec::Aac,
+        "opus" => AudioCodec::Opus,
+        other => return Err(anyhow::anyhow!("Invalid audio codec: {}", other)),
+    };
+    let bitrate_kbps = parse_number(&settings.audio_bitrate_kbps, "audio bitrate")?;
+
+    let system = match system {
+        Some(base) => Some(AudioConfig {
+            codec,
+            bitrate_kbps,
+            gain_db: parse_gain(&settings.system_gain_db, "system audio gain")?,
+            ..base
+        }),
+        None => None,
+    };
+    let microphone = match microphone {
+        Some(base) => Some(AudioConfig {
+            codec,
+            bitrate_kbps,
+            gain_db: parse_gain(&settings.mic_gain_db, "microphone gain")?,
+            filters: parse_mic_filters(settings)?,
+            ..base
+        }),
+        None => None,
+    };
+    Ok((system, microphone))
+}
+
+/// Parses a listed audio source, `device:<input format>:<device name>`, on
+/// top of `base`.
+fn parse_audio_device(value: &str, base: AudioConfig) -> anyhow::Result<AudioConfig> {
+    let (input_format, device) = value
+        .strip_prefix("device:")
+        .and_then(|rest| rest.split_once(':'))
+        .ok_or_else(|| anyhow::anyhow!("Invalid audio source: {}", value))?;
+    Ok(AudioConfig {
+        input_format: input_format.to_string(),
+        device: device.to_string(),
+        ..base
+    })
+}
+
+fn parse_mic_filters(settings: &RecordingSettings) -> anyhow::Result<AudioFilters> {
+    let noise_reduction = match settings.mic_noise_reduction.as_str() {
+        "off" => None,
+        "fft" => Some(NoiseReduction::Fft),
+        "rnn" => match settings.mic_rnn_model.trim() {
+            "" => return Err(anyhow::anyhow!("Speech noise reduction needs a model file")),
+            model => Some(NoiseReduction::Rnn {
+                model: PathBuf::from(model),
+            }),
+        },
+        other => return Err(anyhow::anyhow!("Invalid noise reduction: {}", other)),
+    };
+    Ok(AudioFilters {
+        noise_reduction,
+        compressor: settings.mic_compressor,
+        loudnorm: settings.mic_loudnorm,
+        custom: None,
+    })
+}
+
+fn parse_gain(value: &str, what: &str) -> anyhow::Result<f32> {
+    value
+        .trim()
+        .parse::<f32>()
+        .ok()
+        .filter(|db| db.is_finite() && db.abs() <= 60.0)
+        .ok_or_else(|| anyhow::anyhow!("Invalid {}: {}", what, value))
+}
+
+fn parse_number(value: &str, what: &str) -> anyhow::Result<u32> {
+    value
+        .trim()
+        .parse::<u32>()
+        .map_err(|_| anyhow::anyhow!("Invalid {}: {}", what, value))
+}
+
+/// Like [`parse_number`], but an empty field means "use the default".
+fn parse_optional(value: &str, what: &str) -> anyhow::Result<Option<u32>> {
+    match value.trim() {
+        "" => Ok(None),
+        value => parse_number(value, what).map(Some),
+    }
+}
+
+fn parse_overwrite_policy(if_exists: &str) -> anyhow::Result<OverwritePolicy> {
+    match if_exists {
+        "number" => Ok(OverwritePolicy::UniqueName),
+        "overwrite" => Ok(OverwritePolicy::Overwrite),
+        "fail" => Ok(OverwritePolicy::Fail),
+        other => Err(anyhow::anyhow!("Invalid overwrite policy: {}", other)),
+    }
+}
+
+fn parse_retention(
+    max_gb: &str,
+    max_days: &str,
+    keep_starred: bool,
+) -> anyhow::Result<RetentionPolicy> {
+    let max_total_bytes = match max_gb.trim() {
+        "" => None,
+        value => {
+            let gb = value
+                .parse::<f64>()
+                .ok()
+                .filter(|gb| *gb > 0.0)
+                .ok_or_else(|| anyhow::anyhow!("Invalid size limit: {}", value))?;
+            Some((gb * 1024.0 * 1024.0 * 1024.0) as u64)
+        }
+    };
+
+    let max_age = match max_days.trim() {
+        "" => None,
+        value => {
+            let days = value
+                .parse::<u64>()
+                .ok()
+                .filter(|days| *days > 0)
+                .ok_or_else(|| anyhow::anyhow!("Invalid age limit: {}", value))?;
+            Some(Duration::from_secs(days * 24 * 60 * 60))
+        }
+    };
+
+    Ok(RetentionPolicy {
+        max_total_bytes,
+        max_age,
+        keep_starred,
+    })
+}
+
+fn parse_save_window(
+    pre_roll_secs: &str,
+    post_roll_secs: &str,
+    buffer_secs: &str,
+) -> anyhow::Result<(Duration, Duration)> {
+    let pre_roll = pre_roll_secs
+        .parse::<u64>()
+        .map_err(|_| anyhow::anyhow!("Invalid pre-roll seconds: {}", pre_roll_secs))?;
+    let post_roll = post_roll_secs
+        .parse::<u64>()
+        .map_err(|_| anyhow::anyhow!("Invalid post-roll seconds: {}", post_roll_secs))?;
+    let buffer = buffer_secs
+        .parse::<u64>()
+        .map_err(|_| anyhow::anyhow!("Invalid buffer seconds: {}", buffer_secs))?;
+
+    if pre_roll == 0 && post_roll == 0 {
+        return Err(anyhow::anyhow!(
+            "Save window must cover at least one second"
+        ));
+    }
+
+    // The oldest packet of the window has to survive in the buffer until the
+    // post-roll has been collected and the save has waited out the coalesce
+    // window.
+    let coalesce = SAVE_COALESCE_WINDOW.as_secs();
+    if pre_roll + post_roll + coalesce > buffer {
+        return Err(anyhow::anyhow!(
+            "Save window ({}s before + {}s after + {}s to merge presses) exceeds the {}s buffer",
+            pre_roll,
+            post_roll,
+            coalesce,
+            buffer
+        ));
+    }
+
+    Ok((
+        Duration::from_secs(pre_roll),
+        Duration::from_secs(post_roll),
+    ))
+}
+
+fn parse_frame_timing(
+    timestamps: &str,
+    pacing: &str,
+) -> anyhow::Result<(FrameTimestamps, FramePacing)> {
+    let timestamps = match timestamps {
+        "capture_clock" => FrameTimestamps::CaptureClock,
+        "input_pts" => FrameTimestamps::InputPts,
+        other => return Err(anyhow::anyhow!("Invalid frame timestamp source: {}", other)),
+    };
+    let pacing = match pacing {
+        "constant" => FramePacing::Constant,
+        "variable" => FramePacing::Variable,
+        other => return Err(anyhow::anyhow!("Invalid frame pacing: {}", other)),
+    };
+    Ok((timestamps, pacing))
+}
+
+fn parse_scaling(fit: &str, algorithm: &str) -> anyhow::Result<(FitMode, ScaleAlgorithm)> {
+    let fit = match fit {
+        "stretch" => FitMode::Stretch,
+        "letterbox" => FitMode::Letterbox,
+        "crop" => FitMode::Crop,
+        other => return Err(anyhow::anyhow!("Invalid aspect ratio mode: {}", other)),
+    };
+    let algorithm = match algorithm {
+        "fast_bilinear" => ScaleAlgorithm::FastBilinear,
+        "bilinear" => ScaleAlgorithm::Bilinear,
+        "bicubic" => ScaleAlgorithm::Bicubic,
+        "lanczos" => ScaleAlgorithm::Lanczos,
+        "area" => ScaleAlgorithm::Area,
+        "point" => ScaleAlgorithm::Point,
+        other => return Err(anyhow::anyhow!("Invalid scaling algorithm: {}", other)),
+    };
+    Ok((fit, algorithm))
+}
+
+/// Parses an X11-style geometry such as `2560x1440+640+0`. Empty means the
+/// whole screen.
+fn parse_region(region: &str) -> anyhow::Result<Option<CaptureRegion>> {
+    let region = region.trim();
+    if region.is_empty() {
+        return Ok(None);
+    }
+    let invalid = || {
+        anyhow::anyhow!(
+            "Capture region must be in format WIDTHxHEIGHT+X+Y (e.g., 2560x1440+640+0): {}",
+            region
+        )
+    };
+
+    let offset_start = region.find(['+', '-']).ok_or_else(invalid)?;
+    let (size, offsets) = region.split_at(offset_start);
+    let (width, height) = size.split_once('x').ok_or_else(invalid)?;
+    let second = offsets[1..].find(['+', '-']).ok_or_else(invalid)? + 1;
+    let (x, y) = offsets.split_at(second);
+
+    let region = CaptureRegion {
+        x: x.parse().map_err(|_| invalid())?,
+        y: y.parse().map_err(|_| invalid())?,
+        width: width.parse().map_err(|_| invalid())?,
+        height: height.parse().map_err(|_| invalid())?,
+    };
+    region.validate().map_err(|e| anyhow::anyhow!(e))?;
+    Ok(Some(region))
+}
+
+/// Parses the capture target picked in the form. Window IDs may be decimal
+/// or `0x` hex, as printed by `xwininfo`.
+fn parse_capture_target(kind: &str, window: &str) -> anyhow::Result<CaptureTarget> {
+    let window = window.trim();
+    if kind != "screen" && window.is_empty() {
+        return Err(anyhow::anyhow!("Enter the window to capture"));
+    }
+    let target = match kind {
+        "screen" => Ok(CaptureTarget::Screen),
+        "title" => Ok(CaptureTarget::WindowTitle(window.to_string())),
+        "class" => Ok(CaptureTarget::WindowClass(window.to_string())),
+        "id" => {
+            let id = match window.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16),
+                None => window.parse(),
+            };
+            id.map(CaptureTarget::WindowId)
+                .map_err(|_| anyhow::anyhow!("Invalid window ID: {}", window))
+        }
+        other => Err(anyhow::anyhow!("Unknown capture target: {}", other)),
+    }?;
+    if !target.is_supported() {
+        return Err(anyhow::anyhow!(
+            "Capturing a window by {} is not supported on {}",
+            kind,
+            std::env::consts::OS
+        ));
+    }
+    Ok(target)
+}
+
 fn parse_resolution(resolution: &str) -> anyhow::Result<(u32, u32)> {
     let parts: Vec<&str> = resolution.split('x').collect();
     if parts.len() != 2 {